@@ -1,8 +1,32 @@
 #![no_std]
 
 pub mod constants;
+pub mod server;
 pub mod usb;
 
+/// Our local clock, the time since boot as if we'd booted at the UNIX epoch. Offsets from
+/// the clock filter are relative to this, and it's the tick source for the discipline.
+pub fn uptime_nanos() -> u64 {
+    embassy_time::Instant::now()
+        .as_micros()
+        .saturating_mul(1_000)
+}
+
+/// embassy-time for the NTP exchange, where [uptime_nanos] is both the wall clock and the
+/// monotonic clock since nothing ever steps it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EmbassyTimeSource;
+
+impl ntp_clock::timesource::TimeSource for EmbassyTimeSource {
+    fn unix_nanos(&self) -> u64 {
+        uptime_nanos()
+    }
+
+    fn monotonic_nanos(&self) -> u64 {
+        uptime_nanos()
+    }
+}
+
 #[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct HandAnglesDeg {
     pub hour: f32,
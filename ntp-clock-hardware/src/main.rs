@@ -1,6 +1,7 @@
 #![no_std]
 #![no_main]
 
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use core::panic;
 use core::str::FromStr;
 
@@ -8,31 +9,43 @@ use cyw43::{Control, JoinOptions};
 use cyw43_pio::{DEFAULT_CLOCK_DIVIDER, PioSpi};
 use embassy_executor::Spawner;
 use embassy_net::udp::{PacketMetadata, UdpSocket};
-use embassy_net::{Config, Ipv4Address, StackResources};
+use embassy_net::{Config, ConfigV6, IpAddress, Ipv6Cidr, StackResources, StaticConfigV6};
 use embassy_rp::bind_interrupts;
 use embassy_rp::clocks::clk_sys_freq;
 use embassy_rp::gpio::{Input, Level, Output, Pull};
 use embassy_rp::peripherals::{DMA_CH0, PIO0};
 use embassy_rp::pio::{InterruptHandler, Pio};
 use embassy_rp::pwm::{Config as PwmConfig, Pwm, SetDutyCycle};
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer, with_deadline, with_timeout};
 use fixed::traits::ToFixed;
 use log::{info, warn};
-use ntp_clock::clock::hand_angles;
-use ntp_clock::constants::NTP_PORT;
+use ntp_clock::NtpClient;
+use ntp_clock::auth::KeyStore;
+use ntp_clock::broadcast::BroadcastClient;
+use ntp_clock::clock::hand_angles_at;
+use ntp_clock::constants::{
+    NTP_ERA_PIVOT_UNIX_NANOS, NTP_MAX_PACKET_LEN, NTP_MULTICAST_IPV4, NTP_MULTICAST_IPV6, NTP_PORT,
+};
+use ntp_clock::discipline::ClockDiscipline;
+use ntp_clock::error::ClockError;
+use ntp_clock::filter::FilterEstimate;
+use ntp_clock::leap::{LeapHandling, LeapSecond};
 use ntp_clock::packets::NtpPacket;
-use ntp_clock::parse_ntp_packet;
+use ntp_clock::sample::SyncSample;
+use ntp_clock::server::NtpServer;
+use ntp_clock::transport::AsyncNtpTransport;
 use ntp_clock_hardware::constants::NETWORK_DETAILS_LOG_DELAY_SECS;
 use ntp_clock_hardware::hardware::{PwmServoController, ServoPwmConfig, angles_to_hand_degrees};
-use ntp_clock_hardware::{ClockMechanism, LimitSwitches};
-use packed_struct::prelude::*;
+use ntp_clock_hardware::{ClockMechanism, EmbassyTimeSource, LimitSwitches, uptime_nanos};
 use panic_halt as _;
 use static_cell::StaticCell;
 
 const PWM_TARGET_HZ: u32 = 50;
 const PWM_DIVIDER: u32 = 125;
-const DEFAULT_NTP_SERVER: Ipv4Address = Ipv4Address::new(10, 0, 0, 1);
+const DEFAULT_NTP_SERVER: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
 const DEFAULT_SYSLOG_PORT: u16 = 514;
+/// Leap seconds are smeared over the 24 hours around them, so the hands never jump
+const LEAP_HANDLING: LeapHandling = LeapHandling::Smear(core::time::Duration::from_secs(86_400));
 
 const WIFI_SSID: &str = match option_env!("WIFI_SSID") {
     Some(value) => value,
@@ -60,6 +73,16 @@ const NTP_SERVER_ENV: &str = match option_env!("NTP_SERVER") {
     None => "",
 };
 const SYSLOG_SERVER_ENV: Option<&str> = option_env!("SYSLOG_SERVER");
+/// A static IPv6 address with its prefix length, e.g. `2001:db8::10/64`, alongside DHCPv4
+const IPV6_ADDRESS_ENV: Option<&str> = option_env!("IPV6_ADDRESS");
+/// The IPv6 default gateway, used with IPV6_ADDRESS
+const IPV6_GATEWAY_ENV: Option<&str> = option_env!("IPV6_GATEWAY");
+/// A line from an ntpd keys file, `<key id> <type> <secret>`, to authenticate with
+const NTP_KEY_ENV: Option<&str> = option_env!("NTP_KEY");
+/// Set to anything to serve the time to NTP clients on the LAN
+const NTP_SERVE_ENV: Option<&str> = option_env!("NTP_SERVE");
+/// Set to anything to follow the NTP server's broadcasts once we've measured the delay to it
+const NTP_BROADCAST_ENV: Option<&str> = option_env!("NTP_BROADCAST");
 
 const SYSLOG_PORT_ENV: &str = match option_env!("SYSLOG_PORT") {
     Some(value) => value,
@@ -120,9 +143,12 @@ async fn main(spawner: Spawner) {
         idle_missing_wifi().await;
     }
 
-    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
+    static RESOURCES: StaticCell<StackResources<4>> = StaticCell::new();
     let resources = RESOURCES.init(StackResources::new());
-    let config = Config::dhcpv4(Default::default());
+    let mut config = Config::dhcpv4(Default::default());
+    if let Some(ipv6) = get_ipv6_config() {
+        config.ipv6 = ConfigV6::Static(ipv6);
+    }
     // TODO: get a random seed from the RNG
     let seed = 0x2f3a_9b5d_7c1e_4d6a;
 
@@ -132,24 +158,46 @@ async fn main(spawner: Spawner) {
     info!("DHCP configuration acquired");
 
     if let Some(syslog_server_env) = SYSLOG_SERVER_ENV {
-        if let Some(syslog_server) = parse_ipv4(syslog_server_env) {
+        if let Ok(syslog_server) = IpAddr::from_str(syslog_server_env) {
             info!("Syslog server configured: {}", syslog_server_env);
             let port = parse_u16(SYSLOG_PORT_ENV).unwrap_or(DEFAULT_SYSLOG_PORT);
             ntp_clock_hardware::usb::init_syslog_logging(
                 &spawner,
                 network_stack,
-                syslog_server,
+                IpAddress::from(syslog_server),
                 port,
             );
         } else {
-            warn!("SYSLOG_SERVER is not a valid IPv4 address");
+            warn!("SYSLOG_SERVER is not a valid IP address");
         }
     } else {
         info!("No syslog server configured");
     }
 
+    let ntp_server = get_ntp_server();
+    if NTP_BROADCAST_ENV.is_some() {
+        let group = match ntp_server {
+            IpAddr::V4(_) => IpAddr::V4(NTP_MULTICAST_IPV4),
+            IpAddr::V6(_) => IpAddr::V6(NTP_MULTICAST_IPV6),
+        };
+        if network_stack
+            .join_multicast_group(IpAddress::from(group))
+            .is_err()
+        {
+            warn!("Failed to join the NTP multicast group, only broadcasts will be heard");
+        }
+        if NTP_SERVE_ENV.is_some() {
+            warn!(
+                "NTP_SERVE is ignored with NTP_BROADCAST, both need port {}",
+                NTP_PORT
+            );
+        }
+    } else if NTP_SERVE_ENV.is_some() {
+        ntp_clock_hardware::server::init_ntp_server(&spawner, network_stack);
+    }
+
     let mut rx_meta = [PacketMetadata::EMPTY; 8];
-    let mut rx_buffer = [0u8; 256];
+    let mut rx_buffer = [0u8; NTP_MAX_PACKET_LEN];
     let mut tx_meta = [PacketMetadata::EMPTY; 8];
     let mut tx_buffer = [0u8; 256];
     let mut socket = UdpSocket::new(
@@ -159,13 +207,26 @@ async fn main(spawner: Spawner) {
         &mut tx_meta,
         &mut tx_buffer,
     );
-    if socket.bind(0).is_err() {
+    // broadcasts arrive on the NTP port, so listen there and send from it too
+    let local_port = if NTP_BROADCAST_ENV.is_some() {
+        NTP_PORT
+    } else {
+        0
+    };
+    if socket.bind(local_port).is_err() {
         loop {
             Timer::after(Duration::from_secs(60)).await;
         }
     }
 
-    let ntp_server = get_ntp_server();
+    let ntp_key = get_ntp_key();
+    let mut broadcast = NTP_BROADCAST_ENV.map(|_| {
+        let client = BroadcastClient::new(ntp_server);
+        match ntp_key.as_ref() {
+            Some((_, keys)) => client.with_authentication(keys.clone()),
+            None => client,
+        }
+    });
 
     let pwm_top = pwm_top_from_sysclk();
     let mut pwm_config = PwmConfig::default();
@@ -189,8 +250,10 @@ async fn main(spawner: Spawner) {
         LimitSwitchPins::new(Input::new(p.PIN_6, Pull::Up), Input::new(p.PIN_7, Pull::Up));
     let mut clock = ClockMechanism::new(controller, switches);
 
-    let mut tick = 0u32;
-    let mut last_packet: Option<NtpPacket> = None;
+    let mut client = new_ntp_client(ntp_server, ntp_key.as_ref());
+    let mut next_poll = Instant::now();
+    let mut discipline = ClockDiscipline::new();
+    let mut server = NtpServer::new();
     loop {
         if let Some(config) = network_stack.config_v4() {
             info!(
@@ -200,29 +263,88 @@ async fn main(spawner: Spawner) {
         } else {
             info!("Net config: DHCP not ready");
         }
+        if let Some(config) = network_stack.config_v6() {
+            info!(
+                "Net config: IPv6 addr={}, gateway={:?}",
+                config.address, config.gateway
+            );
+        }
 
-        if tick.is_multiple_of(5) {
-            info!("Running NTP update against {}", ntp_server);
-
-            if let Some(ntp_time) = query_ntp(
-                &mut socket,
-                ntp_server,
-                last_packet.as_ref().map(|p| p.transmit_time),
-            )
-            .await
-            {
-                info!("NTP update successful: {}", ntp_time.to_string());
-                let angles = hand_angles(&ntp_time);
-                let degrees = angles_to_hand_degrees(angles);
-                let _ = clock.apply_hand_angles(degrees);
-                clock.update_zeroing();
-                last_packet = Some(ntp_time);
-            } else {
-                warn!("NTP update failed");
+        // once the delay is calibrated, broadcasts take over from polling
+        let broadcasting = broadcast
+            .as_ref()
+            .is_some_and(BroadcastClient::is_calibrated);
+        if broadcasting || (!client.is_demobilized() && Instant::now() >= next_poll) {
+            let result = match broadcast.as_mut() {
+                Some(broadcast) if broadcasting => {
+                    // decode the server's timestamps relative to the time we're showing
+                    let pivot = discipline
+                        .now(uptime_nanos())
+                        .unwrap_or(0)
+                        .max(NTP_ERA_PIVOT_UNIX_NANOS);
+                    listen_broadcast(&mut socket, broadcast, ntp_server, pivot)
+                        .await
+                        .map(|(packet, sample)| (packet, client.filter.add(sample)))
+                }
+                _ => {
+                    info!("Running NTP update against {}", ntp_server);
+                    let result = query_ntp(&mut socket, &mut client).await;
+                    // calibrate with this exchange, not the filter's pick
+                    if let (Some(broadcast), Ok(_), Some(sample)) =
+                        (broadcast.as_mut(), &result, client.last_sample.as_ref())
+                    {
+                        broadcast.calibrate(sample);
+                    }
+                    result
+                }
+            };
+            match result {
+                Ok((packet, estimate)) => {
+                    let leap = LeapSecond::from_indicator(
+                        packet.leap_indicator,
+                        estimate.sample.server_time(),
+                    );
+                    discipline.set_leap(estimate.sample.t4, leap);
+                    // the sample times are uptime, so T4 is also our monotonic reading
+                    let action =
+                        discipline.update_from_sample(estimate.sample.t4, &estimate.sample);
+                    discipline.set_poll_interval(client.poll.interval());
+                    if NTP_SERVE_ENV.is_some() && discipline.is_synchronized() {
+                        server.update_from_upstream(&packet, &estimate, ntp_server);
+                        ntp_clock_hardware::server::update_ntp_server(&server, &discipline);
+                    }
+                    info!(
+                        "NTP update successful: {} (offset {}ns, delay {}ns, jitter {}ns, {:?}, frequency {}ppb)",
+                        packet.to_string(),
+                        estimate.offset,
+                        estimate.delay,
+                        estimate.jitter,
+                        action,
+                        discipline.frequency_ppb()
+                    );
+                }
+                // the client stops or backs off by itself, and logs why
+                Err(ClockError::KissOfDeath(code)) => {
+                    warn!("NTP server sent {:?} ({})", code, code.description())
+                }
+                Err(ClockError::Timeout) if broadcasting => {
+                    info!("No NTP broadcast from {}", ntp_server)
+                }
+                Err(err) => warn!("NTP update failed: {:?}", err),
             }
+            next_poll = Instant::now() + Duration::from_secs(client.poll.interval().as_secs());
+        }
+        // keep the hands moving between polls
+        if let Some(now) = discipline.display_time(uptime_nanos(), LEAP_HANDLING) {
+            let angles = hand_angles_at(now);
+            let degrees = angles_to_hand_degrees(angles);
+            let _ = clock.apply_hand_angles(degrees);
+            clock.update_zeroing();
+        }
+        // when broadcasting, waiting for the next broadcast paces the loop
+        if !broadcasting {
+            Timer::after(Duration::from_secs(NETWORK_DETAILS_LOG_DELAY_SECS)).await;
         }
-        tick = tick.wrapping_add(1);
-        Timer::after(Duration::from_secs(NETWORK_DETAILS_LOG_DELAY_SECS)).await;
     }
 }
 
@@ -244,41 +366,131 @@ async fn idle_missing_wifi() -> ! {
     }
 }
 
+/// The client for `server`, on the uptime clock, signing requests when there's a key
+fn new_ntp_client(server: IpAddr, key: Option<&(u32, KeyStore)>) -> NtpClient<EmbassyTimeSource> {
+    let server = SocketAddr::new(server, NTP_PORT);
+    let client = NtpClient::new_with_time_source(server, EmbassyTimeSource);
+    let Some((key_id, keys)) = key else {
+        return client;
+    };
+    client
+        .with_authentication(*key_id, keys.clone())
+        .unwrap_or_else(|err| {
+            warn!("Failed to set up NTP authentication: {:?}", err);
+            NtpClient::new_with_time_source(server, EmbassyTimeSource)
+        })
+}
+
+/// Query the client's server, asking again with a growing delay when it doesn't answer, the
+/// same way the host client does. The uptime is monotonic, so every request carries a unique
+/// origin for the server to echo back
 async fn query_ntp(
     socket: &mut UdpSocket<'_>,
-    server: Ipv4Address,
-    current_time: Option<u64>,
-) -> Option<NtpPacket> {
-    let request = NtpPacket::request()
-        .with_transmit_time(current_time.unwrap_or(0))
-        .pack()
-        .ok()?;
-
-    socket.send_to(&request, (server, NTP_PORT)).await.ok()?;
-    let mut response = [0u8; ntp_clock::constants::NTP_MIN_PACKET_LEN];
-    let (len, _) = socket.recv_from(&mut response).await.ok()?;
-    parse_ntp_packet(&response[..len], 0).ok()
+    client: &mut NtpClient<EmbassyTimeSource>,
+) -> Result<(NtpPacket, FilterEstimate), ClockError> {
+    let server = client.server;
+    let sample = client
+        .update_with(&mut EmbassyTransport(socket), |delay| {
+            warn!(
+                "No response from {}, retrying in {}ms",
+                server,
+                delay.as_millis()
+            );
+            Timer::after(Duration::from_millis(delay.as_millis() as u64))
+        })
+        .await?;
+    let packet = client
+        .last_response
+        .clone()
+        .ok_or(ClockError::NoTimeAvailable)?;
+    let estimate = client
+        .filter
+        .estimate(sample.t4)
+        .ok_or(ClockError::NoTimeAvailable)?;
+    Ok((packet, estimate))
 }
 
-/// parse the NTP_SERVER_ENV or return the default NTP server
-fn get_ntp_server() -> Ipv4Address {
-    parse_ipv4(NTP_SERVER_ENV).unwrap_or(DEFAULT_NTP_SERVER)
+/// The shared NTP exchange over an embassy-net UDP socket
+struct EmbassyTransport<'a, 'b>(&'a mut UdpSocket<'b>);
+
+impl AsyncNtpTransport for EmbassyTransport<'_, '_> {
+    async fn send_to(&mut self, packet: &[u8], server: SocketAddr) -> Result<(), ClockError> {
+        self.0
+            .send_to(packet, (IpAddress::from(server.ip()), server.port()))
+            .await
+            .map_err(|_| ClockError::NetworkError)
+    }
+
+    async fn recv_from(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: core::time::Duration,
+    ) -> Result<(usize, SocketAddr), ClockError> {
+        let timeout = Duration::from_micros(timeout.as_micros() as u64);
+        let (len, meta) = with_timeout(timeout, self.0.recv_from(buffer))
+            .await
+            .map_err(|_| ClockError::Timeout)?
+            .map_err(|_| ClockError::NetworkError)?;
+        let source = match meta.endpoint.addr {
+            IpAddress::Ipv4(address) => IpAddr::V4(address),
+            IpAddress::Ipv6(address) => IpAddr::V6(address),
+        };
+        Ok((len, SocketAddr::new(source, meta.endpoint.port)))
+    }
 }
 
-fn parse_ipv4(input: &str) -> Option<Ipv4Address> {
-    let mut octets = [0u8; 4];
-    let mut parts = input.split('.');
-    for slot in &mut octets {
-        let part = parts.next()?;
-        if part.is_empty() {
-            return None;
+/// Wait for a broadcast from `server`, giving up after a loop's worth of time so the hands
+/// keep moving
+async fn listen_broadcast(
+    socket: &mut UdpSocket<'_>,
+    broadcast: &mut BroadcastClient,
+    server: IpAddr,
+    pivot: u64,
+) -> Result<(NtpPacket, SyncSample), ClockError> {
+    let deadline = Instant::now() + Duration::from_secs(NETWORK_DETAILS_LOG_DELAY_SECS);
+    let mut packet = [0u8; NTP_MAX_PACKET_LEN];
+    loop {
+        let (len, meta) = with_deadline(deadline, socket.recv_from(&mut packet))
+            .await
+            .map_err(|_| ClockError::Timeout)?
+            .map_err(|_| ClockError::NetworkError)?;
+        let arrival = uptime_nanos();
+        if meta.endpoint.addr != IpAddress::from(server) {
+            continue;
         }
-        *slot = u8::from_str(part).ok()?;
+        return broadcast.receive(&packet[..len], arrival, pivot);
     }
-    if parts.next().is_some() {
-        return None;
-    }
-    Some(Ipv4Address::new(octets[0], octets[1], octets[2], octets[3]))
+}
+
+/// parse the NTP_KEY_ENV, if it's set but doesn't parse we carry on unauthenticated
+fn get_ntp_key() -> Option<(u32, KeyStore)> {
+    let keys = match KeyStore::from_keys_file(NTP_KEY_ENV?) {
+        Ok(keys) => keys,
+        Err(err) => {
+            warn!("Failed to parse NTP_KEY: {:?}", err);
+            return None;
+        }
+    };
+    let key_id = keys.key_ids().next()?;
+    info!("Authenticating NTP with key {}", key_id);
+    Some((key_id, keys))
+}
+
+/// parse the NTP_SERVER_ENV (IPv4 or IPv6) or return the default NTP server
+fn get_ntp_server() -> IpAddr {
+    IpAddr::from_str(NTP_SERVER_ENV).unwrap_or(DEFAULT_NTP_SERVER)
+}
+
+/// parse IPV6_ADDRESS_ENV and IPV6_GATEWAY_ENV, without an address there's no static IPv6
+fn get_ipv6_config() -> Option<StaticConfigV6> {
+    let (address, prefix) = IPV6_ADDRESS_ENV?.split_once('/')?;
+    let address = Ipv6Addr::from_str(address).ok()?;
+    let prefix = u8::from_str(prefix).ok().filter(|prefix| *prefix <= 128)?;
+    Some(StaticConfigV6 {
+        address: Ipv6Cidr::new(address, prefix),
+        gateway: IPV6_GATEWAY_ENV.and_then(|gateway| Ipv6Addr::from_str(gateway).ok()),
+        dns_servers: Default::default(),
+    })
 }
 
 fn parse_u16(input: &str) -> Option<u16> {
@@ -0,0 +1,68 @@
+use core::cell::RefCell;
+
+use embassy_executor::Spawner;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use ntp_clock::constants::{NTP_MAX_PACKET_LEN, NTP_PORT};
+use ntp_clock::discipline::ClockDiscipline;
+use ntp_clock::server::NtpServer;
+use static_cell::StaticCell;
+
+use crate::uptime_nanos;
+
+static SERVER_RX_META: StaticCell<[PacketMetadata; 4]> = StaticCell::new();
+static SERVER_RX_BUFFER: StaticCell<[u8; NTP_MAX_PACKET_LEN]> = StaticCell::new();
+static SERVER_TX_META: StaticCell<[PacketMetadata; 4]> = StaticCell::new();
+static SERVER_TX_BUFFER: StaticCell<[u8; 256]> = StaticCell::new();
+
+/// The clock we serve, `None` until the first sync so we never hand out a time we don't have
+static SERVED_CLOCK: Mutex<CriticalSectionRawMutex, RefCell<Option<(NtpServer, ClockDiscipline)>>> =
+    Mutex::new(RefCell::new(None));
+
+#[embassy_executor::task]
+async fn ntp_server_task(socket: UdpSocket<'static>) {
+    let mut request = [0u8; NTP_MAX_PACKET_LEN];
+    loop {
+        let Ok((len, meta)) = socket.recv_from(&mut request).await else {
+            continue;
+        };
+        let recv_uptime = uptime_nanos();
+        let response = SERVED_CLOCK.lock(|served| {
+            let served = served.borrow();
+            let (server, discipline) = served.as_ref()?;
+            let recv_time = discipline.now(recv_uptime)?;
+            let transmit_time = discipline.now(uptime_nanos())?;
+            server
+                .respond(&request[..len], recv_time, transmit_time)
+                .ok()?
+                .as_bytes()
+                .ok()
+        });
+        if let Some(response) = response {
+            let _ = socket.send_to(&response, meta.endpoint).await;
+        }
+    }
+}
+
+/// Answer NTP clients on port 123, once [update_ntp_server] has given us a time to serve.
+pub fn init_ntp_server(spawner: &Spawner, stack: embassy_net::Stack<'static>) {
+    let rx_meta = SERVER_RX_META.init([PacketMetadata::EMPTY; 4]);
+    let rx_buffer = SERVER_RX_BUFFER.init([0u8; NTP_MAX_PACKET_LEN]);
+    let tx_meta = SERVER_TX_META.init([PacketMetadata::EMPTY; 4]);
+    let tx_buffer = SERVER_TX_BUFFER.init([0u8; 256]);
+    let mut socket = UdpSocket::new(stack, rx_meta, rx_buffer, tx_meta, tx_buffer);
+    if socket.bind(NTP_PORT).is_err() {
+        log::warn!("Failed to bind NTP server UDP socket");
+        return;
+    }
+    let _ = spawner.spawn(ntp_server_task(socket));
+    log::info!("Serving NTP on port {}", NTP_PORT);
+}
+
+/// Serve the time from `discipline`, described to clients by `server`. Call after each sync.
+pub fn update_ntp_server(server: &NtpServer, discipline: &ClockDiscipline) {
+    SERVED_CLOCK.lock(|served| {
+        served.replace(Some((server.clone(), discipline.clone())));
+    });
+}
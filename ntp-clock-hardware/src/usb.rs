@@ -1,7 +1,7 @@
 use core::fmt::Write;
 
 use embassy_executor::Spawner;
-use embassy_net::Ipv4Address;
+use embassy_net::IpAddress;
 use embassy_net::udp::{PacketMetadata, UdpSocket};
 use embassy_rp::bind_interrupts;
 use embassy_rp::peripherals::USB;
@@ -52,7 +52,7 @@ async fn usb_task(mut usb: UsbDevice<'static, Driver<'static, USB>>) {
 }
 
 #[embassy_executor::task]
-async fn syslog_task(socket: UdpSocket<'static>, server: Ipv4Address, port: u16) {
+async fn syslog_task(socket: UdpSocket<'static>, server: IpAddress, port: u16) {
     loop {
         let message = SYSLOG_CHANNEL.receive().await;
         let _ = socket.send_to(message.as_bytes(), (server, port)).await;
@@ -98,7 +98,7 @@ pub fn init_usb_logging(spawner: &Spawner, usb: embassy_rp::Peri<'static, USB>)
 pub fn init_syslog_logging(
     spawner: &Spawner,
     stack: embassy_net::Stack<'static>,
-    server: Ipv4Address,
+    server: IpAddress,
     port: u16,
 ) {
     let rx_meta = SYSLOG_RX_META.init([PacketMetadata::EMPTY; 1]);
@@ -0,0 +1,66 @@
+//! Querying servers from async code on [tokio], with the `tokio` feature.
+//!
+//! This is [NtpClient::update_with] over an [AsyncUdpTransport], sleeping between retries on
+//! tokio's timer. It follows the same [crate::transport::NtpQuery] as [NtpClient::update] and
+//! builds requests and checks responses with the same code, so many clients can wait on their
+//! servers at once, like [crate::pool::NtpPool::update_async] does.
+
+use core::time::Duration;
+use std::net::SocketAddr;
+
+use tokio::net::UdpSocket;
+use tokio::time::{sleep, timeout};
+
+use crate::error::ClockError;
+use crate::prelude::*;
+use crate::timesource::TimeSource;
+use crate::transport::AsyncNtpTransport;
+use crate::{NtpClient, local_address_for};
+
+impl<T: TimeSource + Clone> NtpClient<T> {
+    /// Query the server without blocking, see [NtpClient::update].
+    pub async fn update_async(&mut self) -> Result<SyncSample, ClockError> {
+        self.update_async_via(&mut AsyncUdpTransport::default())
+            .await
+    }
+
+    /// [NtpClient::update_async], sending and receiving with `transport`.
+    pub async fn update_async_via(
+        &mut self,
+        transport: &mut impl AsyncNtpTransport,
+    ) -> Result<SyncSample, ClockError> {
+        self.update_with(transport, sleep).await
+    }
+}
+
+/// [crate::transport::UdpTransport] on tokio, with a new socket for each request.
+#[derive(Debug, Default)]
+pub struct AsyncUdpTransport {
+    socket: Option<UdpSocket>,
+}
+
+impl AsyncNtpTransport for AsyncUdpTransport {
+    async fn send_to(&mut self, packet: &[u8], server: SocketAddr) -> Result<(), ClockError> {
+        let socket = UdpSocket::bind(local_address_for(server))
+            .await
+            .map_err(|_| ClockError::NetworkError)?;
+        let socket = self.socket.insert(socket);
+        socket
+            .send_to(packet, server)
+            .await
+            .map(|_| ())
+            .map_err(|_| ClockError::NetworkError)
+    }
+
+    async fn recv_from(
+        &mut self,
+        buffer: &mut [u8],
+        wait: Duration,
+    ) -> Result<(usize, SocketAddr), ClockError> {
+        let socket = self.socket.as_ref().ok_or(ClockError::NetworkError)?;
+        timeout(wait, socket.recv_from(buffer))
+            .await
+            .map_err(|_| ClockError::Timeout)?
+            .map_err(|err| crate::recv_error(&err))
+    }
+}
@@ -0,0 +1,295 @@
+//! Symmetric key authentication, the key ID and digest MAC from
+//! [RFC 5905 Section 7.3](https://www.rfc-editor.org/rfc/rfc5905#section-7.3) with the
+//! AES-CMAC digest from [RFC 8573](https://www.rfc-editor.org/rfc/rfc8573).
+
+use core::str::FromStr;
+
+use aes::Aes128;
+use cmac::Cmac;
+use heapless::Vec;
+use sha1::{Digest, Sha1};
+use subtle::ConstantTimeEq;
+
+use crate::constants::{NTP_MAX_DIGEST_LEN, NTP_MAX_KEY_LEN, NTP_MAX_KEYS};
+use crate::error::ClockError;
+use crate::message::{Mac, NtpMessage};
+
+/// Longest secret ntpd treats as ASCII, anything longer is hex
+const MAX_ASCII_KEY_LEN: usize = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MacAlgorithm {
+    /// MD5 over the key then the message, deprecated by RFC 8573 but still widely deployed
+    Md5,
+    /// SHA-1 over the key then the message
+    Sha1,
+    /// AES-128-CMAC keyed with the secret, RFC 8573
+    Aes128Cmac,
+}
+
+impl MacAlgorithm {
+    pub fn digest_len(&self) -> usize {
+        match self {
+            MacAlgorithm::Md5 | MacAlgorithm::Aes128Cmac => 16,
+            MacAlgorithm::Sha1 => 20,
+        }
+    }
+}
+
+impl FromStr for MacAlgorithm {
+    type Err = ClockError;
+
+    /// Parse the key type names used in ntpd keys files.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("MD5") || s == "M" {
+            Ok(MacAlgorithm::Md5)
+        } else if s.eq_ignore_ascii_case("SHA1") || s.eq_ignore_ascii_case("SHA-1") {
+            Ok(MacAlgorithm::Sha1)
+        } else if s.eq_ignore_ascii_case("AES128CMAC") || s.eq_ignore_ascii_case("AES-128-CMAC") {
+            Ok(MacAlgorithm::Aes128Cmac)
+        } else {
+            Err(ClockError::InvalidKey)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SymmetricKey {
+    pub algorithm: MacAlgorithm,
+    secret: Vec<u8, NTP_MAX_KEY_LEN>,
+}
+
+impl SymmetricKey {
+    /// AES-128-CMAC needs a 16 byte secret, the others take anything up to [NTP_MAX_KEY_LEN].
+    pub fn new(algorithm: MacAlgorithm, secret: &[u8]) -> Result<Self, ClockError> {
+        if secret.is_empty() || (algorithm == MacAlgorithm::Aes128Cmac && secret.len() != 16) {
+            return Err(ClockError::InvalidKey);
+        }
+        Ok(SymmetricKey {
+            algorithm,
+            secret: Vec::from_slice(secret).map_err(|_| ClockError::InvalidKey)?,
+        })
+    }
+
+    /// The digest of `data` under this key.
+    pub fn digest(&self, data: &[u8]) -> Result<Vec<u8, NTP_MAX_DIGEST_LEN>, ClockError> {
+        let digest = match self.algorithm {
+            MacAlgorithm::Md5 => {
+                let mut hasher = md5::Md5::new();
+                hasher.update(&self.secret);
+                hasher.update(data);
+                Vec::from_slice(&hasher.finalize())
+            }
+            MacAlgorithm::Sha1 => {
+                let mut hasher = Sha1::new();
+                hasher.update(&self.secret);
+                hasher.update(data);
+                Vec::from_slice(&hasher.finalize())
+            }
+            MacAlgorithm::Aes128Cmac => {
+                let mut mac = <Cmac<Aes128> as cmac::Mac>::new_from_slice(&self.secret)
+                    .map_err(|_| ClockError::InvalidKey)?;
+                cmac::Mac::update(&mut mac, data);
+                Vec::from_slice(&cmac::Mac::finalize(mac).into_bytes())
+            }
+        };
+        digest.map_err(|_| ClockError::InvalidKey)
+    }
+}
+
+/// The keys we trust, by key ID.
+#[derive(Clone, Debug, Default)]
+pub struct KeyStore {
+    keys: Vec<(u32, SymmetricKey), NTP_MAX_KEYS>,
+}
+
+impl KeyStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse an ntpd keys file, one `<key id> <type> <secret>` per line with `#` comments.
+    /// Secrets of up to 20 characters are ASCII, longer ones are hex.
+    pub fn from_keys_file(contents: &str) -> Result<Self, ClockError> {
+        let mut store = KeyStore::new();
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let (Some(key_id), Some(algorithm), Some(secret), None) =
+                (parts.next(), parts.next(), parts.next(), parts.next())
+            else {
+                return Err(ClockError::InvalidKey);
+            };
+            let key_id = u32::from_str(key_id).map_err(|_| ClockError::InvalidKey)?;
+            let algorithm = MacAlgorithm::from_str(algorithm)?;
+            let key = if secret.len() > MAX_ASCII_KEY_LEN || algorithm == MacAlgorithm::Aes128Cmac {
+                SymmetricKey::new(algorithm, &decode_hex(secret)?)?
+            } else {
+                SymmetricKey::new(algorithm, secret.as_bytes())?
+            };
+            store.insert(key_id, key)?;
+        }
+        Ok(store)
+    }
+
+    /// Add a key, replacing any with the same ID. Key ID 0 is reserved for crypto-NAKs.
+    pub fn insert(&mut self, key_id: u32, key: SymmetricKey) -> Result<(), ClockError> {
+        if key_id == 0 {
+            return Err(ClockError::InvalidKey);
+        }
+        if let Some(entry) = self.keys.iter_mut().find(|(id, _)| *id == key_id) {
+            entry.1 = key;
+            return Ok(());
+        }
+        self.keys
+            .push((key_id, key))
+            .map_err(|_| ClockError::InvalidKey)
+    }
+
+    pub fn get(&self, key_id: u32) -> Option<&SymmetricKey> {
+        self.keys
+            .iter()
+            .find(|(id, _)| *id == key_id)
+            .map(|(_, key)| key)
+    }
+
+    /// The IDs of the keys in the store, in the order they were added.
+    pub fn key_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.keys.iter().map(|(id, _)| *id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Add a MAC to the message using the key `key_id`.
+    pub fn sign(&self, key_id: u32, message: &mut NtpMessage) -> Result<(), ClockError> {
+        let key = self.get(key_id).ok_or(ClockError::UnknownKey(key_id))?;
+        // the MAC doesn't cover itself, but it changes how the last extension field is padded
+        message.mac = Some(Mac::new(key_id, &[])?);
+        let digest = key.digest(&message.authenticated_bytes()?)?;
+        message.mac = Some(Mac::new(key_id, &digest)?);
+        Ok(())
+    }
+
+    /// Check the message's MAC, returns the key ID it was signed with.
+    pub fn verify(&self, message: &NtpMessage) -> Result<u32, ClockError> {
+        let mac = message
+            .mac
+            .as_ref()
+            .ok_or(ClockError::AuthenticationFailed)?;
+        let key = self
+            .get(mac.key_id)
+            .ok_or(ClockError::UnknownKey(mac.key_id))?;
+        let expected = key.digest(&message.authenticated_bytes()?)?;
+        if bool::from(expected.as_slice().ct_eq(&mac.digest)) {
+            Ok(mac.key_id)
+        } else {
+            Err(ClockError::AuthenticationFailed)
+        }
+    }
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8, NTP_MAX_KEY_LEN>, ClockError> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(ClockError::InvalidKey);
+    }
+    let mut bytes = Vec::new();
+    for pair in hex.as_bytes().chunks(2) {
+        let pair = core::str::from_utf8(pair).map_err(|_| ClockError::InvalidKey)?;
+        let byte = u8::from_str_radix(pair, 16).map_err(|_| ClockError::InvalidKey)?;
+        bytes.push(byte).map_err(|_| ClockError::InvalidKey)?;
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::NtpPacket;
+
+    const KEYS: &str = "
+# id type secret
+1 MD5 password
+2 SHA1 0102030405060708090a0b0c0d0e0f1011121314
+3 AES128CMAC 2b7e151628aed2a6abf7158809cf4f3c
+";
+
+    #[test]
+    fn parses_keys_file() {
+        let keys = KeyStore::from_keys_file(KEYS).expect("keys should parse");
+        let md5 = keys.get(1).expect("key 1");
+        assert_eq!(md5.algorithm, MacAlgorithm::Md5);
+        assert_eq!(md5.secret, b"password");
+        assert_eq!(keys.get(2).expect("key 2").secret.len(), 20);
+        assert_eq!(
+            keys.get(3).expect("key 3").algorithm,
+            MacAlgorithm::Aes128Cmac
+        );
+        assert!(keys.get(4).is_none());
+        assert!(KeyStore::from_keys_file("1 MD5").is_err());
+        assert!(KeyStore::from_keys_file("0 MD5 password").is_err());
+        assert!(KeyStore::from_keys_file("1 AES128CMAC 00").is_err());
+    }
+
+    #[test]
+    fn digests_match_known_values() {
+        // RFC 4493 example 2
+        let cmac = SymmetricKey::new(
+            MacAlgorithm::Aes128Cmac,
+            &decode_hex("2b7e151628aed2a6abf7158809cf4f3c").expect("hex"),
+        )
+        .expect("key");
+        let data = decode_hex("6bc1bee22e409f96e93d7e117393172a").expect("hex");
+        assert_eq!(
+            cmac.digest(&data).expect("digest"),
+            decode_hex("070a16b46b4d4144f79bdd9dd04a287c").expect("hex")
+        );
+        // MD5("ab") and SHA1("ab"), split between key and data
+        let md5 = SymmetricKey::new(MacAlgorithm::Md5, b"a").expect("key");
+        assert_eq!(
+            md5.digest(b"b").expect("digest"),
+            decode_hex("187ef4436122d1cc2f40dc2b92f0eba0").expect("hex")
+        );
+        let sha1 = SymmetricKey::new(MacAlgorithm::Sha1, b"a").expect("key");
+        assert_eq!(
+            sha1.digest(b"b").expect("digest"),
+            decode_hex("da23614e02469a0d7c7bd1bdab5c9c474b1904dc").expect("hex")
+        );
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let keys = KeyStore::from_keys_file(KEYS).expect("keys should parse");
+        for key_id in 1..=3 {
+            let mut message = NtpMessage::new(NtpPacket::from_nanos(1_769_311_396_443_478_400));
+            keys.sign(key_id, &mut message).expect("should sign");
+            let bytes = message.to_bytes().expect("should serialize");
+            let algorithm = keys.get(key_id).expect("key").algorithm;
+            assert_eq!(bytes.len(), 48 + 4 + algorithm.digest_len());
+
+            let parsed = NtpMessage::parse(&bytes).expect("should parse");
+            assert_eq!(keys.verify(&parsed).expect("should verify"), key_id);
+
+            let mut tampered = parsed.clone();
+            tampered.header.stratum = 2;
+            assert!(matches!(
+                keys.verify(&tampered),
+                Err(ClockError::AuthenticationFailed)
+            ));
+        }
+
+        let unsigned = NtpMessage::new(NtpPacket::request());
+        assert!(matches!(
+            keys.verify(&unsigned),
+            Err(ClockError::AuthenticationFailed)
+        ));
+        assert!(matches!(
+            keys.sign(9, &mut NtpMessage::new(NtpPacket::request())),
+            Err(ClockError::UnknownKey(9))
+        ));
+    }
+}
@@ -0,0 +1,222 @@
+//! Listening for broadcast and multicast NTP, from
+//! [RFC 5905 Section 8](https://www.rfc-editor.org/rfc/rfc5905#section-8).
+//!
+//! A broadcast only carries the server's transmit time, so the delay to the server is
+//! measured first with ordinary client/server exchanges. Each broadcast then becomes a
+//! [SyncSample] with that round trip, and feeds the filter and discipline like any other.
+
+use core::net::IpAddr;
+
+use crate::auth::KeyStore;
+use crate::constants::NTP_BROADCAST_CALIBRATION_SAMPLES;
+use crate::error::ClockError;
+use crate::message::NtpMessage;
+use crate::packets::{NtpMode, NtpPacket};
+use crate::sample::SyncSample;
+use crate::unpack_ntp_packet;
+
+#[derive(Clone, Debug)]
+pub struct BroadcastClient {
+    /// The broadcast server we follow
+    pub server: IpAddr,
+    /// Lowest round trip to the server while calibrating, nanoseconds
+    delay: Option<i64>,
+    calibration_samples: usize,
+    /// Transmit time of the last broadcast we used, UNIX nanoseconds
+    last_transmit: u64,
+    /// When set, broadcasts must be signed with one of these keys
+    keys: Option<KeyStore>,
+}
+
+impl BroadcastClient {
+    pub fn new(server: IpAddr) -> Self {
+        BroadcastClient {
+            server,
+            delay: None,
+            calibration_samples: 0,
+            last_transmit: 0,
+            keys: None,
+        }
+    }
+
+    /// Only accept broadcasts signed with a key from `keys`.
+    pub fn with_authentication(self, keys: KeyStore) -> Self {
+        BroadcastClient {
+            keys: Some(keys),
+            ..self
+        }
+    }
+
+    /// Measure the delay to the server from a client/server exchange with it, the lowest
+    /// round trip seen is used for every broadcast.
+    pub fn calibrate(&mut self, sample: &SyncSample) {
+        self.delay = Some(
+            self.delay
+                .map_or(sample.delay, |delay| delay.min(sample.delay)),
+        );
+        self.calibration_samples = self.calibration_samples.saturating_add(1);
+    }
+
+    /// Have we made enough exchanges to trust the delay?
+    pub fn is_calibrated(&self) -> bool {
+        self.calibration_samples >= NTP_BROADCAST_CALIBRATION_SAMPLES
+    }
+
+    /// The round trip delay broadcasts are corrected by, nanoseconds.
+    pub fn delay(&self) -> Option<i64> {
+        self.delay
+    }
+
+    /// Turn a broadcast which arrived at `local_time` (T4) into a sample, decoding the server's
+    /// transmit time in the era nearest `pivot`. The server is taken to have sent it half the
+    /// calibrated round trip ago.
+    ///
+    /// Fails with [ClockError::NotCalibrated] until [Self::is_calibrated], and with
+    /// [ClockError::DuplicatePacket] for broadcasts no newer than the last one we used.
+    pub fn receive(
+        &mut self,
+        packet: &[u8],
+        local_time: u64,
+        pivot: u64,
+    ) -> Result<(NtpPacket, SyncSample), ClockError> {
+        let response = unpack_ntp_packet(packet)?;
+        if let Some(keys) = self.keys.as_ref() {
+            keys.verify(&NtpMessage::parse(packet)?)?;
+        }
+        response.check_usable(NtpMode::Broadcast)?;
+        let delay = match self.delay {
+            Some(delay) if self.is_calibrated() => delay.max(0) as u64,
+            _ => return Err(ClockError::NotCalibrated),
+        };
+        let transmit = response.transmit_time.to_unix_nanos_near(pivot);
+        if transmit <= self.last_transmit {
+            return Err(ClockError::DuplicatePacket);
+        }
+        self.last_transmit = transmit;
+
+        // as if we'd sent a request a round trip ago and the server answered instantly
+        let sample = SyncSample {
+            root_delay: response.root_delay_nanos().max(0) as u64,
+            root_dispersion: response.root_dispersion_nanos(),
+            ..SyncSample::new(
+                local_time.saturating_sub(delay),
+                transmit,
+                transmit,
+                local_time,
+                response.precision,
+            )
+        };
+        Ok((response, sample))
+    }
+
+    /// Wait for a broadcast from our server on `socket`, ignoring anything from elsewhere.
+    #[cfg(feature = "std")]
+    pub fn listen(
+        &mut self,
+        socket: &std::net::UdpSocket,
+    ) -> Result<(NtpPacket, SyncSample), ClockError> {
+        use crate::constants::{NTP_ERA_PIVOT_UNIX_NANOS, NTP_MAX_PACKET_LEN};
+
+        let mut packet = [0u8; NTP_MAX_PACKET_LEN];
+        loop {
+            let (len, source) = socket
+                .recv_from(&mut packet)
+                .map_err(|_| ClockError::NetworkError)?;
+            let local_time = crate::unix_nanos_now();
+            if source.ip() != self.server {
+                log::debug!("Ignoring NTP broadcast from {}", source);
+                continue;
+            }
+            let pivot = self.last_transmit.max(NTP_ERA_PIVOT_UNIX_NANOS);
+            return self.receive(&packet[..len], local_time, pivot);
+        }
+    }
+}
+
+/// A socket to listen for broadcasts on `port`, joined to the multicast `group` if there is one.
+/// With an IPv6 group the socket is IPv6, as IPv6 has no broadcast.
+#[cfg(feature = "std")]
+pub fn bind_broadcast_socket(
+    port: u16,
+    group: Option<IpAddr>,
+) -> Result<std::net::UdpSocket, ClockError> {
+    use std::net::{Ipv4Addr, Ipv6Addr, UdpSocket};
+
+    let socket = match group {
+        Some(IpAddr::V6(group)) => {
+            let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port))?;
+            // interface 0 lets the OS pick
+            socket.join_multicast_v6(&group, 0)?;
+            socket
+        }
+        Some(IpAddr::V4(group)) => {
+            let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+            socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+            socket
+        }
+        None => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?,
+    };
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::NtpTimestamp;
+    use core::net::Ipv4Addr;
+
+    const NOW: u64 = 1_769_311_396_443_478_400;
+
+    fn broadcast(transmit_time: u64) -> [u8; 48] {
+        let mut packet = NtpPacket::from_nanos(transmit_time);
+        packet.set_mode(NtpMode::Broadcast);
+        packet.transmit_time = NtpTimestamp::from_unix_nanos(transmit_time);
+        packet.as_bytes().expect("should pack")
+    }
+
+    fn calibrated(delay: u64) -> BroadcastClient {
+        let mut client = BroadcastClient::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        for extra in 0..NTP_BROADCAST_CALIBRATION_SAMPLES as u64 {
+            let round_trip = delay + extra * 1_000_000;
+            client.calibrate(&SyncSample::new(NOW, NOW, NOW, NOW + round_trip, -20));
+        }
+        client
+    }
+
+    #[test]
+    fn corrects_broadcasts_by_half_the_round_trip() {
+        let mut client = BroadcastClient::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        assert!(matches!(
+            client.receive(&broadcast(NOW), NOW, NOW),
+            Err(ClockError::NotCalibrated)
+        ));
+
+        let mut client = calibrated(10_000_000);
+        assert_eq!(client.delay(), Some(10_000_000));
+        // sent at NOW, 5ms on the wire, and our clock is 1s behind
+        let (_, sample) = client
+            .receive(&broadcast(NOW), NOW + 5_000_000 - 1_000_000_000, NOW)
+            .expect("broadcast should be used");
+        assert_eq!(sample.delay, 10_000_000);
+        assert!((sample.offset - 1_000_000_000).abs() < 1_000);
+    }
+
+    #[test]
+    fn rejects_duplicates_and_client_replies() {
+        let mut client = calibrated(10_000_000);
+        client
+            .receive(&broadcast(NOW), NOW, NOW)
+            .expect("first broadcast should be used");
+        assert!(matches!(
+            client.receive(&broadcast(NOW), NOW, NOW),
+            Err(ClockError::DuplicatePacket)
+        ));
+        let reply = NtpPacket::from_nanos(NOW + 1)
+            .as_bytes()
+            .expect("should pack");
+        assert!(matches!(
+            client.receive(&reply, NOW, NOW),
+            Err(ClockError::ModeMismatch(NtpMode::Server))
+        ));
+    }
+}
@@ -1,14 +1,161 @@
 #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-use clap::Parser;
+use clap::{Parser, Subcommand};
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use crate::error::ClockError;
 
 #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
 #[derive(Parser, Debug)]
+#[clap(subcommand_precedence_over_arg = true)]
 pub struct Cli {
     #[clap(long, default_value_t = false)]
     pub debug: bool,
-    #[clap(env = "NTP_SERVER")]
-    pub ntp_server: String,
+    /// One or more NTP servers, comma separated in the environment variable
+    #[clap(env = "NTP_SERVER", required = true, num_args = 1.., value_delimiter = ',')]
+    pub ntp_server: Vec<String>,
 
     #[clap(long, default_value_t = false)]
     pub show_angles: bool,
+
+    /// Query every address the servers resolve to and run clock selection across them
+    #[clap(long, default_value_t = false)]
+    pub pool: bool,
+
+    /// ntpd format keys file, requests are signed and responses must be authenticated
+    #[clap(long, env = "NTP_KEY_FILE", requires = "key_id")]
+    pub key_file: Option<std::path::PathBuf>,
+
+    /// Which key from the keys file to sign requests with
+    #[clap(long, env = "NTP_KEY_ID", requires = "key_file")]
+    pub key_id: Option<u32>,
+
+    /// After synchronizing with the server, serve the time to NTP clients on this address
+    /// (e.g. 0.0.0.0:123)
+    #[clap(long, env = "NTP_SERVE", conflicts_with = "pool")]
+    pub serve: Option<std::net::SocketAddr>,
+
+    /// A symmetric peer to back us up while serving (host:port), can be given more than once
+    #[clap(long, requires = "serve")]
+    pub peer: Vec<std::net::SocketAddr>,
+
+    /// Measure the delay to the server, then take the time from its broadcasts or multicasts
+    /// (224.0.1.1) on port 123
+    #[clap(long, default_value_t = false, conflicts_with_all = ["pool", "serve"])]
+    pub broadcast: bool,
+
+    /// How long to wait for each response, in milliseconds
+    #[clap(long, env = "NTP_TIMEOUT_MS", default_value_t = crate::constants::NTP_REQUEST_TIMEOUT_MILLIS)]
+    pub timeout_ms: u64,
+
+    /// How many times to ask a server address again when it doesn't answer, before trying its
+    /// next address
+    #[clap(long, env = "NTP_RETRIES", default_value_t = crate::constants::NTP_REQUEST_RETRIES)]
+    pub retries: u32,
+
+    /// Use Network Time Security, the server is an NTS-KE server (host or host:port)
+    #[cfg(feature = "nts")]
+    #[clap(long, default_value_t = false, conflicts_with_all = ["pool", "key_file", "broadcast"])]
+    pub nts: bool,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+impl Cli {
+    /// Reject the options which only work with a single server when there's more than one,
+    /// rather than quietly querying them as a pool.
+    pub fn check(&self) -> Result<(), ClockError> {
+        if self.ntp_server.len() <= 1 {
+            return Ok(());
+        }
+        if self.serve.is_some() {
+            return Err(ClockError::ConfigError(
+                "--serve follows a single upstream server".into(),
+            ));
+        }
+        if self.broadcast {
+            return Err(ClockError::ConfigError(
+                "--broadcast listens to a single server".into(),
+            ));
+        }
+        if self.command.is_some() {
+            return Err(ClockError::ConfigError(
+                "control queries go to a single server".into(),
+            ));
+        }
+        #[cfg(feature = "nts")]
+        if self.nts {
+            return Err(ClockError::ConfigError(
+                "--nts takes a single NTS-KE server".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Diagnostics from the server with NTP control (mode 6) queries, like `ntpq`
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// List the server's peers, like `ntpq -p`
+    Peers,
+    /// Print the server's system variables, or a peer's
+    Readvar {
+        /// Association ID of the peer, as listed by `peers`
+        #[clap(long, default_value_t = 0)]
+        association: u16,
+    },
+}
+
+#[cfg(test)]
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Cli {
+        Cli::try_parse_from(["ntp-clock"].iter().chain(args)).expect("should parse arguments")
+    }
+
+    #[test]
+    fn single_server_options_need_one_server() {
+        assert!(parse(&["--serve", "127.0.0.1:123", "a"]).check().is_ok());
+        assert!(matches!(
+            parse(&["--serve", "127.0.0.1:123", "a", "b"]).check(),
+            Err(ClockError::ConfigError(_))
+        ));
+        assert!(parse(&["a", "b"]).check().is_ok());
+    }
+
+    #[test]
+    fn broadcast_needs_one_server() {
+        assert!(parse(&["--broadcast", "a"]).check().is_ok());
+        assert!(matches!(
+            parse(&["--broadcast", "a", "b"]).check(),
+            Err(ClockError::ConfigError(_))
+        ));
+    }
+
+    #[test]
+    fn control_queries_need_one_server() {
+        assert!(parse(&["a", "peers"]).check().is_ok());
+        assert!(matches!(
+            parse(&["a", "b", "peers"]).check(),
+            Err(ClockError::ConfigError(_))
+        ));
+        assert!(matches!(
+            parse(&["a", "b", "readvar", "--association", "3"]).check(),
+            Err(ClockError::ConfigError(_))
+        ));
+    }
+
+    #[cfg(feature = "nts")]
+    #[test]
+    fn nts_needs_one_server() {
+        assert!(parse(&["--nts", "a"]).check().is_ok());
+        assert!(matches!(
+            parse(&["--nts", "a", "b"]).check(),
+            Err(ClockError::ConfigError(_))
+        ));
+    }
 }
@@ -1,3 +1,4 @@
+use crate::leap::{LeapHandling, LeapSecond};
 use crate::packets::NtpPacket;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -42,8 +43,13 @@ fn normalize(value: f64, modulo: f64) -> f64 {
 }
 
 pub fn hand_angles(ntp_response: &NtpPacket) -> HandAngles {
-    let total_seconds = ntp_response.ref_time / 1_000_000_000;
-    let nanos = (ntp_response.ref_time % 1_000_000_000) as f64;
+    hand_angles_at(ntp_response.ref_time.to_unix_nanos())
+}
+
+/// Hand angles in degrees for a UNIX time in nanoseconds.
+pub fn hand_angles_at(unix_nanos: u64) -> HandAngles {
+    let total_seconds = unix_nanos / 1_000_000_000;
+    let nanos = (unix_nanos % 1_000_000_000) as f64;
 
     let hour = (total_seconds / 3600) % 12;
     let minute = (total_seconds / 60) % 60;
@@ -60,6 +66,16 @@ pub fn hand_angles(ntp_response: &NtpPacket) -> HandAngles {
     .normalize_degrees()
 }
 
+/// Hand angles in degrees for a leap-continuous time (see [crate::leap]), showing a pending
+/// leap second the way `handling` says.
+pub fn hand_angles_with_leap(
+    continuous_unix_nanos: u64,
+    leap: Option<&LeapSecond>,
+    handling: LeapHandling,
+) -> HandAngles {
+    hand_angles_at(handling.apply(leap, continuous_unix_nanos))
+}
+
 pub fn hand_angles_radians(ntp_response: &NtpPacket) -> HandAngles {
     hand_angles(ntp_response).to_radians().normalize_radians()
 }
@@ -75,5 +91,17 @@ mod tests {
         assert!((angles.hour - 97.5).abs() < 1e-9);
         assert!((angles.minute - 90.0).abs() < 1e-9);
         assert!((angles.second - 0.0).abs() < 1e-9);
+        assert_eq!(hand_angles_at(1_735_701_300_000_000_000u64), angles);
+    }
+
+    #[test]
+    fn hand_angles_hold_for_leap_second() {
+        // half a second into the extra second before 2017, the hand waits at 59
+        let new_year = 1_483_228_800_000_000_000u64;
+        let leap = LeapSecond::from_indicator(1, new_year - 86_400_000_000_000);
+        let angles =
+            hand_angles_with_leap(new_year - 500_000_000, leap.as_ref(), LeapHandling::Hold);
+        assert!((angles.second - 354.0).abs() < 1e-9);
+        assert!((angles.minute - 359.9).abs() < 1e-9);
     }
 }
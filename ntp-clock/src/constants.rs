@@ -1,3 +1,59 @@
 pub const NTP_MIN_PACKET_LEN: usize = 48;
+/// Largest NTP message we'll send or receive, the IPv6 minimum MTU so it won't fragment
+pub const NTP_MAX_PACKET_LEN: usize = 1280;
+/// Control (mode 6) message header length
+pub const NTP_CONTROL_HEADER_LEN: usize = 12;
+/// Most data a control message fragment carries
+pub const NTP_CONTROL_MAX_DATA_LEN: usize = 468;
+/// How long to wait for each fragment of a control response
+pub const NTP_CONTROL_TIMEOUT_SECS: u64 = 5;
+/// Most extension fields we'll keep from a message
+pub const NTP_MAX_EXTENSION_FIELDS: usize = 8;
+/// Largest extension field value we'll keep, in bytes
+pub const NTP_MAX_EXTENSION_VALUE_LEN: usize = 1024;
+/// Largest MAC digest, 160 bits for SHA-1
+pub const NTP_MAX_DIGEST_LEN: usize = 20;
+/// Most symmetric keys a key store holds
+pub const NTP_MAX_KEYS: usize = 16;
+/// Longest symmetric key secret, in bytes
+pub const NTP_MAX_KEY_LEN: usize = 32;
 
+/// Default NTS Key Establishment port, RFC 8915
+pub const NTS_KE_PORT: u16 = 4460;
 pub const NTP_PORT: u16 = 123;
+/// IPv4 multicast group for NTP broadcasts, ntp.mcast.net
+pub const NTP_MULTICAST_IPV4: core::net::Ipv4Addr = core::net::Ipv4Addr::new(224, 0, 1, 1);
+/// IPv6 multicast group for NTP broadcasts, site-local all NTP servers
+pub const NTP_MULTICAST_IPV6: core::net::Ipv6Addr =
+    core::net::Ipv6Addr::new(0xff05, 0, 0, 0, 0, 0, 0, 0x101);
+/// Client/server exchanges to measure the delay to a broadcast server before we trust its broadcasts
+pub const NTP_BROADCAST_CALIBRATION_SAMPLES: usize = 4;
+
+/// How long to wait for a response before asking again, milliseconds
+pub const NTP_REQUEST_TIMEOUT_MILLIS: u64 = 2_000;
+/// How many times to ask an address again when it doesn't answer
+pub const NTP_REQUEST_RETRIES: u32 = 2;
+/// Wait before the first retry, doubling for each after it, milliseconds
+pub const NTP_RETRY_BACKOFF_MILLIS: u64 = 250;
+/// Most addresses of one server a query falls through, any more are left out
+pub const NTP_MAX_SERVER_ADDRESSES: usize = 8;
+
+/// Precision of the local clock, log2 seconds (~1us)
+pub const NTP_LOCAL_PRECISION: i8 = -20;
+/// Frequency tolerance (PHI) from RFC 5905, in parts per million
+pub const NTP_PHI_PPM: u64 = 15;
+/// Maximum dispersion (MAXDISP) from RFC 5905, 16 seconds
+pub const NTP_MAX_DISPERSION_NANOS: u64 = 16_000_000_000;
+/// Minimum poll exponent (MINPOLL) from RFC 5905, 2^6 = 64 seconds
+pub const NTP_MIN_POLL_EXPONENT: i8 = 6;
+/// Maximum poll exponent (MAXPOLL) from RFC 5905, 2^17 = ~36 hours
+pub const NTP_MAX_POLL_EXPONENT: i8 = 17;
+/// Distance threshold (MAXDIST) from RFC 5905, 1 second
+pub const NTP_MAX_DISTANCE_NANOS: u64 = 1_000_000_000;
+/// Most servers we'll run clock selection over at once
+pub const NTP_MAX_SELECTION_CANDIDATES: usize = 16;
+/// Number of samples kept by the clock filter (NSTAGE) from RFC 5905
+pub const NTP_FILTER_STAGES: usize = 8;
+/// NTP timestamps are decoded to the era that puts them nearest this time (2026-01-01), which
+/// covers 1958 to 2094 without needing a known good time
+pub const NTP_ERA_PIVOT_UNIX_NANOS: u64 = 1_767_225_600_000_000_000;
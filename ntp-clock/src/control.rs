@@ -0,0 +1,194 @@
+//! Querying a server's status with NTP control (mode 6) messages, the way `ntpq` does, from
+//! [RFC 9327](https://www.rfc-editor.org/rfc/rfc9327).
+//!
+//! READSTAT lists the server's associations, and READVAR reads the system variables or one
+//! association's. Responses longer than a packet come back in fragments, which are put back
+//! together by their offsets.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::constants::{NTP_CONTROL_TIMEOUT_SECS, NTP_MAX_PACKET_LEN};
+use crate::error::ClockError;
+use crate::packets::{
+    ControlMessage, ControlOpcode, PeerStatus, parse_control_variables, parse_peer_statuses,
+};
+use crate::prelude::*;
+
+pub struct ControlClient {
+    pub server: SocketAddr,
+    /// Sequence number of the last request we sent
+    sequence: u16,
+}
+
+impl ControlClient {
+    pub fn new(server: &str) -> Result<Self, ClockError> {
+        Ok(Self::with_address(crate::resolve_server(server)?))
+    }
+
+    pub fn with_address(server: SocketAddr) -> Self {
+        ControlClient {
+            server,
+            sequence: 0,
+        }
+    }
+
+    /// The server's associations (READSTAT), each ID with its peer status word.
+    pub fn read_status(&mut self) -> Result<Vec<(u16, PeerStatus)>, ClockError> {
+        let data = self.query(ControlOpcode::ReadStatus, 0)?;
+        Ok(parse_peer_statuses(&data).collect())
+    }
+
+    /// The variables (READVAR) of association `association_id`, or the system variables for 0.
+    pub fn read_variables(
+        &mut self,
+        association_id: u16,
+    ) -> Result<Vec<(String, String)>, ClockError> {
+        let data = self.query(ControlOpcode::ReadVariables, association_id)?;
+        let data = String::from_utf8_lossy(&data);
+        Ok(parse_control_variables(&data)
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect())
+    }
+
+    /// Send one request and wait for every fragment of the response.
+    fn query(&mut self, opcode: ControlOpcode, association_id: u16) -> Result<Vec<u8>, ClockError> {
+        let socket = crate::bind_for(self.server)?;
+        socket.set_read_timeout(Some(Duration::from_secs(NTP_CONTROL_TIMEOUT_SECS)))?;
+
+        self.sequence = self.sequence.wrapping_add(1);
+        let request = ControlMessage::request(opcode, self.sequence, association_id);
+        socket
+            .send_to(&request.to_bytes()?, self.server)
+            .map_err(|_| ClockError::NetworkError)?;
+
+        let mut fragments = ControlFragments::new(request);
+        let mut buffer = [0u8; NTP_MAX_PACKET_LEN];
+        loop {
+            let (len, source) = socket
+                .recv_from(&mut buffer)
+                .map_err(|err| crate::recv_error(&err))?;
+            if source != self.server {
+                debug!("Ignoring control response from {}", source);
+                continue;
+            }
+            match ControlMessage::parse(&buffer[..len]) {
+                Ok(message) => {
+                    if let Some(data) = fragments.add(&message)? {
+                        return Ok(data);
+                    }
+                }
+                Err(err) => debug!("Ignoring control response from {}: {err}", source),
+            }
+        }
+    }
+}
+
+/// The fragments of the response to one request, which may arrive in any order.
+pub struct ControlFragments {
+    request: ControlMessage,
+    /// Each fragment's offset and data
+    fragments: Vec<(u16, Vec<u8>)>,
+    /// Where the response ends, once we've seen the fragment without the more bit
+    end: Option<usize>,
+}
+
+impl ControlFragments {
+    pub fn new(request: ControlMessage) -> Self {
+        ControlFragments {
+            request,
+            fragments: Vec::new(),
+            end: None,
+        }
+    }
+
+    /// Add a fragment, returning the whole response once every fragment has arrived.
+    /// Messages which don't answer the request are ignored, and a response with the error
+    /// bit set fails with [ClockError::ControlError].
+    pub fn add(&mut self, message: &ControlMessage) -> Result<Option<Vec<u8>>, ClockError> {
+        if !message.response
+            || message.opcode != self.request.opcode
+            || message.sequence != self.request.sequence
+        {
+            debug!("Ignoring control message which isn't our response");
+            return Ok(None);
+        }
+        if let Some(code) = message.error_code() {
+            return Err(ClockError::ControlError(code));
+        }
+        if !message.more {
+            self.end = Some(message.offset as usize + message.data.len());
+        }
+        if !self
+            .fragments
+            .iter()
+            .any(|(offset, _)| *offset == message.offset)
+        {
+            self.fragments.push((message.offset, message.data.to_vec()));
+        }
+
+        let Some(end) = self.end else {
+            return Ok(None);
+        };
+        self.fragments.sort_by_key(|(offset, _)| *offset);
+        let mut data = Vec::with_capacity(end);
+        for (offset, fragment) in &self.fragments {
+            if *offset as usize != data.len() {
+                // still waiting for the one before
+                return Ok(None);
+            }
+            data.extend_from_slice(fragment);
+        }
+        Ok((data.len() == end).then_some(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(offset: u16, data: &[u8], more: bool) -> ControlMessage {
+        let mut message = ControlMessage::request(ControlOpcode::ReadVariables, 7, 0);
+        message.response = true;
+        message.more = more;
+        message.offset = offset;
+        message.data = heapless::Vec::from_slice(data).expect("fits");
+        message
+    }
+
+    #[test]
+    fn reassembles_fragments_in_any_order() {
+        let mut fragments =
+            ControlFragments::new(ControlMessage::request(ControlOpcode::ReadVariables, 7, 0));
+        assert!(matches!(
+            fragments.add(&fragment(8, b"=2", false)),
+            Ok(None)
+        ));
+        // a response to some other request
+        let mut other = fragment(0, b"nope", true);
+        other.sequence = 6;
+        assert!(matches!(fragments.add(&other), Ok(None)));
+        assert!(matches!(
+            fragments.add(&fragment(0, b"a=1, b", true)),
+            Ok(None)
+        ));
+        let data = fragments
+            .add(&fragment(6, b"  ", true))
+            .expect("should add")
+            .expect("should be complete");
+        assert_eq!(data, b"a=1, b  =2");
+    }
+
+    #[test]
+    fn error_responses_fail() {
+        let mut fragments =
+            ControlFragments::new(ControlMessage::request(ControlOpcode::ReadVariables, 7, 9));
+        let mut error = fragment(0, b"", false);
+        error.error = true;
+        error.status = 4 << 8;
+        assert!(matches!(
+            fragments.add(&error),
+            Err(ClockError::ControlError(4))
+        ));
+    }
+}
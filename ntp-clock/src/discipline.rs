@@ -0,0 +1,345 @@
+//! Local clock discipline, loosely following
+//! [RFC 5905 Section 11.3](https://www.rfc-editor.org/rfc/rfc5905#section-11.3).
+//!
+//! This keeps a time estimate on top of a monotonic tick source (`std::time::Instant`,
+//! `embassy_time::Instant` or similar, converted to nanoseconds by the caller) and corrects
+//! both its phase and frequency from the server times we get back, so the estimate stays
+//! accurate between polls and through short outages.
+
+use core::time::Duration;
+
+use crate::constants::NTP_MIN_POLL_EXPONENT;
+use crate::leap::{LeapHandling, LeapSecond};
+use crate::sample::SyncSample;
+
+/// Offsets larger than this step the clock instead of slewing it (STEPT)
+pub const STEP_THRESHOLD_NANOS: u64 = 128_000_000;
+/// Largest frequency correction we'll apply, 500 PPM (MAXFREQ), in parts per trillion
+pub const MAX_FREQUENCY_PPT: i64 = 500_000_000;
+/// Shortest interval we'll measure the initial frequency over
+const MIN_FREQUENCY_INTERVAL_NANOS: u64 = 16_000_000_000;
+/// The phase correction is spread over this many poll intervals
+const PHASE_GAIN: u128 = 2;
+/// The frequency correction is damped by the square of this many poll intervals
+const FREQUENCY_GAIN: i128 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisciplineState {
+    /// We haven't seen a time yet
+    Unset,
+    /// The time is set, waiting for the next update to measure the frequency
+    Frequency,
+    /// Tracking phase and frequency
+    Sync,
+}
+
+/// What [ClockDiscipline::update] did with the time it was given.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisciplineAction {
+    /// The first time we've seen, the clock was set to it
+    Set,
+    /// The offset (nanoseconds) was too large to slew, so the clock was stepped
+    Step(i64),
+    /// The offset (nanoseconds) is being corrected gradually
+    Slew(i64),
+    /// The update was no newer than the last one and was ignored
+    Ignored,
+}
+
+#[derive(Clone, Debug)]
+pub struct ClockDiscipline {
+    state: DisciplineState,
+    /// Monotonic time the estimate was last rebased, nanoseconds
+    base_monotonic: u64,
+    /// Estimated time at `base_monotonic`, UNIX nanoseconds
+    base_time: u64,
+    /// Phase correction being slewed in since `base_monotonic`, nanoseconds
+    phase: i64,
+    /// How much faster the real time runs than the tick source, parts per trillion
+    frequency: i64,
+    /// Monotonic time of the last accepted update, nanoseconds
+    last_update: u64,
+    /// Interval we expect between updates, nanoseconds
+    poll_interval: u64,
+    /// The leap second servers have announced, `base_time` runs straight through it until
+    /// it's been fully applied
+    leap: Option<LeapSecond>,
+}
+
+impl Default for ClockDiscipline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClockDiscipline {
+    pub const fn new() -> Self {
+        ClockDiscipline {
+            state: DisciplineState::Unset,
+            base_monotonic: 0,
+            base_time: 0,
+            phase: 0,
+            frequency: 0,
+            last_update: 0,
+            poll_interval: (1u64 << NTP_MIN_POLL_EXPONENT) * 1_000_000_000,
+            leap: None,
+        }
+    }
+
+    /// Set the interval we expect between updates, which sets the loop time constant.
+    pub fn set_poll_interval(&mut self, poll_interval: Duration) {
+        self.poll_interval = (poll_interval.as_nanos() as u64).max(1_000_000_000);
+    }
+
+    pub fn state(&self) -> DisciplineState {
+        self.state
+    }
+
+    pub fn is_synchronized(&self) -> bool {
+        self.state == DisciplineState::Sync
+    }
+
+    /// The frequency correction, parts per billion (nanoseconds per second)
+    pub fn frequency_ppb(&self) -> i64 {
+        self.frequency / 1_000
+    }
+
+    /// Forget everything, including the frequency and any pending leap second.
+    pub fn reset(&mut self) {
+        *self = Self {
+            poll_interval: self.poll_interval,
+            ..Self::new()
+        };
+    }
+
+    /// The pending leap second, if there is one.
+    pub fn leap(&self) -> Option<LeapSecond> {
+        self.leap
+    }
+
+    /// Record the leap second announced by the servers at `monotonic` nanoseconds. Servers
+    /// stop announcing a leap once it has happened, so clearing it after that is ignored.
+    pub fn set_leap(&mut self, monotonic: u64, leap: Option<LeapSecond>) {
+        let continuous = self.continuous(monotonic);
+        match (self.leap, continuous) {
+            (Some(pending), Some(continuous)) if continuous >= pending.at => {}
+            _ => self.leap = leap,
+        }
+    }
+
+    /// The estimated time at `monotonic` nanoseconds, in UNIX nanoseconds. This steps at a
+    /// leap second like UTC does.
+    pub fn now(&self, monotonic: u64) -> Option<u64> {
+        self.display_time(monotonic, LeapHandling::Step)
+    }
+
+    /// The time to show at `monotonic` nanoseconds, in UNIX nanoseconds, with any leap
+    /// second shown the way `handling` says.
+    pub fn display_time(&self, monotonic: u64, handling: LeapHandling) -> Option<u64> {
+        self.continuous(monotonic)
+            .map(|continuous| handling.apply(self.leap.as_ref(), continuous))
+    }
+
+    /// The estimated time counting straight through a pending leap second.
+    fn continuous(&self, monotonic: u64) -> Option<u64> {
+        if self.state == DisciplineState::Unset {
+            return None;
+        }
+        let elapsed = monotonic.saturating_sub(self.base_monotonic) as i128;
+        let drift = elapsed * self.frequency as i128 / 1_000_000_000_000;
+        let slew_period = (self.poll_interval as u128 * PHASE_GAIN) as i128;
+        let slew = self.phase as i128 * elapsed.min(slew_period) / slew_period;
+        let time = self.base_time as i128 + elapsed + drift + slew;
+        Some(time.clamp(0, u64::MAX as i128) as u64)
+    }
+
+    /// Feed in a sample, `monotonic` is the tick source reading when the response arrived (T4).
+    pub fn update_from_sample(&mut self, monotonic: u64, sample: &SyncSample) -> DisciplineAction {
+        self.update(monotonic, sample.server_time())
+    }
+
+    /// Feed in the `server_time` (UNIX nanoseconds) observed at `monotonic` nanoseconds.
+    pub fn update(&mut self, monotonic: u64, server_time: u64) -> DisciplineAction {
+        let action = self.discipline(monotonic, server_time);
+        // once the leap has been shown it becomes part of the time
+        if let Some(leap) = self.leap
+            && self.base_time
+                >= leap
+                    .at
+                    .saturating_add(LeapHandling::MAX_DURATION_AFTER.as_nanos() as u64)
+        {
+            self.base_time = leap.to_utc(self.base_time);
+            self.leap = None;
+        }
+        action
+    }
+
+    fn discipline(&mut self, monotonic: u64, server_time: u64) -> DisciplineAction {
+        // compare in UTC, which is what the servers send, but keep the estimate continuous
+        let (predicted, continuous) = match (self.now(monotonic), self.continuous(monotonic)) {
+            (Some(predicted), Some(continuous)) => (predicted, continuous),
+            _ => {
+                let server_time = self
+                    .leap
+                    .map_or(server_time, |leap| leap.to_continuous(server_time));
+                self.rebase(monotonic, server_time, 0);
+                self.state = DisciplineState::Frequency;
+                return DisciplineAction::Set;
+            }
+        };
+        if monotonic <= self.last_update {
+            return DisciplineAction::Ignored;
+        }
+        let offset = (server_time as i128 - predicted as i128)
+            .clamp(i64::MIN as i128, i64::MAX as i128) as i64;
+        let interval = monotonic - self.last_update;
+        let server_time = (continuous as i128 + offset as i128).max(0) as u64;
+
+        if offset.unsigned_abs() > STEP_THRESHOLD_NANOS {
+            self.rebase(monotonic, server_time, 0);
+            return DisciplineAction::Step(offset);
+        }
+
+        match self.state {
+            DisciplineState::Frequency => {
+                if interval >= MIN_FREQUENCY_INTERVAL_NANOS {
+                    // measure the frequency directly from the drift since the clock was set
+                    self.adjust_frequency(offset as i128 * 1_000_000_000_000 / interval as i128);
+                    self.state = DisciplineState::Sync;
+                }
+                self.rebase(monotonic, server_time, 0);
+                DisciplineAction::Step(offset)
+            }
+            _ => {
+                let time_constant = FREQUENCY_GAIN * self.poll_interval as i128;
+                self.adjust_frequency(
+                    offset as i128 * interval as i128 * 1_000_000_000_000
+                        / (time_constant * time_constant),
+                );
+                self.rebase(monotonic, continuous, offset);
+                DisciplineAction::Slew(offset)
+            }
+        }
+    }
+
+    fn adjust_frequency(&mut self, adjustment: i128) {
+        self.frequency = (self.frequency as i128 + adjustment)
+            .clamp(-MAX_FREQUENCY_PPT as i128, MAX_FREQUENCY_PPT as i128)
+            as i64;
+    }
+
+    fn rebase(&mut self, monotonic: u64, time: u64, phase: i64) {
+        self.base_monotonic = monotonic;
+        self.base_time = time;
+        self.phase = phase;
+        self.last_update = monotonic;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+    const START: u64 = 1_769_311_396 * SECOND;
+
+    /// Server time after `monotonic` nanoseconds of a tick source which is `ppm` slow
+    fn server_time(monotonic: u64, ppm: i64) -> u64 {
+        START + monotonic + (monotonic as i128 * ppm as i128 / 1_000_000) as u64
+    }
+
+    #[test]
+    fn unset_until_first_update() {
+        let mut discipline = ClockDiscipline::new();
+        assert_eq!(discipline.now(0), None);
+        assert_eq!(discipline.update(0, START), DisciplineAction::Set);
+        assert_eq!(discipline.now(10 * SECOND), Some(START + 10 * SECOND));
+        assert_eq!(discipline.update(0, START), DisciplineAction::Ignored);
+    }
+
+    #[test]
+    fn tracks_frequency_error() {
+        let mut discipline = ClockDiscipline::new();
+        let poll = 64 * SECOND;
+        for step in 0..40 {
+            let monotonic = step * poll;
+            discipline.update(monotonic, server_time(monotonic, 40));
+        }
+        assert!(discipline.is_synchronized());
+        assert!(
+            (discipline.frequency_ppb() - 40_000).abs() < 100,
+            "frequency should converge on 40PPM, got {}ppb",
+            discipline.frequency_ppb()
+        );
+
+        // an hour with no updates should still be within a millisecond
+        let monotonic = 39 * poll + 3_600 * SECOND;
+        let estimate = discipline.now(monotonic).expect("discipline is set");
+        let error = estimate as i128 - server_time(monotonic, 40) as i128;
+        assert!(error.abs() < 1_000_000, "holdover error {error}ns");
+    }
+
+    #[test]
+    fn steps_large_offsets() {
+        let mut discipline = ClockDiscipline::new();
+        discipline.update(0, START);
+        let action = discipline.update(64 * SECOND, START + 65 * SECOND);
+        assert_eq!(action, DisciplineAction::Step(SECOND as i64));
+        assert_eq!(discipline.now(64 * SECOND), Some(START + 65 * SECOND));
+    }
+
+    #[test]
+    fn leap_second_does_not_step() {
+        // 2017-01-01 00:00:00 UTC
+        let new_year = 1_483_228_800 * SECOND;
+        let start = new_year - 600 * SECOND;
+        let mut discipline = ClockDiscipline::new();
+        discipline.update(0, start);
+        discipline.set_leap(0, LeapSecond::from_indicator(1, start));
+        discipline.update(64 * SECOND, start + 64 * SECOND);
+        assert!(discipline.is_synchronized());
+
+        // the servers repeat 23:59:59 and then stop announcing the leap
+        let monotonic = 600 * SECOND;
+        assert_eq!(discipline.now(monotonic), Some(new_year - SECOND));
+        assert_eq!(
+            discipline.display_time(monotonic, LeapHandling::Hold),
+            Some(new_year - SECOND)
+        );
+        let monotonic = 640 * SECOND;
+        discipline.set_leap(monotonic, None);
+        assert!(
+            discipline.leap().is_some(),
+            "the leap is kept until it's applied"
+        );
+        let action = discipline.update(monotonic, new_year + 39 * SECOND);
+        assert_eq!(action, DisciplineAction::Slew(0));
+        assert_eq!(discipline.now(monotonic), Some(new_year + 39 * SECOND));
+
+        // a day later it's folded into the estimate
+        let monotonic = 600 * SECOND + 90_000 * SECOND;
+        let utc = new_year + 89_999 * SECOND;
+        assert_eq!(discipline.update(monotonic, utc), DisciplineAction::Slew(0));
+        assert_eq!(discipline.leap(), None);
+        assert_eq!(discipline.now(monotonic), Some(utc));
+        assert_eq!(
+            discipline.display_time(monotonic, LeapHandling::default()),
+            Some(utc)
+        );
+    }
+
+    #[test]
+    fn slews_small_offsets() {
+        let mut discipline = ClockDiscipline::new();
+        discipline.update(0, START);
+        discipline.update(64 * SECOND, START + 64 * SECOND);
+        assert!(discipline.is_synchronized());
+        let action = discipline.update(128 * SECOND, START + 128 * SECOND + 10_000_000);
+        assert_eq!(action, DisciplineAction::Slew(10_000_000));
+        // nothing applied yet, then half the phase plus the new frequency after one poll
+        let now = discipline.now(128 * SECOND).expect("discipline is set");
+        assert_eq!(now, START + 128 * SECOND);
+        let now = discipline.now(192 * SECOND).expect("discipline is set");
+        assert_eq!(now, START + 192 * SECOND + 5_000_000 + 1_111_111);
+    }
+}
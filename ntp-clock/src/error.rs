@@ -1,3 +1,4 @@
+use crate::packets::{KissCode, NtpMode};
 #[cfg(feature = "std")]
 use crate::prelude::*;
 
@@ -16,6 +17,42 @@ pub enum ClockError {
     PacketTooShort,
     InvalidIdentifier,
     InvalidVersion,
+    /// An extension field or MAC is malformed, or there are too many or they're too long
+    InvalidExtensionField,
+    /// A symmetric key or keys file entry is malformed
+    InvalidKey,
+    /// The packet has no key ID we know about
+    UnknownKey(u32),
+    /// The packet's MAC is missing or doesn't match its contents
+    AuthenticationFailed,
+    /// NTS key establishment failed or the server wouldn't agree to anything we support
+    NtsKeyExchange,
+    /// We've used every NTS cookie, a new key exchange is needed
+    NtsNoCookies,
+    /// The response didn't echo the transmit time of our outstanding request
+    OriginMismatch,
+    /// The response came from an address we didn't send the request to
+    UnexpectedSource,
+    /// The server sent a Kiss-o'-Death, or we're still backing off after one
+    KissOfDeath(KissCode),
+    /// Clock selection couldn't find a majority of servers which agree on the time
+    NoMajority,
+    /// The server says its clock isn't synchronized (leap indicator 3)
+    Unsynchronized,
+    /// The stratum is 0 without a Kiss-o'-Death code, or 16 and above (unsynchronized)
+    InvalidStratum(u8),
+    /// The server didn't fill in its transmit time
+    ZeroTransmitTime,
+    /// The server's root distance (nanoseconds) is over the distance threshold
+    ExcessiveRootDistance(u64),
+    /// The packet has a different mode to the one we expected
+    ModeMismatch(NtpMode),
+    /// We haven't measured the delay to the broadcast server yet
+    NotCalibrated,
+    /// A broadcast we've already seen, or one older than the last we used
+    DuplicatePacket,
+    /// A control (mode 6) request was refused, with the error code from the response
+    ControlError(u8),
 }
 
 #[cfg(feature = "std")]
@@ -34,6 +71,45 @@ impl std::fmt::Display for ClockError {
             ClockError::PacketTooShort => write!(f, "NTP packet too short"),
             ClockError::InvalidIdentifier => write!(f, "Invalid NTP identifier"),
             ClockError::InvalidVersion => write!(f, "Invalid NTP version"),
+            ClockError::InvalidExtensionField => write!(f, "Invalid NTP extension field"),
+            ClockError::InvalidKey => write!(f, "Invalid symmetric key"),
+            ClockError::UnknownKey(key_id) => write!(f, "Unknown NTP key ID {}", key_id),
+            ClockError::AuthenticationFailed => write!(f, "NTP packet failed authentication"),
+            ClockError::NtsKeyExchange => write!(f, "NTS key exchange failed"),
+            ClockError::NtsNoCookies => write!(f, "No NTS cookies left, key exchange needed"),
+            ClockError::OriginMismatch => {
+                write!(f, "NTP response does not match the outstanding request")
+            }
+            ClockError::UnexpectedSource => {
+                write!(f, "NTP response received from an unexpected address")
+            }
+            ClockError::NoMajority => write!(f, "No majority of NTP servers agree on the time"),
+            ClockError::Unsynchronized => write!(f, "NTP server clock is not synchronized"),
+            ClockError::InvalidStratum(stratum) => {
+                write!(f, "NTP server reported invalid stratum {}", stratum)
+            }
+            ClockError::ZeroTransmitTime => write!(f, "NTP response has no transmit time"),
+            ClockError::ExcessiveRootDistance(distance) => {
+                write!(f, "NTP server root distance too large: {}ns", distance)
+            }
+            ClockError::ModeMismatch(mode) => {
+                write!(f, "NTP packet has unexpected mode {:?}", mode)
+            }
+            ClockError::NotCalibrated => write!(f, "Broadcast delay not calibrated yet"),
+            ClockError::DuplicatePacket => write!(f, "Duplicate or stale NTP broadcast"),
+            ClockError::ControlError(code) => write!(
+                f,
+                "NTP control request failed: {}",
+                crate::packets::ControlMessage::error_description(*code)
+            ),
+            ClockError::KissOfDeath(code) => {
+                write!(
+                    f,
+                    "Kiss-o'-Death from server: {:?} ({})",
+                    code,
+                    code.description()
+                )
+            }
         }
     }
 }
@@ -60,10 +136,25 @@ impl From<ClockError> for ExitCode {
             ClockError::NetworkError => ExitCode::from(1),
             ClockError::InvalidResponse => ExitCode::from(2),
             ClockError::ConfigError(_) => ExitCode::from(3),
-            ClockError::NoTimeAvailable => ExitCode::from(4),
+            ClockError::NoTimeAvailable | ClockError::NotCalibrated => ExitCode::from(4),
             ClockError::Timeout => ExitCode::from(5),
             ClockError::Io => ExitCode::from(6),
             ClockError::PacketTooShort => ExitCode::from(7),
+            ClockError::OriginMismatch | ClockError::DuplicatePacket => ExitCode::from(8),
+            ClockError::UnexpectedSource => ExitCode::from(9),
+            ClockError::KissOfDeath(_) => ExitCode::from(10),
+            ClockError::NoMajority => ExitCode::from(11),
+            ClockError::Unsynchronized
+            | ClockError::InvalidStratum(_)
+            | ClockError::ZeroTransmitTime
+            | ClockError::ExcessiveRootDistance(_)
+            | ClockError::ModeMismatch(_) => ExitCode::from(12),
+            ClockError::InvalidKey
+            | ClockError::UnknownKey(_)
+            | ClockError::AuthenticationFailed
+            | ClockError::NtsKeyExchange
+            | ClockError::NtsNoCookies => ExitCode::from(13),
+            ClockError::ControlError(_) => ExitCode::from(14),
             _ => ExitCode::from(1),
         }
     }
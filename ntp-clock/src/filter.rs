@@ -0,0 +1,177 @@
+//! The per-peer clock filter from
+//! [RFC 5905 Section 10](https://www.rfc-editor.org/rfc/rfc5905#section-10), which keeps the
+//! last few samples from a server and picks the one with the lowest delay, since that's the
+//! one least affected by network queueing.
+
+use heapless::HistoryBuf;
+
+use crate::constants::{NTP_FILTER_STAGES, NTP_MAX_DISPERSION_NANOS, NTP_PHI_PPM};
+use crate::sample::{SyncSample, root_distance};
+
+/// The filter's current best estimate for a peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FilterEstimate {
+    /// The lowest-delay sample in the window
+    pub sample: SyncSample,
+    /// Offset of the selected sample, nanoseconds
+    pub offset: i64,
+    /// Delay of the selected sample, nanoseconds
+    pub delay: i64,
+    /// Weighted sum of the aged sample dispersions, nanoseconds
+    pub dispersion: u64,
+    /// RMS difference between the selected offset and the rest of the window, nanoseconds
+    pub jitter: u64,
+}
+
+impl FilterEstimate {
+    /// Root distance of the selected sample, using the filtered dispersion.
+    pub fn distance(&self) -> u64 {
+        root_distance(self.delay, self.dispersion, &self.sample)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ClockFilter {
+    samples: HistoryBuf<SyncSample, NTP_FILTER_STAGES>,
+}
+
+impl ClockFilter {
+    pub const fn new() -> Self {
+        ClockFilter {
+            samples: HistoryBuf::new(),
+        }
+    }
+
+    /// Add a sample, pushing out the oldest one when the window is full, and return the
+    /// new estimate.
+    pub fn add(&mut self, sample: SyncSample) -> FilterEstimate {
+        self.samples.write(sample);
+        // can't be None, we just wrote a sample
+        self.estimate(sample.t4).unwrap_or(FilterEstimate {
+            sample,
+            offset: sample.offset,
+            delay: sample.delay,
+            dispersion: sample.dispersion,
+            jitter: 0,
+        })
+    }
+
+    /// The estimate from the samples in the window, with dispersions aged to `now` (UNIX nanoseconds).
+    pub fn estimate(&self, now: u64) -> Option<FilterEstimate> {
+        let mut sorted: heapless::Vec<(SyncSample, u64), NTP_FILTER_STAGES> = self
+            .samples
+            .oldest_ordered()
+            .map(|sample| (*sample, aged_dispersion(sample, now)))
+            .collect();
+        sorted.sort_unstable_by_key(|(sample, _)| sample.delay);
+        let (best, _) = *sorted.first()?;
+
+        // each sample counts half as much as the one before it
+        let dispersion = sorted
+            .iter()
+            .enumerate()
+            .map(|(stage, (_, dispersion))| dispersion >> (stage + 1))
+            .fold(0u64, |acc, value| acc.saturating_add(value));
+
+        let jitter = if sorted.len() > 1 {
+            let sum = sorted
+                .iter()
+                .skip(1)
+                .map(|(sample, _)| {
+                    let diff = (sample.offset as i128 - best.offset as i128).unsigned_abs();
+                    diff.saturating_mul(diff)
+                })
+                .fold(0u128, |acc, value| acc.saturating_add(value));
+            (sum / (sorted.len() as u128 - 1)).isqrt() as u64
+        } else {
+            0
+        };
+
+        Some(FilterEstimate {
+            sample: best,
+            offset: best.offset,
+            delay: best.delay,
+            dispersion: dispersion.min(NTP_MAX_DISPERSION_NANOS),
+            jitter,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+}
+
+/// Dispersion grows at PHI from when the sample was taken.
+fn aged_dispersion(sample: &SyncSample, now: u64) -> u64 {
+    let age = now.saturating_sub(sample.t4) as u128;
+    let growth = (age * NTP_PHI_PPM as u128 / 1_000_000) as u64;
+    sample
+        .dispersion
+        .saturating_add(growth)
+        .min(NTP_MAX_DISPERSION_NANOS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    /// A sample taken at `at` seconds with the given offset and delay in milliseconds
+    fn sample(at: u64, offset_ms: i64, delay_ms: u64) -> SyncSample {
+        let t1 = 1_000_000 * SECOND + at * SECOND;
+        let t4 = t1 + delay_ms * 1_000_000;
+        let t2 = (t1 as i64 + offset_ms * 1_000_000) as u64 + delay_ms * 500_000;
+        SyncSample::new(t1, t2, t2, t4, -20)
+    }
+
+    #[test]
+    fn picks_lowest_delay() {
+        let mut filter = ClockFilter::new();
+        filter.add(sample(0, 10, 50));
+        filter.add(sample(64, 2, 5));
+        let estimate = filter.add(sample(128, 20, 80));
+        assert_eq!(filter.len(), 3);
+        assert_eq!(estimate.offset, 2_000_000);
+        assert_eq!(estimate.delay, 5_000_000);
+        // sqrt((8ms^2 + 18ms^2) / 2)
+        assert_eq!(estimate.jitter, 13_928_388);
+    }
+
+    #[test]
+    fn window_is_eight_stages() {
+        let mut filter = ClockFilter::new();
+        filter.add(sample(0, 1, 1));
+        for stage in 1..=NTP_FILTER_STAGES as u64 {
+            filter.add(sample(stage * 64, 7, 30));
+        }
+        assert_eq!(filter.len(), NTP_FILTER_STAGES);
+        let estimate = filter
+            .estimate(1_000_000 * SECOND + 600 * SECOND)
+            .expect("filter has samples");
+        assert_eq!(
+            estimate.offset, 7_000_000,
+            "oldest low-delay sample should have been pushed out"
+        );
+        assert_eq!(estimate.jitter, 0);
+    }
+
+    #[test]
+    fn dispersion_ages() {
+        let mut filter = ClockFilter::new();
+        let fresh = filter.add(sample(0, 0, 10));
+        let aged = filter
+            .estimate(fresh.sample.t4 + 1_000 * SECOND)
+            .expect("filter has samples");
+        // 15PPM over 1000s is 15ms, halved for the first stage
+        assert_eq!(aged.dispersion - fresh.dispersion, 7_500_000);
+    }
+}
@@ -0,0 +1,275 @@
+//! Leap seconds, announced by the leap indicator in server responses. A leap second is
+//! inserted (or, in theory, deleted) in the last minute of the current UTC month, see
+//! [RFC 5905 Section 7.3](https://www.rfc-editor.org/rfc/rfc5905#section-7.3).
+//!
+//! Times here are "leap-continuous": counted as if the pending leap never happens, so they
+//! keep ticking through the leap second. [LeapHandling] turns them into the time to show.
+
+use core::time::Duration;
+
+const SECOND: u64 = 1_000_000_000;
+const SECONDS_PER_DAY: i64 = 86_400;
+/// Shortest smear we'll do, the second has to go somewhere
+const MIN_SMEAR: Duration = Duration::from_secs(2);
+/// Longest smear we'll do, so it's finished a day after the leap
+const MAX_SMEAR: Duration = Duration::from_secs(2 * SECONDS_PER_DAY as u64);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LeapKind {
+    /// 23:59:60 is inserted, the last minute has 61 seconds
+    Insert,
+    /// 23:59:59 is skipped, the last minute has 59 seconds
+    Delete,
+}
+
+/// A pending leap second.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LeapSecond {
+    pub kind: LeapKind,
+    /// Midnight UTC at the end of the leap, UNIX nanoseconds
+    pub at: u64,
+}
+
+impl LeapSecond {
+    /// The leap announced by a leap indicator received at `unix_nanos`, if there is one.
+    pub fn from_indicator(leap_indicator: u8, unix_nanos: u64) -> Option<Self> {
+        let kind = match leap_indicator {
+            1 => LeapKind::Insert,
+            2 => LeapKind::Delete,
+            _ => return None,
+        };
+        Some(LeapSecond {
+            kind,
+            at: end_of_month(unix_nanos),
+        })
+    }
+
+    /// The UTC time a leap-continuous time would be, stepping at the leap like NTP servers
+    /// do, so an inserted second repeats 23:59:59.
+    pub fn to_utc(&self, continuous: u64) -> u64 {
+        LeapHandling::Step.apply(Some(self), continuous)
+    }
+
+    /// The leap-continuous time for a UTC time, which is ambiguous in the repeated second.
+    pub fn to_continuous(&self, utc: u64) -> u64 {
+        match self.kind {
+            LeapKind::Insert if utc >= self.at => utc.saturating_add(SECOND),
+            LeapKind::Delete if utc >= self.at => utc.saturating_sub(SECOND),
+            _ => utc,
+        }
+    }
+}
+
+/// How the clock shows a leap second.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LeapHandling {
+    /// Jump at the leap like UTC does, the hands go back a second (or skip one)
+    Step,
+    /// Hold the second hand at 59 for the extra second, deleted seconds are skipped
+    Hold,
+    /// Spread the second linearly over this long, centred on the leap
+    Smear(Duration),
+}
+
+impl Default for LeapHandling {
+    /// A 24-hour linear smear, noon to noon
+    fn default() -> Self {
+        LeapHandling::Smear(Duration::from_secs(SECONDS_PER_DAY as u64))
+    }
+}
+
+impl LeapHandling {
+    /// How long after the leap any handling could still be applying it.
+    pub const MAX_DURATION_AFTER: Duration = Duration::from_secs(SECONDS_PER_DAY as u64 + 1);
+
+    /// The time to show for a leap-continuous time (UNIX nanoseconds).
+    pub fn apply(&self, leap: Option<&LeapSecond>, continuous: u64) -> u64 {
+        let Some(leap) = leap else {
+            return continuous;
+        };
+        let at = leap.at;
+        match (self, leap.kind) {
+            (LeapHandling::Step, LeapKind::Insert) if continuous >= at => continuous - SECOND,
+            (LeapHandling::Hold, LeapKind::Insert) if continuous >= at => continuous - SECOND,
+            (LeapHandling::Hold, LeapKind::Insert) if continuous >= at - SECOND => at - SECOND,
+            (LeapHandling::Step | LeapHandling::Hold, LeapKind::Delete)
+                if continuous >= at - SECOND =>
+            {
+                continuous + SECOND
+            }
+            (LeapHandling::Smear(window), kind) => {
+                let window = smear_nanos(*window);
+                let start = at.saturating_sub(window / 2);
+                if continuous < start {
+                    return continuous;
+                }
+                let elapsed = (continuous - start) as u128;
+                match kind {
+                    // the smear takes an extra second of real time
+                    LeapKind::Insert => {
+                        let length = (window + SECOND) as u128;
+                        let adjustment = (elapsed.min(length) * SECOND as u128 / length) as u64;
+                        continuous - adjustment
+                    }
+                    LeapKind::Delete => {
+                        let length = (window - SECOND) as u128;
+                        let adjustment = (elapsed.min(length) * SECOND as u128 / length) as u64;
+                        continuous + adjustment
+                    }
+                }
+            }
+            _ => continuous,
+        }
+    }
+
+    /// The leap-continuous time when the leap has been fully applied.
+    pub fn end(&self, leap: &LeapSecond) -> u64 {
+        match self {
+            LeapHandling::Smear(window) => {
+                let window = smear_nanos(*window);
+                leap.at.saturating_add(window / 2 + SECOND)
+            }
+            _ => leap.at.saturating_add(SECOND),
+        }
+    }
+}
+
+fn smear_nanos(window: Duration) -> u64 {
+    window.clamp(MIN_SMEAR, MAX_SMEAR).as_nanos() as u64
+}
+
+/// Midnight UTC at the start of the month after `unix_nanos`, in UNIX nanoseconds.
+fn end_of_month(unix_nanos: u64) -> u64 {
+    let days = (unix_nanos / SECOND) as i64 / SECONDS_PER_DAY;
+    let (year, month, _) = civil_from_days(days);
+    let (year, month) = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+    (days_from_civil(year, month, 1) * SECONDS_PER_DAY) as u64 * SECOND
+}
+
+/// Days since 1970-01-01 to a proleptic Gregorian (year, month, day), using Howard Hinnant's
+/// date algorithms.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// The reverse of [civil_from_days].
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2017-01-01 00:00:00 UTC, after the last leap second so far
+    const NEW_YEAR_2017: u64 = 1_483_228_800 * SECOND;
+
+    fn insert() -> LeapSecond {
+        LeapSecond::from_indicator(1, NEW_YEAR_2017 - 15 * SECONDS_PER_DAY as u64 * SECOND)
+            .expect("indicator 1 is a leap")
+    }
+
+    #[test]
+    fn leap_is_at_the_end_of_the_month() {
+        assert_eq!(
+            insert(),
+            LeapSecond {
+                kind: LeapKind::Insert,
+                at: NEW_YEAR_2017
+            }
+        );
+        // a deleted second at the end of a 30 day month
+        let june = LeapSecond::from_indicator(2, 1_466_035_200 * SECOND).expect("leap");
+        assert_eq!(june.kind, LeapKind::Delete);
+        assert_eq!(june.at, 1_467_331_200 * SECOND);
+        assert_eq!(LeapSecond::from_indicator(0, NEW_YEAR_2017), None);
+        assert_eq!(LeapSecond::from_indicator(3, NEW_YEAR_2017), None);
+        assert_eq!(days_from_civil(2016, 12, 31), 17_166);
+        assert_eq!(civil_from_days(17_166), (2016, 12, 31));
+    }
+
+    #[test]
+    fn step_repeats_the_second() {
+        let leap = insert();
+        assert_eq!(leap.to_utc(NEW_YEAR_2017 - 1), NEW_YEAR_2017 - 1);
+        assert_eq!(leap.to_utc(NEW_YEAR_2017), NEW_YEAR_2017 - SECOND);
+        assert_eq!(leap.to_utc(NEW_YEAR_2017 + SECOND), NEW_YEAR_2017);
+        assert_eq!(leap.to_continuous(NEW_YEAR_2017), NEW_YEAR_2017 + SECOND);
+    }
+
+    #[test]
+    fn hold_stops_at_59() {
+        let leap = insert();
+        let hold = LeapHandling::Hold;
+        assert_eq!(
+            hold.apply(Some(&leap), NEW_YEAR_2017 - 2 * SECOND),
+            NEW_YEAR_2017 - 2 * SECOND
+        );
+        for millis in [0, 500, 999] {
+            let continuous = NEW_YEAR_2017 - SECOND + millis * 1_000_000;
+            assert_eq!(hold.apply(Some(&leap), continuous), NEW_YEAR_2017 - SECOND);
+        }
+        assert_eq!(
+            hold.apply(Some(&leap), NEW_YEAR_2017 + SECOND / 2),
+            NEW_YEAR_2017 - SECOND / 2
+        );
+        assert_eq!(
+            hold.apply(Some(&leap), NEW_YEAR_2017 + SECOND),
+            NEW_YEAR_2017
+        );
+        assert_eq!(
+            hold.apply(None, NEW_YEAR_2017 + SECOND),
+            NEW_YEAR_2017 + SECOND
+        );
+    }
+
+    #[test]
+    fn smear_is_linear_and_continuous() {
+        let leap = insert();
+        let smear = LeapHandling::default();
+        let noon = NEW_YEAR_2017 - 12 * 3_600 * SECOND;
+        assert_eq!(smear.apply(Some(&leap), noon), noon);
+        // half way through the smear we're half a second behind
+        let midpoint = NEW_YEAR_2017 + SECOND / 2;
+        assert_eq!(smear.apply(Some(&leap), midpoint), NEW_YEAR_2017);
+        let end = smear.end(&leap);
+        assert_eq!(smear.apply(Some(&leap), end), end - SECOND);
+        assert_eq!(smear.apply(Some(&leap), end + SECOND), end);
+
+        let mut last = 0;
+        for step in 0..=200 {
+            let continuous = noon + step * 432 * SECOND;
+            let shown = smear.apply(Some(&leap), continuous);
+            assert!(shown >= last, "the smear should never go backwards");
+            last = shown;
+        }
+
+        let delete = LeapSecond {
+            kind: LeapKind::Delete,
+            ..leap
+        };
+        assert_eq!(
+            smear.apply(Some(&delete), NEW_YEAR_2017 - SECOND / 2),
+            NEW_YEAR_2017
+        );
+    }
+}
@@ -11,27 +11,71 @@
 #![deny(clippy::trivially_copy_pass_by_ref)]
 #![cfg_attr(not(feature = "std"), no_std)]
 
+#[cfg(feature = "tokio")]
+pub mod async_client;
 #[cfg(feature = "std")]
 pub mod cli;
+#[cfg(feature = "std")]
+pub mod control;
+#[cfg(feature = "std")]
+pub mod pool;
 
+pub mod auth;
+pub mod broadcast;
 pub mod clock;
 pub mod constants;
+pub mod discipline;
 pub mod error;
+pub mod filter;
+pub mod leap;
+pub mod message;
+#[cfg(feature = "nts")]
+pub mod nts;
 pub mod packets;
+pub mod peer;
+pub mod poll;
 pub mod prelude;
+pub mod sample;
+pub mod selection;
+pub mod server;
+pub mod timesource;
+pub mod transport;
 
+use core::future::Future;
+use core::net::SocketAddr;
+use core::ops::ControlFlow;
+use core::time::Duration;
 #[cfg(feature = "std")]
-use std::net::{SocketAddr, UdpSocket};
+use std::net::UdpSocket;
 #[cfg(feature = "std")]
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::error::ClockError;
 use packed_struct::PackedStructSlice;
-#[cfg(feature = "std")]
 use prelude::*;
 
+use crate::auth::KeyStore;
 use crate::constants::NTP_MIN_PACKET_LEN;
-use crate::packets::NtpPacket;
+use crate::message::NtpMessage;
+use crate::packets::{NtpMode, NtpPacket, NtpTimestamp};
+use crate::{
+    constants::{
+        NTP_ERA_PIVOT_UNIX_NANOS, NTP_MAX_PACKET_LEN, NTP_MAX_POLL_EXPONENT,
+        NTP_MAX_SERVER_ADDRESSES, NTP_MIN_POLL_EXPONENT, NTP_REQUEST_RETRIES,
+        NTP_REQUEST_TIMEOUT_MILLIS,
+    },
+    filter::ClockFilter,
+    leap::LeapSecond,
+    packets::{KissAction, KissCode},
+    poll::PollInterval,
+    timesource::TimeSource,
+    transport::{AsyncNtpTransport, NtpQuery, NtpRequester, QueryStep},
+};
+#[cfg(feature = "std")]
+use crate::{
+    timesource::SystemTimeSource,
+    transport::{NtpTransport, UdpTransport},
+};
 
 #[cfg(feature = "std")]
 pub struct NtpData {
@@ -51,122 +95,537 @@ impl NtpData {
     }
 }
 
-#[cfg(feature = "std")]
-pub struct NtpClient {
+/// A client of one server, keeping the state of the exchanges with it. Everything apart from
+/// [NtpClient::update] and [NtpClient::get_time] works without `std`, the firmware drives it
+/// with [NtpClient::update_with] over its own socket and clocks.
+pub struct NtpClient<#[cfg(feature = "std")] T = SystemTimeSource, #[cfg(not(feature = "std"))] T> {
+    /// The address we're querying, one of `addresses`
     pub server: SocketAddr,
-    pub time_validity: Duration,
+    /// How long to wait for each response
+    pub timeout: Duration,
+    /// How many times to ask an address again when it doesn't answer, before falling through
+    /// to the next
+    pub retries: u32,
+    /// The addresses the server resolved to, up to [NTP_MAX_SERVER_ADDRESSES] of them
+    addresses: heapless::Vec<SocketAddr, NTP_MAX_SERVER_ADDRESSES>,
+    /// How often to poll the server, the cached time is valid for one interval
+    pub poll: PollInterval,
     pub last_response: Option<NtpPacket>,
+    /// Offset and delay calculated from the last exchange
+    pub last_sample: Option<SyncSample>,
+    /// The recent samples from this server
+    pub filter: ClockFilter,
+    /// The local time we sent the outstanding request, T1
+    origin_time: Option<u64>,
+    /// Leap second announced in the last response
+    leap: Option<LeapSecond>,
+    /// Keys we trust, responses must be signed with one of them when `key_id` is set
+    keys: KeyStore,
+    /// The key we sign requests with
+    key_id: Option<u32>,
+    #[cfg(feature = "nts")]
+    /// Keys and cookies when we're using NTS
+    nts: Option<nts::NtsSession>,
+    /// The addresses which told us to go away with a Kiss-o'-Death, and how
+    demobilized: heapless::Vec<(SocketAddr, KissCode), NTP_MAX_SERVER_ADDRESSES>,
+    /// How long to hold off after a RATE Kiss-o'-Death, doubles each time we get one in a row
+    rate_backoff: Duration,
+    /// Don't send another request before this time on the monotonic clock
+    hold_until: u64,
+    /// The server's time when the last response arrived, and our monotonic time then
+    synced_at: Option<(u64, u64)>,
+    /// The latest time [NtpClient::current_time] returned, it never goes back before this
+    last_time: u64,
+    /// Where we read the local time from
+    time: T,
 }
 
 #[cfg(feature = "std")]
 impl NtpClient {
+    /// Create a client for `server`, falling through its addresses in the order they resolve.
     pub fn new(server: &str) -> Result<Self, ClockError> {
-        let server: SocketAddr = resolve_server(server)?;
+        Self::with_addresses(&resolve_servers(server)?)
+    }
+
+    /// Create a client for an already-resolved server address.
+    pub fn with_address(server: SocketAddr) -> Self {
+        NtpClient::new_with_time_source(server, SystemTimeSource)
+    }
+
+    /// Create a client for one server's addresses, starting with the first and moving on to
+    /// the next when one doesn't answer. Only the first [NTP_MAX_SERVER_ADDRESSES] are used.
+    pub fn with_addresses(addresses: &[SocketAddr]) -> Result<Self, ClockError> {
+        let server = addresses
+            .first()
+            .ok_or_else(|| ClockError::ConfigError("No NTP server addresses".to_string()))?;
         Ok(NtpClient {
+            addresses: addresses
+                .iter()
+                .copied()
+                .take(NTP_MAX_SERVER_ADDRESSES)
+                .collect(),
+            ..Self::with_address(*server)
+        })
+    }
+
+    /// Create a client protected by NTS, for the server the key exchange pointed us at.
+    #[cfg(feature = "nts")]
+    pub fn with_nts(session: nts::NtsSession) -> Self {
+        let server = session.ntp_server;
+        NtpClient {
+            nts: Some(session),
+            ..Self::with_address(server)
+        }
+    }
+}
+
+impl<T: TimeSource> NtpClient<T> {
+    /// Create a client for `server`, reading the local time from `time`.
+    pub fn new_with_time_source(server: SocketAddr, time: T) -> Self {
+        let mut addresses = heapless::Vec::new();
+        // can't fail, there's room for more than one
+        let _ = addresses.push(server);
+        NtpClient {
             server,
+            timeout: Duration::from_millis(NTP_REQUEST_TIMEOUT_MILLIS),
+            retries: NTP_REQUEST_RETRIES,
+            addresses,
 
-            time_validity: std::time::Duration::from_secs(60),
+            poll: PollInterval::new(),
             last_response: None,
-        })
+            last_sample: None,
+            filter: ClockFilter::new(),
+            origin_time: None,
+            leap: None,
+            keys: KeyStore::new(),
+            key_id: None,
+            #[cfg(feature = "nts")]
+            nts: None,
+            demobilized: heapless::Vec::new(),
+            rate_backoff: Duration::ZERO,
+            hold_until: 0,
+            synced_at: None,
+            last_time: 0,
+            time,
+        }
+    }
+
+    /// Read the local time from `time` rather than the clocks we were created with.
+    pub fn with_time_source<U: TimeSource>(self, time: U) -> NtpClient<U> {
+        NtpClient {
+            server: self.server,
+            timeout: self.timeout,
+            retries: self.retries,
+            addresses: self.addresses,
+            poll: self.poll,
+            last_response: self.last_response,
+            last_sample: self.last_sample,
+            filter: self.filter,
+            origin_time: self.origin_time,
+            leap: self.leap,
+            keys: self.keys,
+            key_id: self.key_id,
+            #[cfg(feature = "nts")]
+            nts: self.nts,
+            demobilized: self.demobilized,
+            rate_backoff: self.rate_backoff,
+            hold_until: self.hold_until,
+            synced_at: self.synced_at,
+            last_time: self.last_time,
+            time,
+        }
     }
+}
 
-    // Has the time been updated in the last `time_validity` period?
+impl<T: TimeSource + Clone> NtpClient<T> {
+    /// Wait up to `timeout` for each response.
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        NtpClient { timeout, ..self }
+    }
+
+    /// Ask an address up to `retries` more times before falling through to the next.
+    pub fn with_retries(self, retries: u32) -> Self {
+        NtpClient { retries, ..self }
+    }
+
+    /// Sign requests with `key_id` from `keys`, and only accept responses signed with a key
+    /// from `keys`.
+    pub fn with_authentication(mut self, key_id: u32, keys: KeyStore) -> Result<Self, ClockError> {
+        if keys.get(key_id).is_none() {
+            return Err(ClockError::UnknownKey(key_id));
+        }
+        self.keys = keys;
+        self.key_id = Some(key_id);
+        Ok(self)
+    }
+
+    // Has the time been updated in the last poll interval, going by the monotonic clock?
     pub fn time_is_valid(&self) -> bool {
-        match self.last_response.as_ref() {
+        match self.synced_at {
             None => false,
-            Some(response) => {
-                let now = unix_nanos_now();
-                let elapsed = now.saturating_sub(response.transmit_time);
-                elapsed < self.time_validity.as_nanos() as u64
+            Some((_, synced_at)) => {
+                let elapsed = self.time.monotonic_nanos().saturating_sub(synced_at);
+                elapsed < self.poll.interval().as_nanos() as u64
             }
         }
     }
 
-    pub fn get_time(&mut self) -> Result<u64, ClockError> {
-        if !self.time_is_valid() {
-            return self.update();
-        }
-        match self.last_response.as_ref() {
-            Some(response) => Ok(response.ref_time),
-            None => Err(ClockError::NoTimeAvailable),
-        }
+    /// The server's time now, UNIX nanoseconds: the time it gave us at the last update, moved
+    /// on by however long the monotonic clock says it's been since. Fails with
+    /// [ClockError::NoTimeAvailable] before the first update.
+    ///
+    /// The result never goes backwards, when an update finds the server behind where we'd got
+    /// to the time holds until it catches up.
+    pub fn current_time(&mut self) -> Result<u64, ClockError> {
+        let (server_time, synced_at) = self.synced_at.ok_or(ClockError::NoTimeAvailable)?;
+        let elapsed = self.time.monotonic_nanos().saturating_sub(synced_at);
+        self.last_time = server_time.saturating_add(elapsed).max(self.last_time);
+        Ok(self.last_time)
     }
 
-    pub fn update(&mut self) -> Result<u64, ClockError> {
-        use packed_struct::PackedStruct;
+    /// The leap second the server is announcing, if any.
+    pub fn pending_leap(&self) -> Option<LeapSecond> {
+        self.leap
+    }
 
-        use crate::{constants::NTP_MIN_PACKET_LEN, packets::NtpPacket};
+    /// Has every address of the server told us to stop querying it?
+    pub fn is_demobilized(&self) -> bool {
+        self.addresses
+            .iter()
+            .all(|address| self.demobilized_by(*address).is_some())
+    }
 
-        debug!("Updating...");
-        let socket = UdpSocket::bind("0.0.0.0:0").map_err(|_| ClockError::NetworkError)?;
+    /// The Kiss-o'-Death `address` told us to go away with, if it has.
+    fn demobilized_by(&self, address: SocketAddr) -> Option<KissCode> {
+        self.demobilized
+            .iter()
+            .find(|(demobilized, _)| *demobilized == address)
+            .map(|(_, code)| *code)
+    }
 
-        let transmit_time = unix_nanos_now();
-        let ntp_transmit_time = unix_nanos_to_ntp_timestamp(transmit_time);
-        let request = NtpPacket::request().with_transmit_time(ntp_transmit_time);
-        let request = request.pack().map_err(|err| {
-            #[cfg(feature = "std")]
+    /// Build a request sent at `transmit_time` (UNIX nanoseconds) and remember it as the
+    /// outstanding origin, so only a response echoing it is accepted.
+    ///
+    /// Fails with [ClockError::KissOfDeath] if the server has told us to stop, or to slow
+    /// down and the backoff hasn't passed yet.
+    pub fn build_request(
+        &mut self,
+        transmit_time: u64,
+    ) -> Result<heapless::Vec<u8, NTP_MAX_PACKET_LEN>, ClockError> {
+        if let Some(code) = self.demobilized_by(self.server) {
+            return Err(ClockError::KissOfDeath(code));
+        }
+        if self.time.monotonic_nanos() < self.hold_until {
+            return Err(ClockError::KissOfDeath(KissCode::Rate));
+        }
+        let mut request = self.new_request(
+            NtpPacket::request().with_transmit_time(NtpTimestamp::from_unix_nanos(transmit_time)),
+        )?;
+        if let Some(key_id) = self.key_id {
+            self.keys.sign(key_id, &mut request)?;
+        }
+        let request = request.to_bytes().inspect_err(|err| {
             error!("Failed to pack NTP request packet: {:?}", err);
-            ClockError::Io
         })?;
+        self.origin_time = Some(transmit_time);
+        Ok(request)
+    }
 
-        // trim request to NTP_MIN_PACKET_LEN
-        if request.len() < NTP_MIN_PACKET_LEN {
-            return Err(ClockError::Io);
+    /// Query the server like [NtpClient::update], over any async `transport`, waiting between
+    /// retries with `sleep`.
+    pub async fn update_with<S, F>(
+        &mut self,
+        transport: &mut impl AsyncNtpTransport,
+        mut sleep: S,
+    ) -> Result<SyncSample, ClockError>
+    where
+        S: FnMut(Duration) -> F,
+        F: Future<Output = ()>,
+    {
+        debug!("Updating...");
+        let mut query = self.query();
+        loop {
+            match query.next(self.time.unix_nanos() / 1_000) {
+                QueryStep::Exchange(address) => {
+                    self.switch_to(address);
+                    let (timeout, time) = (self.timeout, self.time.clone());
+                    let result =
+                        transport::exchange_async(transport, self, address, timeout, &time).await;
+                    if let ControlFlow::Break(result) = query.record(result) {
+                        return result;
+                    }
+                }
+                QueryStep::Sleep(delay) => sleep(delay).await,
+                QueryStep::Failed(error) => {
+                    self.origin_time = None;
+                    return Err(error);
+                }
+            }
         }
-        let request = &request[..NTP_MIN_PACKET_LEN];
-        socket
-            .send_to(request, self.server)
-            .map_err(|_| ClockError::NetworkError)?;
+    }
+
+    /// A query of every address which hasn't told us to go away, starting with the one we're
+    /// using.
+    pub(crate) fn query(&self) -> NtpQuery {
+        let query = NtpQuery::new(&self.addresses, self.server, self.retries);
+        self.demobilized
+            .iter()
+            .fold(query, |query, (address, code)| {
+                query.skipping(*address, ClockError::KissOfDeath(*code))
+            })
+    }
 
-        let mut response = [0u8; NTP_MIN_PACKET_LEN];
-        let (len, _) = socket
-            .recv_from(&mut response)
-            .map_err(|_| ClockError::NetworkError)?;
-        let local_time = unix_nanos_now();
-        self.update_from_response(&response[..len], local_time)
+    /// Start querying `address`, if we aren't already.
+    pub(crate) fn switch_to(&mut self, address: SocketAddr) {
+        if address != self.server {
+            info!("Falling through from {} to {}", self.server, address);
+            self.server = address;
+            // samples from another host don't belong in the same filter
+            self.filter.clear();
+        }
     }
 
+    /// Handle a response that arrived at `local_time` (T4), pairing it with the origin time
+    /// of the outstanding request from [NtpClient::build_request].
+    ///
+    /// Responses which don't echo that origin, or arrive when no request is outstanding
+    /// (duplicates and late replies), are rejected with [ClockError::OriginMismatch].
     pub fn update_from_response(
         &mut self,
         response: &[u8],
         local_time: u64,
-    ) -> Result<u64, ClockError> {
-        let response = parse_ntp_packet(response, local_time)?;
-        self.last_response = Some(response.clone());
+    ) -> Result<SyncSample, ClockError> {
+        let origin_time = self.origin_time.ok_or(ClockError::OriginMismatch)?;
+        let response = match self.check_response(response, origin_time) {
+            Err(ClockError::KissOfDeath(code)) => {
+                self.origin_time = None;
+                // it unpacked to get this far, its poll field is how often the server wants us
+                let poll = unpack_ntp_packet(response).map_or(0, |kiss| kiss.poll);
+                self.handle_kiss(code, poll);
+                return Err(ClockError::KissOfDeath(code));
+            }
+            other => other?,
+        };
+        self.origin_time = None;
+        // the server's happy with how often we're asking now
+        self.rate_backoff = Duration::ZERO;
+        // decode relative to the last time the server gave us, so we keep working past 2094
+        let pivot = self
+            .last_sample
+            .map(|sample| sample.server_time())
+            .unwrap_or(0)
+            .max(NTP_ERA_PIVOT_UNIX_NANOS);
+        let sample = SyncSample::from_packet_near(&response, origin_time, local_time, pivot);
+        self.poll.set_server_exponent(response.poll);
+        let leap = LeapSecond::from_indicator(response.leap_indicator, sample.server_time());
+        if leap.is_some() && leap != self.leap {
+            info!("{} announced a leap second: {:?}", self.server, leap);
+        }
+        self.leap = leap;
+        self.last_response = Some(response);
+        self.last_sample = Some(sample);
+        self.synced_at = Some((sample.server_time(), self.time.monotonic_nanos()));
+        let estimate = self.filter.add(sample);
+        self.poll.update(estimate.offset, estimate.jitter);
 
-        debug!("Done updating NTP time: {}", response.transmit_time);
-        Ok(response.transmit_time)
+        debug!(
+            "Done updating NTP time: {} (offset {}ns, delay {}ns)",
+            sample.t3, sample.offset, sample.delay
+        );
+        Ok(sample)
+    }
+
+    /// Wrap a request header in a message, with the NTS fields when we're using NTS.
+    fn new_request(&mut self, header: NtpPacket) -> Result<NtpMessage, ClockError> {
+        #[cfg(feature = "nts")]
+        if let Some(nts) = self.nts.as_mut() {
+            return nts.request(header);
+        }
+        Ok(NtpMessage::new(header))
+    }
+
+    /// Authenticate and parse a response to the request we sent at `origin_time`.
+    fn check_response(
+        &mut self,
+        response: &[u8],
+        origin_time: u64,
+    ) -> Result<NtpPacket, ClockError> {
+        #[cfg(feature = "nts")]
+        if let Some(nts) = self.nts.as_mut() {
+            nts.handle_response(response)?;
+        }
+        parse_ntp_response(
+            response,
+            NtpTimestamp::from_unix_nanos(origin_time),
+            self.key_id.map(|_| &self.keys),
+        )
+    }
+
+    /// Apply the client behaviour from [RFC 5905 Section 7.4](https://www.rfc-editor.org/rfc/rfc5905#section-7.4),
+    /// `poll` is the exponent from the Kiss-o'-Death's poll field
+    fn handle_kiss(&mut self, code: KissCode, poll: i8) {
+        match code.action() {
+            KissAction::Demobilize => {
+                error!(
+                    "{} sent {:?} ({}), no longer querying it",
+                    self.server,
+                    code,
+                    code.description()
+                );
+                if self.demobilized_by(self.server).is_none() {
+                    // one entry per address, so there's always room
+                    let _ = self.demobilized.push((self.server, code));
+                }
+            }
+            KissAction::ReducePolling => {
+                self.poll.back_off();
+                self.poll.set_server_exponent(poll);
+                self.rate_backoff = (self.rate_backoff * 2).clamp(
+                    Duration::from_secs(1 << NTP_MIN_POLL_EXPONENT),
+                    Duration::from_secs(1 << NTP_MAX_POLL_EXPONENT),
+                );
+                warn!(
+                    "{} is rate limiting us, backing off for {}s then polling every {}s",
+                    self.server,
+                    self.rate_backoff.as_secs(),
+                    self.poll.interval().as_secs()
+                );
+                self.hold_until = self
+                    .time
+                    .monotonic_nanos()
+                    .saturating_add(self.rate_backoff.as_nanos() as u64);
+            }
+            KissAction::Discard => {
+                warn!(
+                    "Discarding Kiss-o'-Death {:?} ({}) from {}",
+                    code,
+                    code.description(),
+                    self.server
+                );
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: TimeSource + Clone> NtpClient<T> {
+    /// The server's time now, see [NtpClient::current_time]. Updates first when the last
+    /// update was more than a poll interval ago.
+    pub fn get_time(&mut self) -> Result<u64, ClockError> {
+        if !self.time_is_valid() {
+            self.update()?;
+        }
+        self.current_time()
+    }
+
+    /// Query the server, asking again after [Self::retries] timeouts with a growing delay,
+    /// then falling through to its next address. Fails with [ClockError::Timeout] once every
+    /// address has had its retries without answering.
+    pub fn update(&mut self) -> Result<SyncSample, ClockError> {
+        self.update_via(&mut UdpTransport::default())
+    }
+
+    /// [NtpClient::update], sending and receiving with `transport`.
+    pub fn update_via(
+        &mut self,
+        transport: &mut impl NtpTransport,
+    ) -> Result<SyncSample, ClockError> {
+        debug!("Updating...");
+        let mut query = self.query();
+        loop {
+            match query.next(self.time.unix_nanos() / 1_000) {
+                QueryStep::Exchange(address) => {
+                    self.switch_to(address);
+                    if let ControlFlow::Break(result) = query.record(self.exchange(transport)) {
+                        return result;
+                    }
+                }
+                QueryStep::Sleep(delay) => std::thread::sleep(delay),
+                QueryStep::Failed(error) => {
+                    self.origin_time = None;
+                    return Err(error);
+                }
+            }
+        }
+    }
+
+    /// Send one request to the current address and wait up to the timeout for the response.
+    fn exchange(&mut self, transport: &mut impl NtpTransport) -> Result<SyncSample, ClockError> {
+        let (server, timeout, time) = (self.server, self.timeout, self.time.clone());
+        transport::exchange(transport, self, server, timeout, &time)
+    }
+}
+
+impl<T: TimeSource + Clone> NtpRequester for NtpClient<T> {
+    type Response = SyncSample;
+
+    fn request(
+        &mut self,
+        transmit_time: u64,
+    ) -> Result<heapless::Vec<u8, NTP_MAX_PACKET_LEN>, ClockError> {
+        self.build_request(transmit_time)
+    }
+
+    fn is_response(&self, packet: &[u8]) -> bool {
+        #[cfg(feature = "nts")]
+        if let Some(nts) = self.nts.as_ref() {
+            return nts.is_response(packet);
+        }
+        self.origin_time
+            .is_some_and(|origin| transport::echoes_origin(packet, origin))
+    }
+
+    fn response(&mut self, packet: &[u8], local_time: u64) -> Result<SyncSample, ClockError> {
+        self.update_from_response(packet, local_time)
     }
 }
 
 pub const NTP_UNIX_EPOCH: i64 = 2_208_988_800;
 
-#[cfg(feature = "std")]
-fn unix_nanos_to_ntp_timestamp(unix_nanos: u64) -> u64 {
-    let unix_seconds = (unix_nanos / 1_000_000_000) as i128;
-    let nanos = (unix_nanos % 1_000_000_000) as i128;
-    let ntp_seconds = unix_seconds + NTP_UNIX_EPOCH as i128;
-    let fraction = (nanos << 32) / 1_000_000_000i128;
-    ((ntp_seconds as u64) << 32) | (fraction as u64)
+/// Parse an NTP packet, the timestamps are left as they were on the wire, use
+/// [NtpTimestamp::to_unix_nanos] to decode them.
+/// With `keys` the packet must carry a MAC from one of them, or it fails with
+/// [ClockError::AuthenticationFailed] or [ClockError::UnknownKey].
+pub fn parse_ntp_packet(packet: &[u8], keys: Option<&KeyStore>) -> Result<NtpPacket, ClockError> {
+    let res = unpack_ntp_packet(packet)?;
+    verify_mac(packet, keys)?;
+    Ok(res)
 }
 
-fn ntp_timestamp_to_unix_nanos(ntp_timestamp: u64) -> u64 {
-    if ntp_timestamp == 0 {
-        return 0;
+/// Parse a response to a request which carried `request_transmit_time` in its transmit field.
+/// The server must echo that value back as the origin time, anything else is a spoofed,
+/// duplicated or stale reply and fails with [ClockError::OriginMismatch].
+/// With `keys` the reply must carry a MAC from one of them, or it fails with
+/// [ClockError::AuthenticationFailed] or [ClockError::UnknownKey].
+/// Kiss-o'-Death replies fail with [ClockError::KissOfDeath], and replies we shouldn't set
+/// the time from fail [NtpPacket::check_usable].
+pub fn parse_ntp_response(
+    packet: &[u8],
+    request_transmit_time: NtpTimestamp,
+    keys: Option<&KeyStore>,
+) -> Result<NtpPacket, ClockError> {
+    let res = unpack_ntp_packet(packet)?;
+    if request_transmit_time.is_zero() || res.origin_time != request_transmit_time {
+        return Err(ClockError::OriginMismatch);
     }
-    let seconds = (ntp_timestamp >> 32) as i128;
-    let fraction = (ntp_timestamp & 0xffff_ffff) as i128;
-    let unix_seconds = seconds - NTP_UNIX_EPOCH as i128;
-    if unix_seconds < 0 {
-        return 0;
+    verify_mac(packet, keys)?;
+    // only trust a KoD once we know it's a reply to our request
+    if let Some(code) = res.kiss_code() {
+        return Err(ClockError::KissOfDeath(code));
     }
-    let nanos = (fraction * 1_000_000_000i128) >> 32;
-    let unix_nanos = unix_seconds * 1_000_000_000i128 + nanos;
-    unix_nanos as u64
+    res.check_usable(NtpMode::Server)?;
+    Ok(res)
 }
 
-/// Returns the NTP time and offset in nanoseconds.
-pub fn parse_ntp_packet(packet: &[u8], _local_time: u64) -> Result<NtpPacket, ClockError> {
+/// Check the MAC on `packet` against `keys`, if we have any.
+fn verify_mac(packet: &[u8], keys: Option<&KeyStore>) -> Result<(), ClockError> {
+    if let Some(keys) = keys {
+        keys.verify(&NtpMessage::parse(packet)?)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn unpack_ntp_packet(packet: &[u8]) -> Result<NtpPacket, ClockError> {
     if packet.len() < NTP_MIN_PACKET_LEN {
         return Err(ClockError::PacketTooShort);
     }
@@ -181,19 +640,13 @@ pub fn parse_ntp_packet(packet: &[u8], _local_time: u64) -> Result<NtpPacket, Cl
                 .collect::<Vec<_>>()
         );
     }
-    let mut result = [0u8; 60];
-    result[..packet.len()].copy_from_slice(packet);
-    let mut res = NtpPacket::unpack_from_slice(&result).map_err(|_| ClockError::InvalidResponse)?;
+    // anything after the header is extension fields or a MAC, which NtpMessage handles
+    let res = NtpPacket::unpack_from_slice(&packet[..NTP_MIN_PACKET_LEN])
+        .map_err(|_| ClockError::InvalidResponse)?;
 
     if res.version < 1 || res.version > 4 {
         return Err(ClockError::InvalidVersion);
     }
-
-    res.ref_time = ntp_timestamp_to_unix_nanos(res.ref_time);
-    res.origin_time = ntp_timestamp_to_unix_nanos(res.origin_time);
-    res.recv_time = ntp_timestamp_to_unix_nanos(res.recv_time);
-    res.transmit_time = ntp_timestamp_to_unix_nanos(res.transmit_time);
-
     Ok(res)
 }
 
@@ -205,18 +658,54 @@ pub fn unix_nanos_now() -> u64 {
         .unwrap_or(0)
 }
 
+/// A failed receive, [ClockError::Timeout] if it's the read timeout running out.
+#[cfg(feature = "std")]
+pub(crate) fn recv_error(err: &std::io::Error) -> ClockError {
+    if matches!(
+        err.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    ) {
+        ClockError::Timeout
+    } else {
+        ClockError::NetworkError
+    }
+}
+
+/// A socket on an ephemeral port to talk to `server` from, IPv6 servers can't be reached
+/// from an IPv4 socket.
+#[cfg(feature = "std")]
+pub(crate) fn bind_for(server: SocketAddr) -> Result<UdpSocket, ClockError> {
+    UdpSocket::bind(local_address_for(server)).map_err(|_| ClockError::NetworkError)
+}
+
+/// Any local address, in the same family as `server`.
+#[cfg(feature = "std")]
+pub(crate) fn local_address_for(server: SocketAddr) -> SocketAddr {
+    if server.is_ipv4() {
+        (std::net::Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+    }
+}
+
 #[cfg(feature = "std")]
 fn resolve_server(server: &str) -> Result<SocketAddr, ClockError> {
+    resolve_servers(server).map(|addrs| addrs[0])
+}
+
+/// Resolve every address for an NTP server, returns an error if there aren't any.
+#[cfg(feature = "std")]
+pub fn resolve_servers(server: &str) -> Result<Vec<SocketAddr>, ClockError> {
     use std::net::ToSocketAddrs;
 
     use crate::constants::NTP_PORT;
     let addrs: Vec<SocketAddr> = (server, NTP_PORT).to_socket_addrs()?.collect();
-    if let Some(addr) = addrs.first() {
-        Ok(*addr)
-    } else {
+    if addrs.is_empty() {
         Err(ClockError::ConfigError(format!(
             "Could not resolve NTP server address: {}",
             server
         )))
+    } else {
+        Ok(addrs)
     }
 }
@@ -15,8 +15,7 @@ use std::process::ExitCode;
 #[cfg(any(target_family = "unix", target_family = "windows"))]
 fn cli_main() -> Result<(), ExitCode> {
     use clap::Parser;
-    use ntp_clock::packets::NtpPacket;
-    use ntp_clock::{cli::Cli, clock::hand_angles, prelude::*};
+    use ntp_clock::{cli::Cli, clock::hand_angles_at, prelude::*};
 
     let cliopts = Cli::parse();
 
@@ -32,26 +31,80 @@ fn cli_main() -> Result<(), ExitCode> {
         .init()
         .expect("Failed to initialize logger");
 
-    let mut client = NtpClient::new(&cliopts.ntp_server).inspect_err(|err| {
-        error!("Failed to create NTP client: {err}");
-    })?;
-    let time = client
-        .get_time()
-        .inspect_err(|err| error!("Failed to run update: {err}"))?;
-    let offset = client
-        .last_response
-        .as_ref()
-        .map(|resp| resp.offset_from_local(unix_nanos_now()))
-        .map(|v| v.to_string())
-        .unwrap_or_else(|| "unknown".into());
-    let seconds = time / 1_000_000_000;
-    let nanos = time % 1_000_000_000;
-    info!(
-        "NTP time from {}: {}.{:09} UTC (Offset: {}ns)",
-        cliopts.ntp_server, seconds, nanos, offset
-    );
+    let authentication = match (&cliopts.key_file, cliopts.key_id) {
+        (Some(key_file), Some(key_id)) => Some((key_id, load_keys(key_file)?)),
+        _ => None,
+    };
+
+    cliopts.check().inspect_err(|err| error!("{err}"))?;
+
+    if let Some(command) = cliopts.command.as_ref() {
+        return control_query(&cliopts.ntp_server.join(","), command);
+    }
+
+    let timeout = std::time::Duration::from_millis(cliopts.timeout_ms);
+    let time = if cliopts.pool || cliopts.ntp_server.len() > 1 {
+        pool_time(
+            &cliopts.ntp_server,
+            authentication.as_ref(),
+            timeout,
+            cliopts.retries,
+        )?
+    } else if cliopts.broadcast {
+        broadcast_time(
+            &cliopts.ntp_server.join(","),
+            authentication,
+            timeout,
+            cliopts.retries,
+        )?
+    } else {
+        let ntp_server = cliopts.ntp_server.join(",");
+        #[cfg(feature = "nts")]
+        let mut client = if cliopts.nts {
+            nts_client(&ntp_server)?
+        } else {
+            NtpClient::new(&ntp_server).inspect_err(|err| {
+                error!("Failed to create NTP client: {err}");
+            })?
+        };
+        #[cfg(not(feature = "nts"))]
+        let mut client = NtpClient::new(&ntp_server).inspect_err(|err| {
+            error!("Failed to create NTP client: {err}");
+        })?;
+        client = client.with_timeout(timeout).with_retries(cliopts.retries);
+        if let Some((key_id, keys)) = authentication.as_ref() {
+            client = client
+                .with_authentication(*key_id, keys.clone())
+                .inspect_err(|err| error!("Failed to set up authentication: {err}"))?;
+        }
+        let time = client
+            .get_time()
+            .inspect_err(|err| error!("Failed to run update: {err}"))?;
+        let (offset, delay) = client
+            .last_sample
+            .as_ref()
+            .map(|sample| (sample.offset.to_string(), sample.delay.to_string()))
+            .unwrap_or_else(|| ("unknown".into(), "unknown".into()));
+        let seconds = time / 1_000_000_000;
+        let nanos = time % 1_000_000_000;
+        info!(
+            "NTP time from {}: {}.{:09} UTC (Offset: {}ns, Delay: {}ns)",
+            ntp_server, seconds, nanos, offset, delay
+        );
+        if let Some(leap) = client.pending_leap() {
+            info!(
+                "Leap second pending: {:?} at {} UTC",
+                leap.kind,
+                leap.at / 1_000_000_000
+            );
+        }
+        if let Some(listen) = cliopts.serve {
+            return serve(client, listen, &cliopts.peer, authentication.as_ref());
+        }
+        time
+    };
     if cliopts.show_angles {
-        let angles = hand_angles(&NtpPacket::from_nanos(time));
+        let angles = hand_angles_at(time);
         info!(
             "Hand angles (deg): hour={}, minute={}, second={}",
             angles.hour.round() as i64,
@@ -62,6 +115,383 @@ fn cli_main() -> Result<(), ExitCode> {
     Ok(())
 }
 
+/// Run NTS-KE against `server` and create a client for the NTP server it gives us
+#[cfg(all(
+    feature = "nts",
+    any(target_family = "unix", target_family = "windows")
+))]
+fn nts_client(server: &str) -> Result<ntp_clock::NtpClient, ExitCode> {
+    use ntp_clock::nts::{NtsSession, client_config, split_host_port};
+    use ntp_clock::prelude::*;
+
+    let (host, port) = split_host_port(server)?;
+    let session = NtsSession::key_exchange(host, port, client_config()?)
+        .inspect_err(|err| error!("NTS key exchange with {server} failed: {err}"))?;
+    info!(
+        "NTS key exchange with {} complete, using {}",
+        server, session.ntp_server
+    );
+    Ok(NtpClient::with_nts(session))
+}
+
+/// Answer NTP clients on `listen` with our clock corrected by the filter offset for
+/// `client`'s server, polling it again every poll interval. `peers` back us up as symmetric
+/// peers when the server can't be reached, and anyone can start a passive association with
+/// us. Only returns if we can't listen.
+#[cfg(any(target_family = "unix", target_family = "windows"))]
+fn serve(
+    mut client: ntp_clock::NtpClient,
+    listen: std::net::SocketAddr,
+    peers: &[std::net::SocketAddr],
+    authentication: Option<&(u32, ntp_clock::auth::KeyStore)>,
+) -> Result<(), ExitCode> {
+    use ntp_clock::constants::NTP_MAX_PACKET_LEN;
+    use ntp_clock::peer::SymmetricPeer;
+    use ntp_clock::prelude::*;
+    use ntp_clock::server::NtpServer;
+    use std::time::Duration;
+
+    let with_keys = |peer: SymmetricPeer| match authentication {
+        Some((key_id, keys)) => peer.with_authentication(*key_id, keys.clone()),
+        None => Ok(peer),
+    };
+    // each peer with when we next send to it
+    let mut peers = peers
+        .iter()
+        .map(|address| with_keys(SymmetricPeer::active(*address)).map(|peer| (peer, 0)))
+        .collect::<Result<Vec<(SymmetricPeer, u64)>, ClockError>>()
+        .inspect_err(|err| error!("Failed to set up peers: {err}"))?;
+
+    let socket = std::net::UdpSocket::bind(listen)
+        .map_err(ClockError::from)
+        .inspect_err(|err| error!("Failed to listen on {listen}: {err}"))?;
+    info!("Serving time to NTP clients on {}", listen);
+    let mut server = NtpServer::new();
+    // what to add to the system clock to get the time we serve
+    let mut offset = 0i64;
+    let mut next_poll = 0u64;
+    let mut buffer = [0u8; NTP_MAX_PACKET_LEN];
+    loop {
+        let now = unix_nanos_now();
+        if now >= next_poll {
+            // the client has just synchronized the first time round
+            let upstream_ok = next_poll == 0
+                || client
+                    .update()
+                    .inspect_err(|err| warn!("Failed to update from {}: {err}", client.server))
+                    .is_ok();
+            let upstream = client
+                .last_response
+                .as_ref()
+                .zip(client.filter.estimate(now))
+                .filter(|_| upstream_ok);
+            if let Some((packet, estimate)) = upstream {
+                server.update_from_upstream(packet, &estimate, client.server.ip());
+                offset = estimate.offset;
+            } else if let Some(index) = best_peer(&peers, server.stratum(), now) {
+                let peer = &mut peers[index].0;
+                if let (Some(packet), Some(estimate)) =
+                    (peer.last_response.as_ref(), peer.filter.estimate(now))
+                {
+                    info!("Following peer {}", peer.address);
+                    server.update_from_upstream(packet, &estimate, peer.address.ip());
+                    // peer samples are taken against the time we serve, not the system clock
+                    offset = offset.saturating_add(estimate.offset);
+                    peer.filter.clear();
+                }
+            } else {
+                warn!("Nothing to follow, serving from the last update");
+            }
+            next_poll = now.saturating_add(client.poll.interval().as_nanos() as u64);
+        }
+        let clock = || (unix_nanos_now() as i128 + offset as i128).max(0) as u64;
+
+        for (peer, next_send) in peers.iter_mut() {
+            if now >= *next_send {
+                if let Err(err) = peer.send(&socket, &server, clock()) {
+                    warn!("Failed to send to peer {}: {err}", peer.address);
+                }
+                *next_send = now.saturating_add(peer.poll.interval().as_nanos() as u64);
+            }
+        }
+
+        let wake = peers
+            .iter()
+            .map(|(_, next_send)| *next_send)
+            .fold(next_poll, u64::min);
+        let timeout = wake.saturating_sub(unix_nanos_now()).max(1_000_000);
+        socket
+            .set_read_timeout(Some(Duration::from_nanos(timeout)))
+            .map_err(ClockError::from)?;
+        let (len, source) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue;
+            }
+            Err(err) => return Err(ClockError::from(err).into()),
+        };
+        let recv_time = clock();
+        let packet = &buffer[..len];
+
+        if let Some((peer, _)) = peers.iter_mut().find(|(peer, _)| peer.address == source) {
+            match peer.receive(packet, recv_time) {
+                Ok(sample) => debug!(
+                    "Peer {} offset={}ns delay={}ns",
+                    source, sample.offset, sample.delay
+                ),
+                Err(err) => debug!("Peer {} packet not used: {err}", source),
+            }
+        } else if SymmetricPeer::accepts(packet) {
+            let mut peer = with_keys(SymmetricPeer::passive(source))?;
+            match peer.receive(packet, recv_time) {
+                // the first packet never completes an exchange, but it has to be authentic
+                Ok(_) | Err(ClockError::OriginMismatch) => {
+                    info!("{} started a symmetric association", source);
+                    peers.push((peer, 0));
+                }
+                Err(err) => debug!("Refused an association from {}: {err}", source),
+            }
+        } else {
+            let response = server
+                .respond(packet, recv_time, clock())
+                .and_then(|response| response.as_bytes().map_err(|_| ClockError::Io));
+            match response {
+                Ok(response) => {
+                    if let Err(err) = socket.send_to(&response, source) {
+                        debug!("Failed to answer {}: {err}", source);
+                    }
+                }
+                Err(err) => debug!("Dropped an NTP request from {}: {err}", source),
+            }
+        }
+    }
+}
+
+/// The peer to follow when the server is out of reach: the lowest stratum, then the lowest
+/// root distance. Peers at a higher stratum than us could be following us.
+#[cfg(any(target_family = "unix", target_family = "windows"))]
+fn best_peer(
+    peers: &[(ntp_clock::peer::SymmetricPeer, u64)],
+    stratum: u8,
+    now: u64,
+) -> Option<usize> {
+    peers
+        .iter()
+        .enumerate()
+        .filter_map(|(index, (peer, _))| {
+            let packet = peer.last_response.as_ref()?;
+            let estimate = peer.filter.estimate(now)?;
+            (packet.stratum <= stratum).then_some((index, packet.stratum, estimate.distance()))
+        })
+        .min_by_key(|(_, stratum, distance)| (*stratum, *distance))
+        .map(|(index, _, _)| index)
+}
+
+/// Calibrate the delay to `server` with client exchanges, then return the time from the
+/// first broadcast or multicast it sends us
+#[cfg(any(target_family = "unix", target_family = "windows"))]
+fn broadcast_time(
+    server: &str,
+    authentication: Option<(u32, ntp_clock::auth::KeyStore)>,
+    timeout: std::time::Duration,
+    retries: u32,
+) -> Result<u64, ExitCode> {
+    use ntp_clock::broadcast::{BroadcastClient, bind_broadcast_socket};
+    use ntp_clock::constants::{NTP_MULTICAST_IPV4, NTP_MULTICAST_IPV6, NTP_PORT};
+    use ntp_clock::prelude::*;
+
+    let mut client = NtpClient::new(server)
+        .inspect_err(|err| {
+            error!("Failed to create NTP client: {err}");
+        })?
+        .with_timeout(timeout)
+        .with_retries(retries);
+    let mut broadcast = BroadcastClient::new(client.server.ip());
+    if let Some((key_id, keys)) = authentication {
+        broadcast = broadcast.with_authentication(keys.clone());
+        client = client
+            .with_authentication(key_id, keys)
+            .inspect_err(|err| error!("Failed to set up authentication: {err}"))?;
+    }
+    let group = if client.server.is_ipv4() {
+        NTP_MULTICAST_IPV4.into()
+    } else {
+        NTP_MULTICAST_IPV6.into()
+    };
+    let socket = bind_broadcast_socket(NTP_PORT, Some(group))
+        .inspect_err(|err| error!("Failed to listen for broadcasts: {err}"))?;
+
+    while !broadcast.is_calibrated() {
+        let sample = client
+            .update()
+            .inspect_err(|err| error!("Failed to calibrate against {server}: {err}"))?;
+        broadcast.calibrate(&sample);
+    }
+    info!(
+        "Calibrated round trip to {}: {}ns, waiting for a broadcast",
+        server,
+        broadcast.delay().unwrap_or_default()
+    );
+    loop {
+        match broadcast.listen(&socket) {
+            Ok((_, sample)) => {
+                let time = sample.server_time();
+                info!(
+                    "NTP broadcast from {}: {}.{:09} UTC (Offset: {}ns)",
+                    server,
+                    time / 1_000_000_000,
+                    time % 1_000_000_000,
+                    sample.offset
+                );
+                return Ok(time);
+            }
+            Err(ClockError::NetworkError) => {
+                error!("Failed to receive a broadcast from {server}");
+                return Err(ClockError::NetworkError.into());
+            }
+            Err(err) => warn!("Ignoring broadcast from {server}: {err}"),
+        }
+    }
+}
+
+/// Query `server` with NTP control messages and print the answers, like `ntpq`
+#[cfg(any(target_family = "unix", target_family = "windows"))]
+fn control_query(server: &str, command: &ntp_clock::cli::Command) -> Result<(), ExitCode> {
+    use ntp_clock::cli::Command;
+    use ntp_clock::control::ControlClient;
+    use ntp_clock::prelude::*;
+
+    let mut client = ControlClient::new(server).inspect_err(|err| {
+        error!("Failed to create control client: {err}");
+    })?;
+    match command {
+        Command::Peers => {
+            let associations = client
+                .read_status()
+                .inspect_err(|err| error!("Failed to read the peers of {server}: {err}"))?;
+            println!(
+                "{:>5}  {:<24} {:<16} {:>2} {:>5} {:>5} {:>9} {:>9} {:>9}",
+                "assid", "remote", "refid", "st", "poll", "reach", "delay", "offset", "jitter"
+            );
+            for (association_id, status) in associations {
+                let variables = client.read_variables(association_id).inspect_err(|err| {
+                    error!("Failed to read association {association_id} of {server}: {err}")
+                })?;
+                let get = |name: &str| {
+                    variables
+                        .iter()
+                        .find(|(variable, _)| variable == name)
+                        .map_or("-", |(_, value)| value.as_str())
+                };
+                let poll = get("hpoll")
+                    .parse::<u32>()
+                    .ok()
+                    .and_then(|exponent| 1u64.checked_shl(exponent))
+                    .map_or_else(|| "-".to_string(), |seconds| seconds.to_string());
+                println!(
+                    "{:>5} {}{:<24} {:<16} {:>2} {:>5} {:>5} {:>9} {:>9} {:>9}",
+                    association_id,
+                    status.tally(),
+                    get("srcadr"),
+                    get("refid"),
+                    get("stratum"),
+                    poll,
+                    get("reach"),
+                    get("delay"),
+                    get("offset"),
+                    get("jitter")
+                );
+            }
+        }
+        Command::Readvar { association } => {
+            let variables = client
+                .read_variables(*association)
+                .inspect_err(|err| error!("Failed to read the variables of {server}: {err}"))?;
+            for (name, value) in variables {
+                println!("{name}={value}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read an ntpd format keys file
+#[cfg(any(target_family = "unix", target_family = "windows"))]
+fn load_keys(path: &std::path::Path) -> Result<ntp_clock::auth::KeyStore, ExitCode> {
+    use ntp_clock::auth::KeyStore;
+    use ntp_clock::prelude::*;
+
+    let contents = std::fs::read_to_string(path)
+        .map_err(ClockError::from)
+        .inspect_err(|err| error!("Failed to read {}: {err}", path.display()))?;
+    Ok(KeyStore::from_keys_file(&contents)
+        .inspect_err(|err| error!("Failed to parse {}: {err}", path.display()))?)
+}
+
+/// Poll every address of every server, log the selection outcome and return the selected time
+#[cfg(any(target_family = "unix", target_family = "windows"))]
+fn pool_time(
+    servers: &[String],
+    authentication: Option<&(u32, ntp_clock::auth::KeyStore)>,
+    timeout: std::time::Duration,
+    retries: u32,
+) -> Result<u64, ExitCode> {
+    use ntp_clock::pool::NtpPool;
+    use ntp_clock::prelude::*;
+
+    let servers: Vec<&str> = servers.iter().map(String::as_str).collect();
+    let mut pool = NtpPool::new(&servers)
+        .inspect_err(|err| {
+            error!("Failed to create NTP pool: {err}");
+        })?
+        .with_retry_policy(timeout, retries);
+    if let Some((key_id, keys)) = authentication {
+        pool = pool
+            .with_authentication(*key_id, keys)
+            .inspect_err(|err| error!("Failed to set up authentication: {err}"))?;
+    }
+    let selection = pool
+        .update()
+        .inspect_err(|err| error!("Failed to select a time source: {err}"))?;
+
+    for (index, peer) in pool.peers().iter().enumerate() {
+        let tally = if index == selection.system_peer {
+            '*'
+        } else if selection.survivors.contains(&index) {
+            '+'
+        } else if selection.falsetickers.contains(&index) {
+            'x'
+        } else if selection.unreachable.contains(&index) {
+            '-'
+        } else {
+            ' '
+        };
+        match peer.last_sample.as_ref() {
+            Some(sample) => info!(
+                "{}{} offset={}ns delay={}ns dispersion={}ns",
+                tally, peer.server, sample.offset, sample.delay, sample.dispersion
+            ),
+            None => info!("{}{} unreachable", tally, peer.server),
+        }
+    }
+
+    let time = (unix_nanos_now() as i128 + selection.offset as i128).max(0) as u64;
+    info!(
+        "Selected time: {}.{:09} UTC (Offset: {}ns, {} survivors, {} falsetickers)",
+        time / 1_000_000_000,
+        time % 1_000_000_000,
+        selection.offset,
+        selection.survivors.len(),
+        selection.falsetickers.len()
+    );
+    Ok(time)
+}
+
 fn main() -> Result<(), ExitCode> {
     #[cfg(any(target_os = "linux", target_os = "windows", target_os = "macos"))]
     cli_main()?;
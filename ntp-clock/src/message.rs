@@ -0,0 +1,300 @@
+//! Full NTPv4 messages: the 48-byte header, any extension fields
+//! ([RFC 7822](https://www.rfc-editor.org/rfc/rfc7822)) and a trailing MAC
+//! ([RFC 5905 Section 7.3](https://www.rfc-editor.org/rfc/rfc5905#section-7.3)).
+
+use heapless::Vec;
+
+use crate::constants::{
+    NTP_MAX_DIGEST_LEN, NTP_MAX_EXTENSION_FIELDS, NTP_MAX_EXTENSION_VALUE_LEN, NTP_MAX_PACKET_LEN,
+    NTP_MIN_PACKET_LEN,
+};
+use crate::error::ClockError;
+use crate::packets::NtpPacket;
+
+/// Type and length, each 16 bits
+const EXTENSION_HEADER_LEN: usize = 4;
+/// Shortest extension field allowed by RFC 7822
+const MIN_EXTENSION_LEN: usize = 16;
+/// The last extension field is padded to this when there's no MAC after it, so it can't be
+/// mistaken for one
+const MIN_LAST_EXTENSION_LEN: usize = 28;
+const KEY_ID_LEN: usize = 4;
+
+/// One extension field. The value includes any padding it had on the wire.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtensionField {
+    pub field_type: u16,
+    pub value: Vec<u8, NTP_MAX_EXTENSION_VALUE_LEN>,
+}
+
+impl ExtensionField {
+    pub fn new(field_type: u16, value: &[u8]) -> Result<Self, ClockError> {
+        Ok(ExtensionField {
+            field_type,
+            value: Vec::from_slice(value).map_err(|_| ClockError::InvalidExtensionField)?,
+        })
+    }
+
+    /// Length on the wire including the header, padded to a multiple of four and the minimum.
+    fn wire_len(&self, last: bool) -> usize {
+        let min = if last {
+            MIN_LAST_EXTENSION_LEN
+        } else {
+            MIN_EXTENSION_LEN
+        };
+        (EXTENSION_HEADER_LEN + self.value.len())
+            .next_multiple_of(4)
+            .max(min)
+    }
+}
+
+/// The message authentication code at the end of a message, a key ID and a digest. A key ID
+/// with no digest is a crypto-NAK.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mac {
+    pub key_id: u32,
+    pub digest: Vec<u8, NTP_MAX_DIGEST_LEN>,
+}
+
+impl Mac {
+    pub fn new(key_id: u32, digest: &[u8]) -> Result<Self, ClockError> {
+        Ok(Mac {
+            key_id,
+            digest: Vec::from_slice(digest).map_err(|_| ClockError::InvalidExtensionField)?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NtpMessage {
+    pub header: NtpPacket,
+    pub extensions: Vec<ExtensionField, NTP_MAX_EXTENSION_FIELDS>,
+    pub mac: Option<Mac>,
+}
+
+impl NtpMessage {
+    pub fn new(header: NtpPacket) -> Self {
+        NtpMessage {
+            header,
+            extensions: Vec::new(),
+            mac: None,
+        }
+    }
+
+    /// Parse a message. Whatever follows the header is read as extension fields, except a
+    /// 4, 20 or 24 byte tail which is a MAC (crypto-NAK, 128 or 160 bit digest).
+    pub fn parse(bytes: &[u8]) -> Result<Self, ClockError> {
+        let header = crate::unpack_ntp_packet(bytes)?;
+        let mut message = NtpMessage::new(header);
+        let mut rest = bytes.get(NTP_MIN_PACKET_LEN..).unwrap_or_default();
+
+        while !rest.is_empty() {
+            if is_mac_len(rest.len()) {
+                let (key_id, digest) = rest.split_at(KEY_ID_LEN);
+                let key_id = u32::from_be_bytes([key_id[0], key_id[1], key_id[2], key_id[3]]);
+                message.mac = Some(Mac::new(key_id, digest)?);
+                break;
+            }
+            let (field, remainder) = parse_extension(rest)?;
+            message
+                .extensions
+                .push(field)
+                .map_err(|_| ClockError::InvalidExtensionField)?;
+            rest = remainder;
+        }
+        Ok(message)
+    }
+
+    /// The first extension field of the given type.
+    pub fn extension(&self, field_type: u16) -> Option<&ExtensionField> {
+        self.extensions
+            .iter()
+            .find(|field| field.field_type == field_type)
+    }
+
+    pub fn push_extension(&mut self, field: ExtensionField) -> Result<(), ClockError> {
+        self.extensions
+            .push(field)
+            .map_err(|_| ClockError::InvalidExtensionField)
+    }
+
+    /// The header and extension fields, which is what a MAC covers.
+    pub fn authenticated_bytes(&self) -> Result<Vec<u8, NTP_MAX_PACKET_LEN>, ClockError> {
+        let mut bytes = self.bytes_before_extension(0)?;
+        let count = self.extensions.len();
+        for (index, field) in self.extensions.iter().enumerate() {
+            let last = index + 1 == count && self.mac.is_none();
+            write_extension(&mut bytes, field, last)?;
+        }
+        Ok(bytes)
+    }
+
+    /// The header and the extension fields before `index`, as they are on the wire. NTS
+    /// authenticates exactly these bytes.
+    pub fn bytes_before_extension(
+        &self,
+        index: usize,
+    ) -> Result<Vec<u8, NTP_MAX_PACKET_LEN>, ClockError> {
+        let mut bytes: Vec<u8, NTP_MAX_PACKET_LEN> = Vec::new();
+        let header = self.header.as_bytes().map_err(|_| ClockError::Io)?;
+        extend(&mut bytes, &header)?;
+        for field in self.extensions.iter().take(index) {
+            write_extension(&mut bytes, field, false)?;
+        }
+        Ok(bytes)
+    }
+
+    /// The message as it goes on the wire.
+    pub fn to_bytes(&self) -> Result<Vec<u8, NTP_MAX_PACKET_LEN>, ClockError> {
+        let mut bytes = self.authenticated_bytes()?;
+        if let Some(mac) = &self.mac {
+            extend(&mut bytes, &mac.key_id.to_be_bytes())?;
+            extend(&mut bytes, &mac.digest)?;
+        }
+        Ok(bytes)
+    }
+}
+
+/// Parse a run of extension fields with no MAC after them, like the plaintext of an NTS
+/// authenticator.
+pub fn parse_extension_fields(
+    mut bytes: &[u8],
+) -> Result<Vec<ExtensionField, NTP_MAX_EXTENSION_FIELDS>, ClockError> {
+    let mut fields = Vec::new();
+    while !bytes.is_empty() {
+        let (field, remainder) = parse_extension(bytes)?;
+        fields
+            .push(field)
+            .map_err(|_| ClockError::InvalidExtensionField)?;
+        bytes = remainder;
+    }
+    Ok(fields)
+}
+
+/// Serialize extension fields on their own, the reverse of [parse_extension_fields].
+pub fn write_extension_fields(
+    fields: &[ExtensionField],
+) -> Result<Vec<u8, NTP_MAX_PACKET_LEN>, ClockError> {
+    let mut bytes = Vec::new();
+    for field in fields {
+        write_extension(&mut bytes, field, false)?;
+    }
+    Ok(bytes)
+}
+
+fn write_extension<const N: usize>(
+    bytes: &mut Vec<u8, N>,
+    field: &ExtensionField,
+    last: bool,
+) -> Result<(), ClockError> {
+    let length = field.wire_len(last);
+    let length_field = u16::try_from(length).map_err(|_| ClockError::InvalidExtensionField)?;
+    extend(bytes, &field.field_type.to_be_bytes())?;
+    extend(bytes, &length_field.to_be_bytes())?;
+    extend(bytes, &field.value)?;
+    for _ in EXTENSION_HEADER_LEN + field.value.len()..length {
+        extend(bytes, &[0])?;
+    }
+    Ok(())
+}
+
+fn is_mac_len(len: usize) -> bool {
+    matches!(len, 4 | 20 | 24)
+}
+
+/// Split the first extension field off `bytes`.
+fn parse_extension(bytes: &[u8]) -> Result<(ExtensionField, &[u8]), ClockError> {
+    let [type_hi, type_lo, len_hi, len_lo, ..] = *bytes else {
+        return Err(ClockError::InvalidExtensionField);
+    };
+    let length = u16::from_be_bytes([len_hi, len_lo]) as usize;
+    if length < MIN_EXTENSION_LEN || !length.is_multiple_of(4) || length > bytes.len() {
+        return Err(ClockError::InvalidExtensionField);
+    }
+    let field = ExtensionField::new(
+        u16::from_be_bytes([type_hi, type_lo]),
+        &bytes[EXTENSION_HEADER_LEN..length],
+    )?;
+    Ok((field, &bytes[length..]))
+}
+
+fn extend<const N: usize>(bytes: &mut Vec<u8, N>, data: &[u8]) -> Result<(), ClockError> {
+    bytes
+        .extend_from_slice(data)
+        .map_err(|_| ClockError::InvalidExtensionField)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNIX_NANOS: u64 = 1_769_311_396_443_478_400;
+
+    #[test]
+    fn round_trip_with_mac() {
+        let mut message = NtpMessage::new(NtpPacket::from_nanos(UNIX_NANOS));
+        message
+            .push_extension(ExtensionField::new(0x0104, &[0xab; 32]).expect("fits"))
+            .expect("room for a field");
+        message
+            .push_extension(ExtensionField::new(0x0204, &[1, 2, 3]).expect("fits"))
+            .expect("room for a field");
+        message.mac = Some(Mac::new(7, &[0x55; 16]).expect("fits"));
+
+        let bytes = message.to_bytes().expect("should serialize");
+        // header, 36 byte field, 3 bytes padded to a 16 byte field, then the MAC
+        assert_eq!(bytes.len(), 48 + 36 + 16 + 20);
+        assert_eq!(bytes[48..52], [0x01, 0x04, 0x00, 36]);
+        assert_eq!(bytes[84..88], [0x02, 0x04, 0x00, 16]);
+
+        let parsed = NtpMessage::parse(&bytes).expect("should parse");
+        assert_eq!(parsed.extensions.len(), 2);
+        assert_eq!(parsed.extension(0x0104), message.extensions.first());
+        let padded = parsed.extension(0x0204).expect("second field");
+        assert_eq!(padded.value[..3], [1, 2, 3]);
+        assert_eq!(padded.value.len(), 12);
+        assert_eq!(parsed.mac, message.mac);
+        assert_eq!(parsed.header.transmit_time, message.header.transmit_time);
+    }
+
+    #[test]
+    fn last_field_is_not_mistaken_for_a_mac() {
+        let mut message = NtpMessage::new(NtpPacket::from_nanos(UNIX_NANOS));
+        message
+            .push_extension(ExtensionField::new(0x0104, &[0xab; 16]).expect("fits"))
+            .expect("room for a field");
+        let bytes = message.to_bytes().expect("should serialize");
+        assert_eq!(bytes.len(), 48 + 28);
+        let parsed = NtpMessage::parse(&bytes).expect("should parse");
+        assert_eq!(parsed.extensions.len(), 1);
+        assert_eq!(parsed.mac, None);
+    }
+
+    #[test]
+    fn rejects_bad_extension_fields() {
+        let header = NtpPacket::from_nanos(UNIX_NANOS)
+            .as_bytes()
+            .expect("should pack");
+        let mut bytes: Vec<u8, 128> = Vec::from_slice(&header).expect("fits");
+        // a length that runs off the end of the packet
+        bytes
+            .extend_from_slice(&[0x01, 0x04, 0x00, 0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+            .expect("fits");
+        assert!(matches!(
+            NtpMessage::parse(&bytes),
+            Err(ClockError::InvalidExtensionField)
+        ));
+        // not a multiple of four
+        bytes[51] = 0x11;
+        assert!(matches!(
+            NtpMessage::parse(&bytes),
+            Err(ClockError::InvalidExtensionField)
+        ));
+
+        // a crypto-NAK is just a key ID
+        let mut nak: Vec<u8, 128> = Vec::from_slice(&header).expect("fits");
+        nak.extend_from_slice(&[0, 0, 0, 0]).expect("fits");
+        let parsed = NtpMessage::parse(&nak).expect("should parse");
+        assert_eq!(parsed.mac, Some(Mac::new(0, &[]).expect("fits")));
+    }
+}
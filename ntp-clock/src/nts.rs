@@ -0,0 +1,622 @@
+//! Network Time Security, [RFC 8915](https://www.rfc-editor.org/rfc/rfc8915). NTS-KE runs
+//! over TLS to agree keys and fetch cookies, then every NTP request carries a cookie and both
+//! directions are authenticated with AEAD_AES_SIV_CMAC_256.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use aes_siv::KeyInit;
+use aes_siv::siv::Aes128Siv;
+use rustls::crypto::SecureRandom;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+use crate::constants::{NTP_MAX_EXTENSION_FIELDS, NTP_PORT, NTS_KE_PORT};
+use crate::message::{ExtensionField, NtpMessage, parse_extension_fields};
+use crate::packets::{KissCode, NtpPacket};
+use crate::prelude::*;
+
+pub const NTS_KE_ALPN: &[u8] = b"ntske/1";
+pub const NTS_EXPORTER_LABEL: &[u8] = b"EXPORTER-network-time-security";
+/// NTPv4 in the NTS Next Protocol Negotiation record
+pub const NTS_PROTOCOL_NTPV4: u16 = 0;
+/// The only AEAD algorithm we support, and the one every NTS server has to
+pub const AEAD_AES_SIV_CMAC_256: u16 = 15;
+pub const NTS_KEY_LEN: usize = 32;
+
+pub const EF_UNIQUE_IDENTIFIER: u16 = 0x0104;
+pub const EF_NTS_COOKIE: u16 = 0x0204;
+pub const EF_NTS_COOKIE_PLACEHOLDER: u16 = 0x0304;
+pub const EF_NTS_AUTHENTICATOR: u16 = 0x0404;
+
+/// How many cookies we try to keep, one per request until we hear back
+const MAX_COOKIES: usize = 8;
+/// The unique identifier, cookie and authenticator take the other fields
+const MAX_PLACEHOLDERS: usize = NTP_MAX_EXTENSION_FIELDS - 3;
+const UNIQUE_ID_LEN: usize = 32;
+const NONCE_LEN: usize = 16;
+const KE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One NTS-KE record, [RFC 8915 Section 4](https://www.rfc-editor.org/rfc/rfc8915#section-4).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NtsKeRecord {
+    pub critical: bool,
+    pub record_type: u16,
+    pub body: Vec<u8>,
+}
+
+impl NtsKeRecord {
+    pub const END_OF_MESSAGE: u16 = 0;
+    pub const NEXT_PROTOCOL: u16 = 1;
+    pub const ERROR: u16 = 2;
+    pub const WARNING: u16 = 3;
+    pub const AEAD_ALGORITHM: u16 = 4;
+    pub const NEW_COOKIE: u16 = 5;
+    pub const NTPV4_SERVER: u16 = 6;
+    pub const NTPV4_PORT: u16 = 7;
+
+    pub fn new(critical: bool, record_type: u16, body: &[u8]) -> Self {
+        NtsKeRecord {
+            critical,
+            record_type,
+            body: body.to_vec(),
+        }
+    }
+
+    pub fn end_of_message() -> Self {
+        Self::new(true, Self::END_OF_MESSAGE, &[])
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ClockError> {
+        let length = u16::try_from(self.body.len()).map_err(|_| ClockError::NtsKeyExchange)?;
+        let record_type = self.record_type | if self.critical { 0x8000 } else { 0 };
+        let mut bytes = Vec::with_capacity(4 + self.body.len());
+        bytes.extend_from_slice(&record_type.to_be_bytes());
+        bytes.extend_from_slice(&length.to_be_bytes());
+        bytes.extend_from_slice(&self.body);
+        Ok(bytes)
+    }
+
+    pub fn read(reader: &mut impl Read) -> Result<Self, ClockError> {
+        let mut header = [0u8; 4];
+        reader.read_exact(&mut header)?;
+        let record_type = u16::from_be_bytes([header[0], header[1]]);
+        let mut body = vec![0u8; u16::from_be_bytes([header[2], header[3]]) as usize];
+        reader.read_exact(&mut body)?;
+        Ok(NtsKeRecord {
+            critical: record_type & 0x8000 != 0,
+            record_type: record_type & 0x7fff,
+            body,
+        })
+    }
+
+    /// The body as a single big-endian u16, which most records are.
+    fn body_u16(&self) -> Result<u16, ClockError> {
+        match self.body.as_slice() {
+            [hi, lo] => Ok(u16::from_be_bytes([*hi, *lo])),
+            _ => Err(ClockError::NtsKeyExchange),
+        }
+    }
+}
+
+/// TLS 1.3 settings for NTS-KE, trusting the Mozilla root certificates.
+pub fn client_config() -> Result<Arc<ClientConfig>, ClockError> {
+    client_config_with_roots(RootCertStore::from_iter(
+        webpki_roots::TLS_SERVER_ROOTS.iter().cloned(),
+    ))
+}
+
+/// TLS 1.3 settings for NTS-KE, trusting `roots`.
+pub fn client_config_with_roots(roots: RootCertStore) -> Result<Arc<ClientConfig>, ClockError> {
+    let mut config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(|err| ClockError::ConfigError(format!("Failed to set up TLS: {}", err)))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    config.alpn_protocols = vec![NTS_KE_ALPN.to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// The keys and cookies from an NTS-KE session, used to protect NTP requests.
+#[derive(Debug)]
+pub struct NtsSession {
+    /// Where to send NTP requests, the NTS-KE server can hand us off to another one
+    pub ntp_server: SocketAddr,
+    c2s: [u8; NTS_KEY_LEN],
+    s2c: [u8; NTS_KEY_LEN],
+    cookies: Vec<Vec<u8>>,
+    /// Unique identifier of the outstanding request
+    unique_id: Option<[u8; UNIQUE_ID_LEN]>,
+    random: &'static dyn SecureRandom,
+}
+
+impl NtsSession {
+    pub fn new(
+        ntp_server: SocketAddr,
+        c2s: [u8; NTS_KEY_LEN],
+        s2c: [u8; NTS_KEY_LEN],
+        cookies: Vec<Vec<u8>>,
+    ) -> Self {
+        NtsSession {
+            ntp_server,
+            c2s,
+            s2c,
+            cookies,
+            unique_id: None,
+            random: rustls::crypto::ring::default_provider().secure_random,
+        }
+    }
+
+    /// Run NTS-KE against `host`, returns a session ready to query the NTP server it names.
+    pub fn key_exchange(
+        host: &str,
+        port: u16,
+        config: Arc<ClientConfig>,
+    ) -> Result<Self, ClockError> {
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|err| ClockError::ConfigError(format!("Invalid NTS-KE host: {}", err)))?;
+        let stream = TcpStream::connect((host, port)).map_err(|_| ClockError::NetworkError)?;
+        stream.set_read_timeout(Some(KE_TIMEOUT))?;
+        stream.set_write_timeout(Some(KE_TIMEOUT))?;
+        let connection = ClientConnection::new(config, server_name).map_err(|err| {
+            error!("Failed to start NTS-KE with {}: {}", host, err);
+            ClockError::NtsKeyExchange
+        })?;
+        let mut tls = StreamOwned::new(connection, stream);
+
+        let mut request = Vec::new();
+        for record in [
+            NtsKeRecord::new(
+                true,
+                NtsKeRecord::NEXT_PROTOCOL,
+                &NTS_PROTOCOL_NTPV4.to_be_bytes(),
+            ),
+            NtsKeRecord::new(
+                false,
+                NtsKeRecord::AEAD_ALGORITHM,
+                &AEAD_AES_SIV_CMAC_256.to_be_bytes(),
+            ),
+            NtsKeRecord::end_of_message(),
+        ] {
+            request.extend_from_slice(&record.to_bytes()?);
+        }
+        tls.write_all(&request).map_err(|err| {
+            error!("NTS-KE with {} failed: {}", host, err);
+            ClockError::NtsKeyExchange
+        })?;
+
+        let mut ntp_host = host.to_string();
+        let mut ntp_port = NTP_PORT;
+        let mut cookies = Vec::new();
+        let (mut protocol, mut algorithm) = (None, None);
+        loop {
+            let record = NtsKeRecord::read(&mut tls).map_err(|err| {
+                error!("NTS-KE with {} failed: {}", host, err);
+                ClockError::NtsKeyExchange
+            })?;
+            match record.record_type {
+                NtsKeRecord::END_OF_MESSAGE => break,
+                NtsKeRecord::NEXT_PROTOCOL => protocol = Some(record.body_u16()?),
+                NtsKeRecord::AEAD_ALGORITHM => algorithm = Some(record.body_u16()?),
+                NtsKeRecord::NEW_COOKIE if cookies.len() < MAX_COOKIES => cookies.push(record.body),
+                NtsKeRecord::NTPV4_SERVER => {
+                    ntp_host =
+                        String::from_utf8(record.body).map_err(|_| ClockError::NtsKeyExchange)?;
+                }
+                NtsKeRecord::NTPV4_PORT => ntp_port = record.body_u16()?,
+                NtsKeRecord::ERROR => {
+                    error!("NTS-KE server {} sent error {:?}", host, record.body_u16());
+                    return Err(ClockError::NtsKeyExchange);
+                }
+                NtsKeRecord::WARNING => {
+                    warn!(
+                        "NTS-KE server {} sent warning {:?}",
+                        host,
+                        record.body_u16()
+                    );
+                }
+                _ if record.critical => {
+                    error!(
+                        "NTS-KE server {} sent unknown critical record {}",
+                        host, record.record_type
+                    );
+                    return Err(ClockError::NtsKeyExchange);
+                }
+                _ => {}
+            }
+        }
+        if protocol != Some(NTS_PROTOCOL_NTPV4)
+            || algorithm != Some(AEAD_AES_SIV_CMAC_256)
+            || tls.conn.alpn_protocol() != Some(NTS_KE_ALPN)
+        {
+            error!("NTS-KE server {} didn't agree to NTPv4 with AES-SIV", host);
+            return Err(ClockError::NtsKeyExchange);
+        }
+        if cookies.is_empty() {
+            return Err(ClockError::NtsNoCookies);
+        }
+
+        let c2s = export_key(&tls.conn, 0)?;
+        let s2c = export_key(&tls.conn, 1)?;
+        tls.conn.send_close_notify();
+        let _ = tls.flush();
+
+        let ntp_server = (ntp_host.as_str(), ntp_port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| ClockError::ConfigError(format!("Failed to resolve {}", ntp_host)))?;
+        debug!(
+            "NTS-KE with {} gave {} cookies for {}",
+            host,
+            cookies.len(),
+            ntp_server
+        );
+        Ok(Self::new(ntp_server, c2s, s2c, cookies))
+    }
+
+    /// How many unused cookies we have, we need a new key exchange when they run out.
+    pub fn cookies(&self) -> usize {
+        self.cookies.len()
+    }
+
+    /// Protect a request, using up one cookie and asking for enough back to refill the jar.
+    pub fn request(&mut self, header: NtpPacket) -> Result<NtpMessage, ClockError> {
+        let cookie = self.cookies.pop().ok_or(ClockError::NtsNoCookies)?;
+        let mut unique_id = [0u8; UNIQUE_ID_LEN];
+        self.fill_random(&mut unique_id)?;
+
+        let mut message = NtpMessage::new(header);
+        message.push_extension(ExtensionField::new(EF_UNIQUE_IDENTIFIER, &unique_id)?)?;
+        message.push_extension(ExtensionField::new(EF_NTS_COOKIE, &cookie)?)?;
+        let placeholders = (MAX_COOKIES - 1)
+            .saturating_sub(self.cookies.len())
+            .min(MAX_PLACEHOLDERS);
+        let placeholder = vec![0u8; cookie.len()];
+        for _ in 0..placeholders {
+            message.push_extension(ExtensionField::new(
+                EF_NTS_COOKIE_PLACEHOLDER,
+                &placeholder,
+            )?)?;
+        }
+
+        let mut nonce = [0u8; NONCE_LEN];
+        self.fill_random(&mut nonce)?;
+        let associated_data = message.bytes_before_extension(message.extensions.len())?;
+        let ciphertext = aes_siv_seal(&self.c2s, &[&associated_data, &nonce], &[])?;
+        message.push_extension(authenticator(&nonce, &ciphertext)?)?;
+        self.unique_id = Some(unique_id);
+        Ok(message)
+    }
+
+    /// Does `bytes` echo the unique identifier of the outstanding request? Only then is it a
+    /// response to it, anything else (including packets which don't parse) is someone else's.
+    pub fn is_response(&self, bytes: &[u8]) -> bool {
+        let Some(unique_id) = self.unique_id else {
+            return false;
+        };
+        NtpMessage::parse(bytes).is_ok_and(|message| {
+            message
+                .extension(EF_UNIQUE_IDENTIFIER)
+                .is_some_and(|field| field.value.as_slice() == unique_id)
+        })
+    }
+
+    /// Check a response to the outstanding request and keep the cookies it carries.
+    ///
+    /// Fails with [ClockError::KissOfDeath] if the server couldn't use our cookie, the
+    /// remaining cookies are dropped as they won't work either.
+    pub fn handle_response(&mut self, bytes: &[u8]) -> Result<NtpMessage, ClockError> {
+        let unique_id = self.unique_id.ok_or(ClockError::OriginMismatch)?;
+        let message = NtpMessage::parse(bytes)?;
+        let echoed = message
+            .extension(EF_UNIQUE_IDENTIFIER)
+            .is_some_and(|field| field.value.as_slice() == unique_id);
+        if !echoed {
+            return Err(ClockError::OriginMismatch);
+        }
+        let Some(index) = message
+            .extensions
+            .iter()
+            .position(|field| field.field_type == EF_NTS_AUTHENTICATOR)
+        else {
+            // NTS NAKs aren't authenticated, the server couldn't decrypt our cookie
+            if message.header.kiss_code() == Some(KissCode::Ntsn) {
+                self.unique_id = None;
+                self.cookies.clear();
+                return Err(ClockError::KissOfDeath(KissCode::Ntsn));
+            }
+            return Err(ClockError::AuthenticationFailed);
+        };
+        let (nonce, ciphertext) = parse_authenticator(&message.extensions[index])?;
+        let associated_data = message.bytes_before_extension(index)?;
+        let plaintext = aes_siv_open(&self.s2c, &[&associated_data, nonce], ciphertext)?;
+        self.unique_id = None;
+        for field in parse_extension_fields(&plaintext)? {
+            if field.field_type == EF_NTS_COOKIE && self.cookies.len() < MAX_COOKIES {
+                self.cookies.push(field.value.to_vec());
+            }
+        }
+        Ok(message)
+    }
+
+    fn fill_random(&self, buffer: &mut [u8]) -> Result<(), ClockError> {
+        self.random.fill(buffer).map_err(|_| ClockError::Io)
+    }
+}
+
+/// Export one direction's key, 0 for client to server and 1 for server to client.
+fn export_key(
+    connection: &ClientConnection,
+    direction: u8,
+) -> Result<[u8; NTS_KEY_LEN], ClockError> {
+    let [protocol_hi, protocol_lo] = NTS_PROTOCOL_NTPV4.to_be_bytes();
+    let [aead_hi, aead_lo] = AEAD_AES_SIV_CMAC_256.to_be_bytes();
+    let context = [protocol_hi, protocol_lo, aead_hi, aead_lo, direction];
+    connection
+        .export_keying_material([0u8; NTS_KEY_LEN], NTS_EXPORTER_LABEL, Some(&context))
+        .map_err(|err| {
+            error!("Failed to export NTS keys: {}", err);
+            ClockError::NtsKeyExchange
+        })
+}
+
+/// The NTS Authenticator and Encrypted Extension Fields extension field.
+pub fn authenticator(nonce: &[u8], ciphertext: &[u8]) -> Result<ExtensionField, ClockError> {
+    let mut value = Vec::new();
+    for length in [nonce.len(), ciphertext.len()] {
+        let length = u16::try_from(length).map_err(|_| ClockError::InvalidExtensionField)?;
+        value.extend_from_slice(&length.to_be_bytes());
+    }
+    for part in [nonce, ciphertext] {
+        value.extend_from_slice(part);
+        value.resize(value.len().next_multiple_of(4), 0);
+    }
+    ExtensionField::new(EF_NTS_AUTHENTICATOR, &value)
+}
+
+/// Split an authenticator extension field into its nonce and ciphertext.
+pub fn parse_authenticator(field: &ExtensionField) -> Result<(&[u8], &[u8]), ClockError> {
+    let value = field.value.as_slice();
+    let [nonce_hi, nonce_lo, ciphertext_hi, ciphertext_lo, rest @ ..] = value else {
+        return Err(ClockError::InvalidExtensionField);
+    };
+    let nonce_len = u16::from_be_bytes([*nonce_hi, *nonce_lo]) as usize;
+    let ciphertext_len = u16::from_be_bytes([*ciphertext_hi, *ciphertext_lo]) as usize;
+    let nonce = rest
+        .get(..nonce_len)
+        .ok_or(ClockError::InvalidExtensionField)?;
+    let ciphertext = rest
+        .get(nonce_len.next_multiple_of(4)..)
+        .and_then(|rest| rest.get(..ciphertext_len))
+        .ok_or(ClockError::InvalidExtensionField)?;
+    Ok((nonce, ciphertext))
+}
+
+/// AES-SIV from [RFC 5297](https://www.rfc-editor.org/rfc/rfc5297) with a 256 bit key, the
+/// synthetic IV followed by the ciphertext. NTS passes the nonce as the last associated data.
+pub fn aes_siv_seal(
+    key: &[u8; NTS_KEY_LEN],
+    associated_data: &[&[u8]],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, ClockError> {
+    Aes128Siv::new(key.into())
+        .encrypt(associated_data, plaintext)
+        .map_err(|_| ClockError::AuthenticationFailed)
+}
+
+/// Reverse [aes_siv_seal], fails with [ClockError::AuthenticationFailed] if anything was
+/// tampered with.
+pub fn aes_siv_open(
+    key: &[u8; NTS_KEY_LEN],
+    associated_data: &[&[u8]],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, ClockError> {
+    Aes128Siv::new(key.into())
+        .decrypt(associated_data, ciphertext)
+        .map_err(|_| ClockError::AuthenticationFailed)
+}
+
+/// Split an NTS-KE server into host and port, using [NTS_KE_PORT] if there isn't a port.
+/// IPv6 addresses need brackets to have a port.
+pub fn split_host_port(server: &str) -> Result<(&str, u16), ClockError> {
+    let (host, port) = match server.strip_prefix('[') {
+        Some(rest) => match rest.split_once("]:") {
+            Some((host, port)) => (host, Some(port)),
+            None => (rest.trim_end_matches(']'), None),
+        },
+        None => match server.split_once(':') {
+            Some((host, port)) if !port.contains(':') => (host, Some(port)),
+            _ => (server, None),
+        },
+    };
+    match port {
+        Some(port) => port
+            .parse()
+            .map(|port| (host, port))
+            .map_err(|_| ClockError::ConfigError(format!("Invalid NTS-KE port in {}", server))),
+        None => Ok((host, NTS_KE_PORT)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(input: &str) -> Vec<u8> {
+        let input: String = input.split_whitespace().collect();
+        (0..input.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&input[index..index + 2], 16).expect("hex"))
+            .collect()
+    }
+
+    #[test]
+    fn aes_siv_matches_rfc_5297() {
+        // Appendix A.1, deterministic authenticated encryption
+        let key: [u8; 32] =
+            hex("fffefdfc fbfaf9f8 f7f6f5f4 f3f2f1f0 f0f1f2f3 f4f5f6f7 f8f9fafb fcfdfeff")
+                .try_into()
+                .expect("32 bytes");
+        let associated_data = hex("10111213 14151617 18191a1b 1c1d1e1f 20212223 24252627");
+        let plaintext = hex("11223344 55667788 99aabbcc ddee");
+        let sealed = aes_siv_seal(&key, &[&associated_data], &plaintext).expect("should seal");
+        assert_eq!(
+            sealed,
+            hex("85632d07 c6e8f37f 950acd32 0a2ecc93 40c02b96 90c4dc04 daef7f6a fe5c")
+        );
+        assert_eq!(
+            aes_siv_open(&key, &[&associated_data], &sealed).expect("should open"),
+            plaintext
+        );
+        let mut tampered = sealed.clone();
+        tampered[20] ^= 1;
+        assert!(matches!(
+            aes_siv_open(&key, &[&associated_data], &tampered),
+            Err(ClockError::AuthenticationFailed)
+        ));
+
+        // Appendix A.2, nonce-based authenticated encryption: two associated data components
+        // then the nonce, like NTS
+        let key: [u8; 32] =
+            hex("7f7e7d7c 7b7a7978 77767574 73727170 40414243 44454647 48494a4b 4c4d4e4f")
+                .try_into()
+                .expect("32 bytes");
+        let first = hex(
+            "00112233 44556677 8899aabb ccddeeff deaddada deaddada ffeeddcc bbaa9988 77665544 33221100",
+        );
+        let second = hex("10203040 50607080 90a0");
+        let nonce = hex("09f91102 9d74e35b d84156c5 635688c0");
+        let plaintext = hex(
+            "74686973 20697320 736f6d65 20706c61 696e7465 78742074 6f20656e 63727970
+             74207573 696e6720 5349562d 414553",
+        );
+        let associated_data: [&[u8]; 3] = [&first, &second, &nonce];
+        let sealed = aes_siv_seal(&key, &associated_data, &plaintext).expect("should seal");
+        assert_eq!(
+            sealed,
+            hex(
+                "7bdb6e3b 432667eb 06f4d14b ff2fbd0f cb900f2f ddbe4043 26601965 c889bf17
+                 dba77ceb 094fa663 b7a3f748 ba8af829 ea64ad54 4a272e9c 485b62a3 fd5c0d"
+            )
+        );
+        assert_eq!(
+            aes_siv_open(&key, &associated_data, &sealed).expect("should open"),
+            plaintext
+        );
+        // the order of the associated data matters
+        assert!(matches!(
+            aes_siv_open(&key, &[&second, &first, &nonce], &sealed),
+            Err(ClockError::AuthenticationFailed)
+        ));
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let record = NtsKeRecord::new(true, NtsKeRecord::NEXT_PROTOCOL, &[0, 0]);
+        let bytes = record.to_bytes().expect("should serialize");
+        assert_eq!(bytes, [0x80, 0x01, 0x00, 0x02, 0x00, 0x00]);
+        let parsed = NtsKeRecord::read(&mut bytes.as_slice()).expect("should parse");
+        assert_eq!(parsed, record);
+        assert_eq!(parsed.body_u16().expect("u16 body"), 0);
+    }
+
+    #[test]
+    fn request_and_response() {
+        let server: SocketAddr = "127.0.0.1:123".parse().expect("address");
+        let (c2s, s2c) = ([1u8; 32], [2u8; 32]);
+        let mut session = NtsSession::new(server, c2s, s2c, vec![vec![0xaa; 64]]);
+        let request = session
+            .request(NtpPacket::request())
+            .expect("should build a request");
+        assert_eq!(session.cookies(), 0);
+        assert_eq!(
+            request
+                .extension(EF_NTS_COOKIE)
+                .expect("cookie")
+                .value
+                .len(),
+            64
+        );
+        assert_eq!(
+            request
+                .extensions
+                .iter()
+                .filter(|field| field.field_type == EF_NTS_COOKIE_PLACEHOLDER)
+                .count(),
+            MAX_PLACEHOLDERS
+        );
+
+        // the server checks the request with the client to server key
+        let bytes = request.to_bytes().expect("should serialize");
+        let parsed = NtpMessage::parse(&bytes).expect("should parse");
+        let index = parsed.extensions.len() - 1;
+        let (nonce, ciphertext) = parse_authenticator(&parsed.extensions[index]).expect("auth");
+        let associated_data = parsed.bytes_before_extension(index).expect("bytes");
+        aes_siv_open(&c2s, &[&associated_data, nonce], ciphertext).expect("request should verify");
+
+        // and answers with the unique identifier and a new cookie
+        let mut response = NtpMessage::new(NtpPacket::from_nanos(1_769_311_396_443_478_400));
+        response
+            .push_extension(
+                request
+                    .extension(EF_UNIQUE_IDENTIFIER)
+                    .expect("uid")
+                    .clone(),
+            )
+            .expect("room");
+        let cookies = crate::message::write_extension_fields(&[ExtensionField::new(
+            EF_NTS_COOKIE,
+            &[0xbb; 64],
+        )
+        .expect("cookie")])
+        .expect("should serialize");
+        let associated_data = response.bytes_before_extension(1).expect("bytes");
+        let nonce = [3u8; NONCE_LEN];
+        let sealed =
+            aes_siv_seal(&s2c, &[&associated_data, &nonce], &cookies).expect("should seal");
+        response
+            .push_extension(authenticator(&nonce, &sealed).expect("auth"))
+            .expect("room");
+        let bytes = response.to_bytes().expect("should serialize");
+
+        assert!(session.is_response(&bytes));
+        assert!(!session.is_response(&[0u8; 12]));
+        let mut replay = NtsSession::new(server, c2s, s2c, Vec::new());
+        assert!(!replay.is_response(&bytes));
+        assert!(matches!(
+            replay.handle_response(&bytes),
+            Err(ClockError::OriginMismatch)
+        ));
+        let mut tampered = bytes.clone();
+        tampered[1] ^= 1;
+        assert!(matches!(
+            session.handle_response(&tampered),
+            Err(ClockError::AuthenticationFailed)
+        ));
+        session
+            .handle_response(&bytes)
+            .expect("response should verify");
+        assert_eq!(session.cookies(), 1);
+        assert!(matches!(
+            session.handle_response(&bytes),
+            Err(ClockError::OriginMismatch)
+        ));
+    }
+
+    #[test]
+    fn host_and_port() {
+        for (server, expected) in [
+            ("time.cloudflare.com", ("time.cloudflare.com", 4460)),
+            ("localhost:4461", ("localhost", 4461)),
+            ("[::1]:4461", ("::1", 4461)),
+            ("[::1]", ("::1", 4460)),
+            ("::1", ("::1", 4460)),
+        ] {
+            assert_eq!(split_host_port(server).expect("should split"), expected);
+        }
+        assert!(split_host_port("localhost:ntske").is_err());
+    }
+}
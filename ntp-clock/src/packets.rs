@@ -1,4 +1,13 @@
-use crate::{constants::NTP_MIN_PACKET_LEN, error::ClockError};
+use crate::{
+    NTP_UNIX_EPOCH,
+    constants::{
+        NTP_CONTROL_HEADER_LEN, NTP_CONTROL_MAX_DATA_LEN, NTP_ERA_PIVOT_UNIX_NANOS,
+        NTP_MAX_DISTANCE_NANOS, NTP_MIN_PACKET_LEN,
+    },
+    error::ClockError,
+};
+
+use core::time::Duration;
 
 use heapless::string::String as HeaplessString;
 use packed_struct::prelude::*;
@@ -63,28 +72,25 @@ pub struct NtpPacket {
     /// relative time and frequency offsets. The values that normally appear
     /// in this field range from negative values of a few milliseconds to
     /// positive values of several hundred milliseconds.
-    root_delay_ms: [u8; 4],
+    root_delay: [u8; 4],
     #[packed_field(endian = "msb")]
-    /// Indicates the estimated dispersion to the primary synchronizing source.
-    dispersion: [u8; 4],
+    /// Indicates the estimated dispersion to the primary synchronizing source, in NTP short format.
+    root_dispersion: [u8; 4],
     #[packed_field(endian = "msb")]
     /// A four-byte reference identifier identifying the particular server or reference clock.
     pub identifier: u32,
-    #[packed_field(endian = "msb")]
+    #[packed_field(element_size_bytes = "8")]
     /// Indicates the local time at which the local clock is last set or corrected.Value 0 indicates that the local clock is never synchronized.
-    pub ref_time: u64,
-    #[packed_field(endian = "msb")]
+    pub ref_time: NtpTimestamp,
+    #[packed_field(element_size_bytes = "8")]
     /// Indicates the local time at which the NTP request is sent from the client host.
-    pub origin_time: u64,
-    #[packed_field(endian = "msb")]
+    pub origin_time: NtpTimestamp,
+    #[packed_field(element_size_bytes = "8")]
     /// Indicates the local time at which the request arrives at the service host.
-    pub recv_time: u64,
-    #[packed_field(endian = "msb")]
+    pub recv_time: NtpTimestamp,
+    #[packed_field(element_size_bytes = "8")]
     /// Indicates the local time at which the response packet is sent from the service host to the client host.
-    pub transmit_time: u64,
-
-    #[packed_field(endian = "msb", optional = "true")]
-    pub authenticator: [u8; 12],
+    pub transmit_time: NtpTimestamp,
 }
 
 impl NtpPacket {
@@ -96,18 +102,17 @@ impl NtpPacket {
             stratum: 0,
             poll: 0,
             precision: 0,
-            root_delay_ms: [0, 0, 0, 0],
-            dispersion: [0, 0, 0, 0],
+            root_delay: [0, 0, 0, 0],
+            root_dispersion: [0, 0, 0, 0],
             identifier: 0,
-            ref_time: 0,
-            origin_time: 0,
-            recv_time: 0,
-            transmit_time: 0,
-            authenticator: [0u8; 12],
+            ref_time: NtpTimestamp::ZERO,
+            origin_time: NtpTimestamp::ZERO,
+            recv_time: NtpTimestamp::ZERO,
+            transmit_time: NtpTimestamp::ZERO,
         }
     }
 
-    pub fn with_transmit_time(&mut self, transmit_time: u64) -> Self {
+    pub fn with_transmit_time(&mut self, transmit_time: NtpTimestamp) -> Self {
         Self {
             transmit_time,
             ..*self
@@ -116,15 +121,7 @@ impl NtpPacket {
 
     /// Create an NTP response packet from a given UNIX timestamp in nanoseconds.
     pub fn from_nanos(unix_nanos: u64) -> NtpPacket {
-        // let mut packet = [0u8; NTP_PACKET_LEN];
-        // let unix_seconds = unix_nanos / 1_000_000_000;
-        // let nanos = (unix_nanos % 1_000_000_000) as u128;
-        // let seconds = (unix_seconds as i64 + NTP_UNIX_EPOCH) as u32;
-        // let fraction = ((nanos << 32) / 1_000_000_000u128) as u32;
-        // packet[40..44].copy_from_slice(&ntp_seconds.to_be_bytes());
-        // packet[44..NTP_PACKET_LEN].copy_from_slice(&fraction.to_be_bytes());
-
-        #[allow(clippy::expect_used)]
+        let timestamp = NtpTimestamp::from_unix_nanos(unix_nanos);
         Self {
             leap_indicator: 0,
             version: 3,
@@ -132,14 +129,13 @@ impl NtpPacket {
             stratum: 1,
             poll: 4,
             precision: -25,
-            root_delay_ms: [0, 0, 0, 15],         // 15 microseconds
-            dispersion: [0x00, 0x00, 0x99, 0x9f], // 0.6 seconds
-            identifier: 0x50505300,               // Generic PPS
-            ref_time: unix_nanos,
-            origin_time: 0,
-            recv_time: unix_nanos,
-            transmit_time: unix_nanos,
-            authenticator: [0u8; 12],
+            root_delay: [0, 0, 0, 1],                  // 15 microseconds
+            root_dispersion: [0x00, 0x00, 0x99, 0x9f], // 0.6 seconds
+            identifier: 0x50505300,                    // Generic PPS
+            ref_time: timestamp,
+            origin_time: NtpTimestamp::ZERO,
+            recv_time: timestamp,
+            transmit_time: timestamp,
         }
     }
 
@@ -148,7 +144,7 @@ impl NtpPacket {
         {
             use core::fmt::Write;
             let mut s = HeaplessString::<256>::new();
-            let _ = core::write!(s, "{}", self.ref_time,);
+            let _ = core::write!(s, "{}", self.ref_time.to_unix_nanos());
             s
         }
     }
@@ -158,15 +154,15 @@ impl NtpPacket {
     pub fn to_string(&self) -> String {
         {
             format!(
-                "NtpResponse {{ leap_indicator: {}, version: {}, mode: {:?}, stratum: {}, poll: {}, precision: {}, delay_ms: {:?}, dispersion: {}, identifier: {}, ref_time: {}, origin_time: {}, recv_time: {}, transmit_time: {} }}",
+                "NtpResponse {{ leap_indicator: {}, version: {}, mode: {:?}, stratum: {}, poll: {}, precision: {}, root_delay: {}ns, root_dispersion: {}ns, identifier: {}, ref_time: {}, origin_time: {}, recv_time: {}, transmit_time: {} }}",
                 self.leap_indicator,
                 self.version,
                 self.mode,
                 self.stratum,
                 self.poll,
                 self.precision,
-                self.delay_ms(),
-                self.dispersion(),
+                self.root_delay_nanos(),
+                self.root_dispersion_nanos(),
                 match self.remote_id() {
                     Ok(id) => match id {
                         NtpIdentifier::IpAddr(ip) => format!("IP({})", ip),
@@ -174,10 +170,10 @@ impl NtpPacket {
                     },
                     Err(_) => "Invalid Identifier".to_string(),
                 },
-                self.ref_time,
-                self.origin_time,
-                self.recv_time,
-                self.transmit_time
+                self.ref_time.to_unix_nanos(),
+                self.origin_time.to_unix_nanos(),
+                self.recv_time.to_unix_nanos(),
+                self.transmit_time.to_unix_nanos()
             )
         }
         #[cfg(not(feature = "std"))]
@@ -190,12 +186,10 @@ impl NtpPacket {
         }
     }
 
-    /// Returns the packed bytes of the NTP response packet without the authenticator bytes
+    /// Returns the packed header bytes, extension fields and MACs are added by
+    /// [crate::message::NtpMessage]
     pub fn as_bytes(&self) -> Result<[u8; NTP_MIN_PACKET_LEN], packed_struct::PackingError> {
-        let bytes = self.pack()?;
-        let mut array = [0u8; NTP_MIN_PACKET_LEN];
-        array.copy_from_slice(&bytes[0..NTP_MIN_PACKET_LEN]);
-        Ok(array)
+        self.pack()
     }
 
     pub fn mode(&self) -> NtpMode {
@@ -205,7 +199,14 @@ impl NtpPacket {
         }
     }
 
-    /// Get the remote ID as an IpAddr
+    pub fn set_mode(&mut self, mode: NtpMode) {
+        self.mode = mode.into();
+    }
+
+    /// Get the remote ID as an IpAddr. Past stratum 1 this is the upstream's IPv4 address, or
+    /// when the upstream is an IPv6 server the first four octets of the MD5 hash of its
+    /// address ([RFC 5905 Section 7.3](https://www.rfc-editor.org/rfc/rfc5905#section-7.3)),
+    /// and nothing in the packet says which.
     pub fn remote_id(&self) -> Result<NtpIdentifier, ClockError> {
         match self.stratum {
             0 => {
@@ -230,31 +231,82 @@ impl NtpPacket {
     }
 
     /// Calculate the offset between the local clock and the NTP server clock in nanoseconds.
+    ///
+    /// This trusts the origin time echoed by the server, use [crate::sample::SyncSample] for
+    /// the full four-timestamp calculation including round-trip delay.
     pub fn offset_from_local(&self, local_time_nanos: u64) -> i64 {
-        if self.origin_time == 0 || local_time_nanos == 0 {
+        if self.origin_time.is_zero() || local_time_nanos == 0 {
             return 0;
         }
-        let origin_time = self.origin_time as i128;
+        let origin_time = self.origin_time.to_unix_nanos() as i128;
 
-        ((self.recv_time as i128 - origin_time)
-            + (self.transmit_time as i128 - local_time_nanos as i128))
+        ((self.recv_time.to_unix_nanos() as i128 - origin_time)
+            + (self.transmit_time.to_unix_nanos() as i128 - local_time_nanos as i128))
             .saturating_div(2) as i64
     }
 
-    /// Root Delay: This is a 32-bit signed fixed-point number indicating the
-    /// total roundtrip delay to the primary reference source, in seconds
-    /// with fraction point between bits 15 and 16. Note that this variable
-    /// can take on both positive and negative values, depending on the
-    /// relative time and frequency offsets. The values that normally appear
-    /// in this field range from negative values of a few milliseconds to
-    /// positive values of several hundred milliseconds.
-    pub fn delay_ms(&self) -> Option<f32> {
-        let root_delay = RootDelay::unpack(&self.root_delay_ms).ok()?;
-        Some(root_delay.to_milliseconds())
+    /// Root delay, the round trip to the primary reference source. Older servers may send
+    /// small negative values (RFC 1305 defines it as signed), so read it with
+    /// [NtpShort::as_signed_nanos] or use [Self::root_delay_nanos].
+    pub fn root_delay(&self) -> NtpShort {
+        NtpShort::from_be_bytes(self.root_delay)
     }
 
-    pub fn dispersion(&self) -> f32 {
-        f32::from_be_bytes(self.dispersion)
+    /// Root delay in nanoseconds, negative values are preserved.
+    pub fn root_delay_nanos(&self) -> i64 {
+        self.root_delay().as_signed_nanos()
+    }
+
+    pub fn set_root_delay(&mut self, root_delay: NtpShort) {
+        self.root_delay = root_delay.to_be_bytes();
+    }
+
+    /// Root dispersion, the maximum error relative to the primary reference source.
+    pub fn root_dispersion(&self) -> NtpShort {
+        NtpShort::from_be_bytes(self.root_dispersion)
+    }
+
+    /// Root dispersion in nanoseconds.
+    pub fn root_dispersion_nanos(&self) -> u64 {
+        self.root_dispersion().as_nanos()
+    }
+
+    pub fn set_root_dispersion(&mut self, root_dispersion: NtpShort) {
+        self.root_dispersion = root_dispersion.to_be_bytes();
+    }
+
+    /// The server's distance to its primary reference, half the root delay plus the root
+    /// dispersion, in nanoseconds.
+    pub fn root_distance(&self) -> u64 {
+        (self.root_delay_nanos().max(0) as u64 / 2).saturating_add(self.root_dispersion_nanos())
+    }
+
+    /// Check a packet is fit to set the time from: the right mode, from a synchronized
+    /// server with a valid stratum, a transmit time and a reasonable root distance.
+    ///
+    /// Kiss-o'-Death packets fail the stratum check, look for them with [Self::kiss_code] first.
+    pub fn check_usable(&self, expected_mode: NtpMode) -> Result<(), ClockError> {
+        if self.mode() != expected_mode {
+            return Err(ClockError::ModeMismatch(self.mode()));
+        }
+        if self.leap_indicator == 3 {
+            return Err(ClockError::Unsynchronized);
+        }
+        if self.stratum == 0 || self.stratum >= 16 {
+            return Err(ClockError::InvalidStratum(self.stratum));
+        }
+        if self.transmit_time.is_zero() {
+            return Err(ClockError::ZeroTransmitTime);
+        }
+        let root_distance = self.root_distance();
+        if root_distance > NTP_MAX_DISTANCE_NANOS {
+            return Err(ClockError::ExcessiveRootDistance(root_distance));
+        }
+        Ok(())
+    }
+
+    pub fn is_usable(&self, expected_mode: NtpMode) -> bool {
+        self.check_usable(expected_mode).is_ok()
     }
 
     // From [RFC2030 Section 4](https://www.rfc-editor.org/rfc/rfc2030.html#section-4)
@@ -276,6 +328,110 @@ impl NtpPacket {
             16..=255 => "reserved",
         }
     }
+
+    /// If this is a Kiss-o'-Death packet, returns the kiss code carried in the reference identifier.
+    ///
+    /// From [RFC 5905 Section 7.4](https://www.rfc-editor.org/rfc/rfc5905#section-7.4), these
+    /// have a stratum of 0 and four ASCII characters in the identifier. The timestamps in a
+    /// KoD packet are not valid and must not be used to set the clock.
+    pub fn kiss_code(&self) -> Option<KissCode> {
+        if self.stratum != 0 {
+            return None;
+        }
+        let bytes = self.identifier.to_be_bytes();
+        if bytes.iter().all(|b| b.is_ascii_alphanumeric()) {
+            Some(KissCode::from(bytes))
+        } else {
+            None
+        }
+    }
+}
+
+/// Kiss codes from [RFC 5905 Section 7.4](https://www.rfc-editor.org/rfc/rfc5905#section-7.4)
+/// and [RFC 8915 Section 5.7](https://www.rfc-editor.org/rfc/rfc8915#section-5.7).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KissCode {
+    Acst,
+    Auth,
+    Auto,
+    Bcst,
+    Cryp,
+    Deny,
+    Drop,
+    Rstr,
+    Init,
+    Mcst,
+    Nkey,
+    Ntsn,
+    Rate,
+    Rmot,
+    Step,
+    Unknown([u8; 4]),
+}
+
+/// What a client should do after receiving a Kiss-o'-Death packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KissAction {
+    /// Stop sending requests to this server
+    Demobilize,
+    /// Increase the interval between requests to this server
+    ReducePolling,
+    /// Discard the packet, but keep using the server
+    Discard,
+}
+
+impl From<[u8; 4]> for KissCode {
+    fn from(bytes: [u8; 4]) -> Self {
+        match &bytes {
+            b"ACST" => Self::Acst,
+            b"AUTH" => Self::Auth,
+            b"AUTO" => Self::Auto,
+            b"BCST" => Self::Bcst,
+            b"CRYP" => Self::Cryp,
+            b"DENY" => Self::Deny,
+            b"DROP" => Self::Drop,
+            b"RSTR" => Self::Rstr,
+            b"INIT" => Self::Init,
+            b"MCST" => Self::Mcst,
+            b"NKEY" => Self::Nkey,
+            b"NTSN" => Self::Ntsn,
+            b"RATE" => Self::Rate,
+            b"RMOT" => Self::Rmot,
+            b"STEP" => Self::Step,
+            _ => Self::Unknown(bytes),
+        }
+    }
+}
+
+impl KissCode {
+    pub fn action(&self) -> KissAction {
+        match self {
+            Self::Deny | Self::Rstr => KissAction::Demobilize,
+            Self::Rate => KissAction::ReducePolling,
+            _ => KissAction::Discard,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::Acst => "the association belongs to a unicast server",
+            Self::Auth => "server authentication failed",
+            Self::Auto => "autokey sequence failed",
+            Self::Bcst => "the association belongs to a broadcast server",
+            Self::Cryp => "cryptographic authentication or identification failed",
+            Self::Deny => "access denied by remote server",
+            Self::Drop => "lost peer in symmetric mode",
+            Self::Rstr => "access denied due to local policy",
+            Self::Init => "the association has not yet synchronized for the first time",
+            Self::Mcst => "the association belongs to a dynamically discovered server",
+            Self::Nkey => "no key found",
+            Self::Ntsn => "NTS negative acknowledgment, the cookie could not be decrypted",
+            Self::Rate => "rate exceeded, the server has temporarily denied access",
+            Self::Rmot => "alteration of association from a remote host running ntpdc",
+            Self::Step => "a step change in system time has occurred",
+            Self::Unknown(_) => "unknown kiss code",
+        }
+    }
 }
 
 #[cfg(feature = "std")]
@@ -403,8 +559,7 @@ impl NtpIdentifier {
             #[cfg(feature = "std")]
             NtpIdentifier::IpAddr(ip) => match ip {
                 IpAddr::V4(v4) => u32::from_be_bytes(v4.octets()),
-                // TODO: Handle IPv6 properly?
-                IpAddr::V6(_) => 0,
+                IpAddr::V6(_) => crate::server::reference_id(*ip),
             },
             #[cfg(not(feature = "std"))]
             NtpIdentifier::IpAddr(ip_u32) => *ip_u32,
@@ -420,22 +575,401 @@ impl NtpIdentifier {
     }
 }
 
-#[derive(PackedStruct)]
-#[packed_struct(bit_numbering = "msb0")]
-struct RootDelay {
-    #[packed_field(endian = "msb", bits = "0..=15")]
-    int_part: u16,
-    #[packed_field(endian = "msb", bits = "16..=31")]
-    frac_part: u16,
+/// The 32-bit NTP short format from
+/// [RFC 5905 Section 6](https://www.rfc-editor.org/rfc/rfc5905#section-6), 16 bits of
+/// seconds and 16 bits of fraction, used for the root delay and root dispersion.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NtpShort(pub u32);
+
+impl NtpShort {
+    pub const ZERO: NtpShort = NtpShort(0);
+    pub const MAX: NtpShort = NtpShort(u32::MAX);
+
+    pub const fn from_be_bytes(bytes: [u8; 4]) -> Self {
+        NtpShort(u32::from_be_bytes(bytes))
+    }
+
+    pub const fn to_be_bytes(self) -> [u8; 4] {
+        self.0.to_be_bytes()
+    }
+
+    /// Nanoseconds, reading the value as unsigned.
+    pub const fn as_nanos(self) -> u64 {
+        (self.0 as u64 * 1_000_000_000) >> 16
+    }
+
+    /// Nanoseconds, reading the value as a signed (two's complement) 16.16 number.
+    pub const fn as_signed_nanos(self) -> i64 {
+        (self.0 as i32 as i64 * 1_000_000_000) >> 16
+    }
+
+    /// Rounded up so error bounds are never understated, saturating at just over 65535 seconds.
+    pub const fn from_nanos(nanos: u64) -> Self {
+        let value = ((nanos as u128) << 16).div_ceil(1_000_000_000);
+        if value > u32::MAX as u128 {
+            Self::MAX
+        } else {
+            NtpShort(value as u32)
+        }
+    }
+
+    /// The nearest signed short format value, saturating at +/- 32768 seconds.
+    pub const fn from_signed_nanos(nanos: i64) -> Self {
+        let value = ((nanos as i128) << 16) / 1_000_000_000;
+        let value = if value > i32::MAX as i128 {
+            i32::MAX
+        } else if value < i32::MIN as i128 {
+            i32::MIN
+        } else {
+            value as i32
+        };
+        NtpShort(value as u32)
+    }
+
+    pub const fn to_duration(self) -> Duration {
+        Duration::from_nanos(self.as_nanos())
+    }
+}
+
+impl From<NtpShort> for Duration {
+    fn from(value: NtpShort) -> Self {
+        value.to_duration()
+    }
+}
+
+impl From<Duration> for NtpShort {
+    fn from(value: Duration) -> Self {
+        NtpShort::from_nanos(value.as_nanos().min(u64::MAX as u128) as u64)
+    }
+}
+
+const NANOS_PER_SECOND: i128 = 1_000_000_000;
+
+/// A 64-bit NTP timestamp as it appears on the wire, 32 bits of seconds and 32 bits of
+/// fraction, see [RFC 5905 Section 6](https://www.rfc-editor.org/rfc/rfc5905#section-6).
+///
+/// The seconds wrap every 2^32 seconds (about 136 years), so the timestamp doesn't say which
+/// era it's in on its own. Era 0 started in 1900 and ends on 2036-02-07.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NtpTimestamp(pub u64);
+
+impl NtpTimestamp {
+    /// The zero timestamp means "unknown" rather than the start of an era
+    pub const ZERO: NtpTimestamp = NtpTimestamp(0);
+
+    pub const fn seconds(self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+
+    pub const fn fraction(self) -> u32 {
+        self.0 as u32
+    }
+
+    pub const fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Encode a UNIX time in nanoseconds, dropping the era.
+    pub const fn from_unix_nanos(unix_nanos: u64) -> Self {
+        let seconds = (unix_nanos / 1_000_000_000) as i128 + NTP_UNIX_EPOCH as i128;
+        let nanos = (unix_nanos % 1_000_000_000) as i128;
+        let fraction = (nanos << 32) / NANOS_PER_SECOND;
+        NtpTimestamp(((seconds as u64) << 32) | fraction as u64)
+    }
+
+    /// The era a UNIX time in nanoseconds falls in.
+    pub const fn era_of_unix_nanos(unix_nanos: u64) -> u32 {
+        let seconds = (unix_nanos / 1_000_000_000) as i128 + NTP_UNIX_EPOCH as i128;
+        (seconds >> 32) as u32
+    }
+
+    /// Decode as a time in the given era, `None` if that's before the UNIX epoch.
+    pub const fn to_unix_nanos_in_era(self, era: u32) -> Option<u64> {
+        let seconds = ((era as i128) << 32) + self.seconds() as i128 - NTP_UNIX_EPOCH as i128;
+        if seconds < 0 {
+            return None;
+        }
+        let nanos = (self.fraction() as i128 * NANOS_PER_SECOND) >> 32;
+        let unix_nanos = seconds * NANOS_PER_SECOND + nanos;
+        if unix_nanos > u64::MAX as i128 {
+            None
+        } else {
+            Some(unix_nanos as u64)
+        }
+    }
+
+    /// Decode as UNIX nanoseconds, in the era nearest [NTP_ERA_PIVOT_UNIX_NANOS]. Zero and
+    /// pre-1970 timestamps decode as 0.
+    pub const fn to_unix_nanos(self) -> u64 {
+        self.to_unix_nanos_near(NTP_ERA_PIVOT_UNIX_NANOS)
+    }
+
+    /// Decode as UNIX nanoseconds in whichever era puts the timestamp within 68 years of
+    /// `pivot_unix_nanos`, usually the last time we knew to be good. Zero and pre-1970
+    /// timestamps decode as 0.
+    pub const fn to_unix_nanos_near(self, pivot_unix_nanos: u64) -> u64 {
+        if self.is_zero() {
+            return 0;
+        }
+        let pivot_era = Self::era_of_unix_nanos(pivot_unix_nanos) as i64;
+        let pivot_seconds = Self::from_unix_nanos(pivot_unix_nanos).seconds() as i64;
+        let difference = self.seconds() as i64 - pivot_seconds;
+        let era = if difference > i32::MAX as i64 {
+            pivot_era - 1
+        } else if difference < i32::MIN as i64 {
+            pivot_era + 1
+        } else {
+            pivot_era
+        };
+        if era < 0 || era > u32::MAX as i64 {
+            return 0;
+        }
+        match self.to_unix_nanos_in_era(era as u32) {
+            Some(unix_nanos) => unix_nanos,
+            None => 0,
+        }
+    }
 }
-impl RootDelay {
-    pub fn to_milliseconds(&self) -> f32 {
-        let int_ms = self.int_part as f32;
-        let frac_ms = (self.frac_part as f32) / 65536.0;
-        int_ms + frac_ms
+
+impl PackedStruct for NtpTimestamp {
+    type ByteArray = [u8; 8];
+
+    fn pack(&self) -> packed_struct::PackingResult<Self::ByteArray> {
+        Ok(self.0.to_be_bytes())
+    }
+
+    fn unpack(src: &Self::ByteArray) -> packed_struct::PackingResult<Self> {
+        Ok(NtpTimestamp(u64::from_be_bytes(*src)))
     }
 }
 
+/// Control message opcodes from [RFC 9327 Section 2.4](https://www.rfc-editor.org/rfc/rfc9327#section-2.4).
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlOpcode {
+    /// Association IDs and status words of the server's peers
+    ReadStatus = 1,
+    /// System variables, or a peer's variables when given an association ID
+    ReadVariables = 2,
+    WriteVariables = 3,
+    ReadClockVariables = 4,
+    WriteClockVariables = 5,
+    SetTrapAddress = 6,
+    AsyncMessage = 7,
+    ClearTrapAddress = 31,
+}
+
+impl TryFrom<u8> for ControlOpcode {
+    type Error = ClockError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            1 => Self::ReadStatus,
+            2 => Self::ReadVariables,
+            3 => Self::WriteVariables,
+            4 => Self::ReadClockVariables,
+            5 => Self::WriteClockVariables,
+            6 => Self::SetTrapAddress,
+            7 => Self::AsyncMessage,
+            31 => Self::ClearTrapAddress,
+            _ => return Err(ClockError::InvalidResponse),
+        })
+    }
+}
+
+/// An NTP control (mode 6) message, as used by `ntpq`, from
+/// [RFC 9327 Section 2](https://www.rfc-editor.org/rfc/rfc9327#section-2). Long responses
+/// come back as several messages, each with the `offset` of its data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ControlMessage {
+    pub version: u8,
+    /// Set on responses from the server
+    pub response: bool,
+    /// Set when the server couldn't carry out the request, the error code is in `status`
+    pub error: bool,
+    /// Set when more fragments of the response follow
+    pub more: bool,
+    pub opcode: ControlOpcode,
+    /// Matches a response to its request
+    pub sequence: u16,
+    pub status: u16,
+    /// 0 for the system, otherwise one of the server's peers
+    pub association_id: u16,
+    /// Where this fragment's data goes in the whole response
+    pub offset: u16,
+    pub data: heapless::Vec<u8, NTP_CONTROL_MAX_DATA_LEN>,
+}
+
+impl ControlMessage {
+    pub fn request(opcode: ControlOpcode, sequence: u16, association_id: u16) -> Self {
+        ControlMessage {
+            version: 2,
+            response: false,
+            error: false,
+            more: false,
+            opcode,
+            sequence,
+            status: 0,
+            association_id,
+            offset: 0,
+            data: heapless::Vec::new(),
+        }
+    }
+
+    /// The error code from a response with the error bit set, see [ControlMessage::error_description].
+    pub fn error_code(&self) -> Option<u8> {
+        self.error.then_some((self.status >> 8) as u8)
+    }
+
+    pub fn error_description(code: u8) -> &'static str {
+        match code {
+            1 => "authentication failure",
+            2 => "invalid message length or format",
+            3 => "invalid opcode",
+            4 => "unknown association identifier",
+            5 => "unknown variable name",
+            6 => "invalid variable value",
+            7 => "administratively prohibited",
+            _ => "unspecified error",
+        }
+    }
+
+    /// The message on the wire, with the data padded to a multiple of 4 bytes.
+    pub fn to_bytes(
+        &self,
+    ) -> Result<heapless::Vec<u8, { NTP_CONTROL_HEADER_LEN + NTP_CONTROL_MAX_DATA_LEN }>, ClockError>
+    {
+        let mut bytes = heapless::Vec::new();
+        let flags = ((self.response as u8) << 7)
+            | ((self.error as u8) << 6)
+            | ((self.more as u8) << 5)
+            | (self.opcode as u8 & 0x1f);
+        let count = self.data.len() as u16;
+        let header = [
+            [
+                (self.version & 0x07) << 3 | NtpMode::ReservedForNtpControlMessages as u8,
+                flags,
+            ],
+            self.sequence.to_be_bytes(),
+            self.status.to_be_bytes(),
+            self.association_id.to_be_bytes(),
+            self.offset.to_be_bytes(),
+            count.to_be_bytes(),
+        ];
+        bytes
+            .extend_from_slice(header.as_flattened())
+            .map_err(|_| ClockError::InvalidResponse)?;
+        bytes
+            .extend_from_slice(&self.data)
+            .map_err(|_| ClockError::InvalidResponse)?;
+        while !bytes.len().is_multiple_of(4) {
+            bytes.push(0).map_err(|_| ClockError::InvalidResponse)?;
+        }
+        Ok(bytes)
+    }
+
+    /// Parse a control message, anything after the data (padding or a MAC) is ignored.
+    pub fn parse(bytes: &[u8]) -> Result<Self, ClockError> {
+        if bytes.len() < NTP_CONTROL_HEADER_LEN {
+            return Err(ClockError::PacketTooShort);
+        }
+        let word = |index: usize| u16::from_be_bytes([bytes[index], bytes[index + 1]]);
+        let mode = bytes[0] & 0x07;
+        if mode != NtpMode::ReservedForNtpControlMessages as u8 {
+            return Err(ClockError::ModeMismatch(
+                NtpMode::from_primitive(mode).unwrap_or(NtpMode::Reserved),
+            ));
+        }
+        let count = word(10) as usize;
+        let data = bytes
+            .get(NTP_CONTROL_HEADER_LEN..NTP_CONTROL_HEADER_LEN + count)
+            .ok_or(ClockError::PacketTooShort)?;
+        Ok(ControlMessage {
+            version: (bytes[0] >> 3) & 0x07,
+            response: bytes[1] & 0x80 != 0,
+            error: bytes[1] & 0x40 != 0,
+            more: bytes[1] & 0x20 != 0,
+            opcode: ControlOpcode::try_from(bytes[1] & 0x1f)?,
+            sequence: word(2),
+            status: word(4),
+            association_id: word(6),
+            offset: word(8),
+            data: heapless::Vec::from_slice(data).map_err(|_| ClockError::InvalidResponse)?,
+        })
+    }
+}
+
+/// A peer's status word from a READSTAT response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerStatus(pub u16);
+
+impl PeerStatus {
+    /// The selection code, how clock selection used the peer.
+    pub fn selection(&self) -> u8 {
+        ((self.0 >> 8) & 0x07) as u8
+    }
+
+    pub fn is_reachable(&self) -> bool {
+        self.0 & 0x1000 != 0
+    }
+
+    /// The `ntpq -p` tally code for [Self::selection].
+    pub fn tally(&self) -> char {
+        match self.selection() {
+            1 => 'x',
+            2 => '.',
+            3 => '-',
+            4 => '+',
+            5 => '#',
+            6 => '*',
+            7 => 'o',
+            _ => ' ',
+        }
+    }
+}
+
+/// The association IDs and peer status words from a READSTAT response's data.
+pub fn parse_peer_statuses(data: &[u8]) -> impl Iterator<Item = (u16, PeerStatus)> + '_ {
+    data.chunks_exact(4).map(|entry| {
+        (
+            u16::from_be_bytes([entry[0], entry[1]]),
+            PeerStatus(u16::from_be_bytes([entry[2], entry[3]])),
+        )
+    })
+}
+
+/// The `name=value` pairs from a READVAR response's data. Values may be quoted, and
+/// commas inside quotes don't split them.
+pub fn parse_control_variables(data: &str) -> impl Iterator<Item = (&str, &str)> {
+    let mut rest = data;
+    core::iter::from_fn(move || {
+        loop {
+            rest = rest.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+            if rest.is_empty() {
+                return None;
+            }
+            let mut quoted = false;
+            let end = rest
+                .char_indices()
+                .find(|(_, c)| {
+                    if *c == '"' {
+                        quoted = !quoted;
+                    }
+                    *c == ',' && !quoted
+                })
+                .map_or(rest.len(), |(index, _)| index);
+            let (item, remainder) = rest.split_at(end);
+            rest = remainder;
+            let (name, value) = item.split_once('=').unwrap_or((item, ""));
+            let name = name.trim();
+            if name.is_empty() {
+                continue;
+            }
+            return Some((name, value.trim().trim_matches('"')));
+        }
+    })
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -447,8 +981,8 @@ mod test {
         let packed_req = req.pack().expect("Should pack NtpPacket request");
         assert_eq!(
             packed_req.len(),
-            60,
-            "Packed NtpPacket request size mismatch, should be 60 bytes"
+            48,
+            "Packed NtpPacket request size mismatch, should be 48 bytes"
         );
 
         let response = NtpPacket::from_nanos(0);
@@ -471,13 +1005,22 @@ mod test {
     }
     #[test]
     fn test_root_delay() {
-        let test_bytes = [0x00, 0x00, 0x04, 0x78];
-        let root_delay = RootDelay::unpack(&test_bytes).expect("Should unpack RootDelay");
-        let delay_ms = root_delay.to_milliseconds();
+        let root_delay = NtpShort::from_be_bytes([0x00, 0x00, 0x04, 0x78]);
+        // 0x0478 / 65536 seconds
+        assert_eq!(root_delay.as_nanos(), 17_456_054);
+        assert_eq!(root_delay.as_signed_nanos(), 17_456_054);
+        assert_eq!(root_delay.to_duration(), Duration::from_nanos(17_456_054));
+
+        let negative = NtpShort::from_signed_nanos(-2_000_000);
+        assert_eq!(negative.to_be_bytes(), [0xff, 0xff, 0xff, 0x7d]);
+        assert_eq!(negative.as_signed_nanos(), -1_998_902);
+
         assert_eq!(
-            delay_ms, 0.017456055,
-            "RootDelay to_milliseconds calculation incorrect"
+            NtpShort::from_nanos(1_500_000_000).to_be_bytes(),
+            [0, 1, 0x80, 0]
         );
+        assert_eq!(NtpShort::from(Duration::from_secs(1 << 20)), NtpShort::MAX);
+        assert_eq!(NtpShort::from_nanos(15_000), NtpShort(1));
     }
     #[test]
     fn test_real_v3_packet() {
@@ -488,7 +1031,7 @@ mod test {
             0xf0,
         ];
 
-        let response = crate::parse_ntp_packet(&bytes, 0).expect("Should parse NTP response");
+        let response = crate::parse_ntp_packet(&bytes, None).expect("Should parse NTP response");
         assert_eq!(response.leap_indicator, 0, "Leap indicator should be 0");
         assert_eq!(response.version, 3, "NTP version should be 3");
         assert_eq!(
@@ -500,40 +1043,190 @@ mod test {
         assert_eq!(response.poll, 0, "Poll should be 0");
         assert_eq!(response.precision, -25, "Precision should be -25");
         assert_eq!(
-            response.root_delay_ms,
+            response.root_delay,
             [0x00, 0x00, 0x04, 0x78],
             "Root delay bytes should match packet"
         );
         assert_eq!(
-            response.dispersion,
+            response.root_dispersion,
             [0x00, 0x00, 0x00, 0x1a],
             "Dispersion bytes should match packet"
         );
+        assert_eq!(
+            response.root_delay_nanos(),
+            17_456_054,
+            "Root delay ~17.5ms"
+        );
+        assert_eq!(
+            response.root_dispersion_nanos(),
+            396_728,
+            "Root dispersion ~0.4ms"
+        );
         assert_eq!(
             response.identifier, 0x0a55_0830,
             "Identifier should match packet"
         );
         // Jan 25, 2026 03:23:16.443478400 UTC
         assert_eq!(
-            response.ref_time, 1_769_311_396_443_478_400,
+            response.ref_time.to_unix_nanos(),
+            1_769_311_396_443_478_400,
             "Local time should be Jan 25, 2026 03:23:16.443478400 UTC"
         );
-        assert_eq!(response.origin_time, 0, "Origin time should be 0");
         assert_eq!(
-            response.recv_time, 1_769_311_616_238_289_647,
+            response.origin_time,
+            NtpTimestamp::ZERO,
+            "Origin time should be 0"
+        );
+        assert_eq!(
+            response.recv_time.to_unix_nanos(),
+            1_769_311_616_238_289_647,
             "Receive time should match packet"
         );
         assert_eq!(
-            response.transmit_time, 1_769_311_616_238_368_805,
+            response.transmit_time.to_unix_nanos(),
+            1_769_311_616_238_368_805,
             "Transmit time should match packet"
         );
         assert_eq!(
-            response.offset_from_local(response.transmit_time),
+            response.offset_from_local(response.transmit_time.to_unix_nanos()),
             0,
             "Offset should be 0"
         );
     }
 
+    #[test]
+    fn test_timestamp_conversion() {
+        let unix_nanos = 1_769_311_396_443_478_400;
+        let timestamp = NtpTimestamp::from_unix_nanos(unix_nanos);
+        assert_eq!(timestamp.seconds(), 0xed20_0b24);
+        assert_eq!(
+            timestamp.to_unix_nanos(),
+            unix_nanos - 1,
+            "fraction truncates"
+        );
+        assert_eq!(NtpTimestamp::era_of_unix_nanos(unix_nanos), 0);
+        assert_eq!(NtpTimestamp::ZERO.to_unix_nanos(), 0);
+        assert_eq!(
+            NtpTimestamp(1 << 32).to_unix_nanos_in_era(0),
+            None,
+            "1900 is before UNIX time"
+        );
+
+        // the packet carries NTP timestamps, not UNIX nanoseconds
+        let packet = NtpPacket::from_nanos(unix_nanos);
+        let bytes = packet.as_bytes().expect("Should pack NtpPacket");
+        assert_eq!(bytes[40..44], [0xed, 0x20, 0x0b, 0x24]);
+        let parsed = crate::parse_ntp_packet(&bytes, None).expect("Should parse NTP packet");
+        assert_eq!(parsed.transmit_time, timestamp);
+    }
+
+    #[test]
+    fn test_era_rollover() {
+        // 2036-02-07 06:28:16 UTC, the first second of era 1
+        const ERA_1_START: u64 = 2_085_978_496_000_000_000;
+        let last_of_era_0 = NtpTimestamp::from_unix_nanos(ERA_1_START - 500_000_000);
+        assert_eq!(last_of_era_0, NtpTimestamp(0xffff_ffff_8000_0000));
+        assert_eq!(last_of_era_0.to_unix_nanos(), ERA_1_START - 500_000_000);
+
+        let first_of_era_1 = NtpTimestamp::from_unix_nanos(ERA_1_START + 500_000_000);
+        assert_eq!(first_of_era_1, NtpTimestamp(0x8000_0000));
+        assert_eq!(NtpTimestamp::era_of_unix_nanos(ERA_1_START), 1);
+        assert_eq!(first_of_era_1.to_unix_nanos(), ERA_1_START + 500_000_000);
+        assert_eq!(
+            first_of_era_1.to_unix_nanos_in_era(0),
+            None,
+            "era 0 would be 1900"
+        );
+
+        // past 2094 we need a more recent pivot, from a clock that's been on the wall since 2090
+        let in_2100 = 4_102_444_800_000_000_000;
+        let timestamp = NtpTimestamp::from_unix_nanos(in_2100);
+        assert_ne!(timestamp.to_unix_nanos(), in_2100);
+        assert_eq!(
+            timestamp.to_unix_nanos_near(3_786_912_000_000_000_000),
+            in_2100
+        );
+
+        // and a pivot in era 1 still reads the end of era 0
+        assert_eq!(
+            last_of_era_0.to_unix_nanos_near(ERA_1_START + 1_000_000_000_000),
+            ERA_1_START - 500_000_000
+        );
+    }
+
+    #[test]
+    fn test_usable() {
+        let packet = NtpPacket::from_nanos(1_769_311_396_443_478_400);
+        assert!(packet.is_usable(NtpMode::Server));
+        assert!(matches!(
+            packet.check_usable(NtpMode::Broadcast),
+            Err(ClockError::ModeMismatch(NtpMode::Server))
+        ));
+        assert!(matches!(
+            NtpPacket::request().check_usable(NtpMode::Server),
+            Err(ClockError::ModeMismatch(NtpMode::Client))
+        ));
+
+        let mut unsynchronized = packet.clone();
+        unsynchronized.leap_indicator = 3;
+        assert!(matches!(
+            unsynchronized.check_usable(NtpMode::Server),
+            Err(ClockError::Unsynchronized)
+        ));
+
+        for stratum in [0, 16, 255] {
+            let mut invalid = packet.clone();
+            invalid.stratum = stratum;
+            assert!(matches!(
+                invalid.check_usable(NtpMode::Server),
+                Err(ClockError::InvalidStratum(value)) if value == stratum
+            ));
+        }
+
+        let mut no_transmit = packet.clone();
+        no_transmit.transmit_time = NtpTimestamp::ZERO;
+        assert!(matches!(
+            no_transmit.check_usable(NtpMode::Server),
+            Err(ClockError::ZeroTransmitTime)
+        ));
+
+        let mut distant = packet.clone();
+        distant.set_root_delay(NtpShort::from_nanos(1_000_000_000));
+        distant.set_root_dispersion(NtpShort::from_nanos(600_000_000));
+        assert!(matches!(
+            distant.check_usable(NtpMode::Server),
+            Err(ClockError::ExcessiveRootDistance(_))
+        ));
+    }
+
+    #[test]
+    fn test_kiss_code() {
+        let mut packet = NtpPacket::from_nanos(0);
+        packet.stratum = 0;
+        packet.identifier = u32::from_be_bytes(*b"RATE");
+        assert_eq!(packet.kiss_code(), Some(KissCode::Rate));
+        assert_eq!(
+            packet.kiss_code().map(|code| code.action()),
+            Some(KissAction::ReducePolling)
+        );
+        packet.identifier = u32::from_be_bytes(*b"RSTR");
+        assert_eq!(
+            packet.kiss_code().map(|code| code.action()),
+            Some(KissAction::Demobilize)
+        );
+        packet.identifier = u32::from_be_bytes(*b"XYZZ");
+        assert_eq!(packet.kiss_code(), Some(KissCode::Unknown(*b"XYZZ")));
+        packet.identifier = 0;
+        assert_eq!(packet.kiss_code(), None, "unspecified stratum 0 is not KoD");
+        packet.stratum = 2;
+        packet.identifier = u32::from_be_bytes(*b"DENY");
+        assert_eq!(
+            packet.kiss_code(),
+            None,
+            "only stratum 0 carries kiss codes"
+        );
+    }
+
     #[test]
     fn test_real_v3_response_packet() {
         let bytes = [
@@ -543,7 +1236,7 @@ mod test {
             0x6b, 0x39, 0xf0,
         ];
 
-        let response = crate::parse_ntp_packet(&bytes, 0).expect("Should parse NTP response");
+        let response = crate::parse_ntp_packet(&bytes, None).expect("Should parse NTP response");
         assert_eq!(response.leap_indicator, 0, "Leap indicator should be 0");
         assert_eq!(response.version, 3, "NTP version should be 3");
         assert_eq!(
@@ -555,32 +1248,114 @@ mod test {
         assert_eq!(response.poll, 0, "Poll should be 0");
         assert_eq!(response.precision, -25, "Precision should be -25");
         assert_eq!(
-            response.root_delay_ms,
+            response.root_delay,
             [0x00, 0x00, 0x17, 0x74],
             "Root delay bytes should match packet"
         );
         assert_eq!(
-            response.dispersion,
+            response.root_dispersion,
             [0x00, 0x00, 0x03, 0x5b],
             "Dispersion bytes should match packet"
         );
+        assert_eq!(
+            response.root_delay_nanos(),
+            91_613_769,
+            "Root delay ~91.6ms"
+        );
+        assert_eq!(
+            response.root_dispersion_nanos(),
+            13_107_299,
+            "Root dispersion ~13.1ms"
+        );
         assert_eq!(
             response.identifier, 0x34_94_72_bc,
             "Identifier should match packet"
         );
         // Jan 25, 2026 03:55:12.374138162 UTC
         assert_eq!(
-            response.ref_time, 1_769_313_312_374_138_162,
+            response.ref_time.to_unix_nanos(),
+            1_769_313_312_374_138_162,
             "Local time should be Jan 25, 2026 03:55:12.374138162 UTC"
         );
-        assert_eq!(response.origin_time, 0, "Origin time should be 0");
         assert_eq!(
-            response.recv_time, 1_769_314_093_466_444_980,
+            response.origin_time,
+            NtpTimestamp::ZERO,
+            "Origin time should be 0"
+        );
+        assert_eq!(
+            response.recv_time.to_unix_nanos(),
+            1_769_314_093_466_444_980,
             "Receive time should match packet"
         );
         assert_eq!(
-            response.transmit_time, 1_769_314_093_466_479_893,
+            response.transmit_time.to_unix_nanos(),
+            1_769_314_093_466_479_893,
             "Transmit time should match packet"
         );
     }
+
+    #[test]
+    fn test_control_message_round_trip() {
+        let request = ControlMessage::request(ControlOpcode::ReadStatus, 0x1234, 0);
+        let bytes = request.to_bytes().expect("should encode");
+        assert_eq!(
+            bytes.as_slice(),
+            &[0x16, 0x01, 0x12, 0x34, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            ControlMessage::parse(&bytes).expect("should parse"),
+            request
+        );
+
+        // ntpd's READSTAT response for two associations, the system peer and a candidate
+        let response = [
+            0x16, 0x81, 0x12, 0x34, 0x06, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0xa1, 0x2b,
+            0x96, 0x1a, 0xa1, 0x2c, 0x94, 0x14,
+        ];
+        let response = ControlMessage::parse(&response).expect("should parse");
+        assert!(response.response && !response.error && !response.more);
+        assert_eq!(response.opcode, ControlOpcode::ReadStatus);
+        let peers: Vec<_> = parse_peer_statuses(&response.data).collect();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].0, 0xa12b);
+        assert_eq!(peers[0].1.tally(), '*');
+        assert_eq!(peers[1].1.tally(), '+');
+        assert!(peers[1].1.is_reachable());
+
+        assert!(matches!(
+            ControlMessage::parse(&response.to_bytes().expect("should encode")[..15]),
+            Err(ClockError::PacketTooShort)
+        ));
+        assert!(matches!(
+            ControlMessage::parse(&[0x23; 12]),
+            Err(ClockError::ModeMismatch(NtpMode::Client))
+        ));
+    }
+
+    #[test]
+    fn test_parse_control_variables() {
+        let variables: Vec<_> = parse_control_variables(
+            "version=\"ntpd 4.2.8p15, built\", stratum=2,\r\nrefid=192.0.2.1, leap",
+        )
+        .collect();
+        assert_eq!(
+            variables,
+            [
+                ("version", "ntpd 4.2.8p15, built"),
+                ("stratum", "2"),
+                ("refid", "192.0.2.1"),
+                ("leap", ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ipv6_reference_id() {
+        // the hash of :: is what we'd send for an IPv6 upstream
+        #[cfg(feature = "std")]
+        assert_eq!(
+            NtpIdentifier::IpAddr(IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED)).as_u32(),
+            0x4ae7_1336
+        );
+    }
 }
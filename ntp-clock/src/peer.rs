@@ -0,0 +1,276 @@
+//! Symmetric active and passive peers, from
+//! [RFC 5905 Section 9](https://www.rfc-editor.org/rfc/rfc5905#section-9), so two clocks
+//! can back each other up.
+//!
+//! Unlike client mode, both sides send on their own schedule. Each packet carries the
+//! transmit time of the last packet we got from the peer as its origin, and the time we got
+//! it as its receive time, so every packet the peer sends back completes an exchange.
+
+use core::net::SocketAddr;
+
+use crate::auth::KeyStore;
+use crate::constants::{NTP_ERA_PIVOT_UNIX_NANOS, NTP_MAX_PACKET_LEN};
+use crate::error::ClockError;
+use crate::filter::ClockFilter;
+use crate::message::NtpMessage;
+use crate::packets::{NtpMode, NtpPacket, NtpTimestamp};
+use crate::poll::PollInterval;
+use crate::sample::SyncSample;
+use crate::server::NtpServer;
+use crate::unpack_ntp_packet;
+
+/// One symmetric association, active if we started it or passive if the peer did.
+#[derive(Clone, Debug)]
+pub struct SymmetricPeer {
+    pub address: SocketAddr,
+    /// [NtpMode::SymmetricActive] or [NtpMode::SymmetricPassive], the mode we send
+    pub mode: NtpMode,
+    /// How often to send to the peer
+    pub poll: PollInterval,
+    pub last_response: Option<NtpPacket>,
+    /// Offset and delay calculated from the last exchange
+    pub last_sample: Option<SyncSample>,
+    /// The recent samples from this peer
+    pub filter: ClockFilter,
+    /// Transmit time of the last packet from the peer, sent back as our origin (RFC 5905 org)
+    their_transmit: NtpTimestamp,
+    /// Local time the last packet from the peer arrived, UNIX nanoseconds (RFC 5905 rec)
+    our_receive: u64,
+    /// Transmit time of the last packet we sent, UNIX nanoseconds, the peer must echo it
+    /// (RFC 5905 xmt)
+    our_transmit: u64,
+    /// Keys we trust, packets must be signed with one of them when `key_id` is set
+    keys: KeyStore,
+    /// The key we sign packets with
+    key_id: Option<u32>,
+}
+
+impl SymmetricPeer {
+    /// Start an association with `address`, which we'll send to first.
+    pub fn active(address: SocketAddr) -> Self {
+        Self::new(address, NtpMode::SymmetricActive)
+    }
+
+    /// Answer an association `address` started, see [Self::accepts].
+    pub fn passive(address: SocketAddr) -> Self {
+        Self::new(address, NtpMode::SymmetricPassive)
+    }
+
+    fn new(address: SocketAddr, mode: NtpMode) -> Self {
+        SymmetricPeer {
+            address,
+            mode,
+            poll: PollInterval::new(),
+            last_response: None,
+            last_sample: None,
+            filter: ClockFilter::new(),
+            their_transmit: NtpTimestamp::ZERO,
+            our_receive: 0,
+            our_transmit: 0,
+            keys: KeyStore::new(),
+            key_id: None,
+        }
+    }
+
+    /// Sign packets with `key_id` from `keys`, and only accept packets signed with a key from
+    /// `keys`.
+    pub fn with_authentication(mut self, key_id: u32, keys: KeyStore) -> Result<Self, ClockError> {
+        if keys.get(key_id).is_none() {
+            return Err(ClockError::UnknownKey(key_id));
+        }
+        self.keys = keys;
+        self.key_id = Some(key_id);
+        Ok(self)
+    }
+
+    /// Is `packet` from a peer starting a new association with us? If so, answer it with a
+    /// [Self::passive] peer.
+    pub fn accepts(packet: &[u8]) -> bool {
+        unpack_ntp_packet(packet).is_ok_and(|packet| packet.mode() == NtpMode::SymmetricActive)
+    }
+
+    /// Should we process a packet in `mode` from the peer? Following the dispatch table in
+    /// [RFC 5905 Section 9.2](https://www.rfc-editor.org/rfc/rfc5905#section-9.2), an active
+    /// association takes either symmetric mode, so two peers which both configured each
+    /// other work, while passive only answers active.
+    pub fn accepts_mode(&self, mode: NtpMode) -> bool {
+        match self.mode {
+            NtpMode::SymmetricActive => {
+                matches!(mode, NtpMode::SymmetricActive | NtpMode::SymmetricPassive)
+            }
+            _ => mode == NtpMode::SymmetricActive,
+        }
+    }
+
+    /// Build the packet to send the peer at `transmit_time` (UNIX nanoseconds), describing our
+    /// clock with `system`.
+    pub fn build_packet(
+        &mut self,
+        system: &NtpServer,
+        transmit_time: u64,
+    ) -> Result<heapless::Vec<u8, NTP_MAX_PACKET_LEN>, ClockError> {
+        let mut header = system.packet(transmit_time);
+        header.set_mode(self.mode);
+        header.poll = self.poll.exponent();
+        header.origin_time = self.their_transmit;
+        header.recv_time = if self.our_receive == 0 {
+            NtpTimestamp::ZERO
+        } else {
+            NtpTimestamp::from_unix_nanos(self.our_receive)
+        };
+        let mut message = NtpMessage::new(header);
+        if let Some(key_id) = self.key_id {
+            self.keys.sign(key_id, &mut message)?;
+        }
+        let packet = message.to_bytes()?;
+        self.our_transmit = transmit_time;
+        Ok(packet)
+    }
+
+    /// Handle a packet from the peer which arrived at `local_time` (T4).
+    ///
+    /// The origin and receive bookkeeping is updated for anything that isn't a duplicate,
+    /// even if it can't be used for a sample: the first packet of an association doesn't
+    /// echo anything ([ClockError::OriginMismatch]), and an unsynchronized peer fails
+    /// [NtpPacket::check_usable]. Both still let the next packet we send complete an exchange.
+    pub fn receive(&mut self, packet: &[u8], local_time: u64) -> Result<SyncSample, ClockError> {
+        let response = unpack_ntp_packet(packet)?;
+        if !self.accepts_mode(response.mode()) {
+            return Err(ClockError::ModeMismatch(response.mode()));
+        }
+        if self.key_id.is_some() {
+            self.keys.verify(&NtpMessage::parse(packet)?)?;
+        }
+        if response.transmit_time.is_zero() {
+            return Err(ClockError::ZeroTransmitTime);
+        }
+        if response.transmit_time == self.their_transmit {
+            return Err(ClockError::DuplicatePacket);
+        }
+
+        let origin = self.our_transmit;
+        let bogus = origin == 0 || response.origin_time != NtpTimestamp::from_unix_nanos(origin);
+        self.their_transmit = response.transmit_time;
+        self.our_receive = local_time;
+        if bogus {
+            return Err(ClockError::OriginMismatch);
+        }
+        // the peer answered what we sent, a second copy of it would be a duplicate
+        self.our_transmit = 0;
+        response.check_usable(response.mode())?;
+
+        let pivot = self
+            .last_sample
+            .map(|sample| sample.server_time())
+            .unwrap_or(0)
+            .max(NTP_ERA_PIVOT_UNIX_NANOS);
+        let sample = SyncSample::from_packet_near(&response, origin, local_time, pivot);
+        self.poll.set_server_exponent(response.poll);
+        self.last_response = Some(response);
+        self.last_sample = Some(sample);
+        let estimate = self.filter.add(sample);
+        self.poll.update(estimate.offset, estimate.jitter);
+        Ok(sample)
+    }
+
+    /// Send our next packet to the peer from `socket`, stamped with `transmit_time`.
+    #[cfg(feature = "std")]
+    pub fn send(
+        &mut self,
+        socket: &std::net::UdpSocket,
+        system: &NtpServer,
+        transmit_time: u64,
+    ) -> Result<(), ClockError> {
+        let packet = self.build_packet(system, transmit_time)?;
+        socket
+            .send_to(&packet, self.address)
+            .map_err(|_| ClockError::NetworkError)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_769_311_396_443_478_400;
+
+    fn synchronized() -> NtpServer {
+        let upstream = NtpPacket::from_nanos(NOW);
+        let sample = SyncSample::from_packet(&upstream, NOW - 1_000_000, NOW + 1_000_000);
+        let mut system = NtpServer::new();
+        system.update_from_upstream(
+            &upstream,
+            &ClockFilter::new().add(sample),
+            "192.0.2.1".parse().expect("address"),
+        );
+        system
+    }
+
+    #[test]
+    fn exchange_completes_on_the_second_packet() {
+        let system = synchronized();
+        let mut active = SymmetricPeer::active("192.0.2.2:123".parse().expect("address"));
+        let mut passive = SymmetricPeer::passive("192.0.2.3:123".parse().expect("address"));
+
+        let first = active.build_packet(&system, NOW).expect("should build");
+        assert!(SymmetricPeer::accepts(&first));
+        // nothing to echo yet
+        assert!(matches!(
+            passive.receive(&first, NOW + 5_000_000),
+            Err(ClockError::OriginMismatch)
+        ));
+        assert!(matches!(
+            passive.receive(&first, NOW + 6_000_000),
+            Err(ClockError::DuplicatePacket)
+        ));
+
+        let reply = passive
+            .build_packet(&system, NOW + 7_000_000)
+            .expect("should build");
+        assert!(!SymmetricPeer::accepts(&reply));
+        let sample = active
+            .receive(&reply, NOW + 12_000_000)
+            .expect("reply completes the exchange");
+        // give or take the nanosecond lost to the NTP timestamp format
+        assert_eq!(sample.t1, NOW);
+        assert!(sample.t2.abs_diff(NOW + 5_000_000) <= 1);
+        assert!(sample.delay.abs_diff(10_000_000) <= 2);
+        assert!(sample.offset.abs() <= 2);
+        // a replay doesn't echo our latest transmit any more
+        assert!(active.receive(&reply, NOW + 13_000_000).is_err());
+    }
+
+    #[test]
+    fn active_peers_exchange_with_each_other() {
+        let system = synchronized();
+        let mut a = SymmetricPeer::active("192.0.2.3:123".parse().expect("address"));
+        let mut b = SymmetricPeer::active("192.0.2.2:123".parse().expect("address"));
+
+        let first = a.build_packet(&system, NOW).expect("should build");
+        assert!(matches!(
+            b.receive(&first, NOW + 5_000_000),
+            Err(ClockError::OriginMismatch)
+        ));
+        let reply = b
+            .build_packet(&system, NOW + 7_000_000)
+            .expect("should build");
+        let sample = a
+            .receive(&reply, NOW + 12_000_000)
+            .expect("an active peer's reply completes the exchange");
+        assert!(sample.delay.abs_diff(10_000_000) <= 2);
+        assert!(sample.offset.abs() <= 2);
+    }
+
+    #[test]
+    fn passive_peers_ignore_each_other() {
+        let system = synchronized();
+        let mut passive = SymmetricPeer::passive("192.0.2.2:123".parse().expect("address"));
+        let mut other = SymmetricPeer::passive("192.0.2.3:123".parse().expect("address"));
+        let packet = other.build_packet(&system, NOW).expect("should build");
+        assert!(matches!(
+            passive.receive(&packet, NOW),
+            Err(ClockError::ModeMismatch(NtpMode::SymmetricPassive))
+        ));
+    }
+}
@@ -0,0 +1,190 @@
+//! Adaptive poll interval, loosely following the poll adjustment in
+//! [RFC 5905 Section 11.3](https://www.rfc-editor.org/rfc/rfc5905#section-11.3). We poll
+//! less often while the offsets are stable, which keeps the load off our servers, and more
+//! often when they start to move around.
+
+use core::time::Duration;
+
+use crate::constants::{NTP_MAX_POLL_EXPONENT, NTP_MIN_POLL_EXPONENT, NTP_RETRY_BACKOFF_MILLIS};
+use crate::sample::precision_to_nanos;
+
+/// Default upper limit, 2^10 = 1024 seconds
+pub const DEFAULT_MAX_POLL_EXPONENT: i8 = 10;
+/// Offsets are stable if they move by less than this many times the jitter (PGATE)
+const POLL_GATE: u64 = 4;
+/// How far the counter has to move before the interval changes (LIMIT)
+const POLL_LIMIT: i32 = 30;
+/// Jitter floor, so very quiet networks still let the interval grow (~1ms)
+const MIN_JITTER_PRECISION: i8 = -10;
+
+/// How long to wait before retry number `attempt` (from 1) of a request that wasn't answered.
+/// The backoff doubles each time, plus up to as much again picked from `entropy` (any
+/// fast-changing value, like the low bits of a clock), so clients which lost packets at the
+/// same time don't retry together.
+pub fn retry_delay(attempt: u32, entropy: u64) -> Duration {
+    let backoff = NTP_RETRY_BACKOFF_MILLIS << attempt.saturating_sub(1).min(8);
+    Duration::from_millis(backoff + entropy % backoff)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PollInterval {
+    exponent: i8,
+    min_exponent: i8,
+    max_exponent: i8,
+    /// Poll exponent the server last advertised
+    server_exponent: i8,
+    /// Hysteresis, counts up while stable and down while not
+    counter: i32,
+    last_offset: Option<i64>,
+}
+
+impl Default for PollInterval {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PollInterval {
+    /// Start at 64 seconds and allow growing up to 1024 seconds.
+    pub const fn new() -> Self {
+        Self::with_limits(NTP_MIN_POLL_EXPONENT, DEFAULT_MAX_POLL_EXPONENT)
+    }
+
+    /// Poll between 2^`min_exponent` and 2^`max_exponent` seconds, starting at the minimum.
+    pub const fn with_limits(min_exponent: i8, max_exponent: i8) -> Self {
+        let max_exponent = if max_exponent > NTP_MAX_POLL_EXPONENT {
+            NTP_MAX_POLL_EXPONENT
+        } else {
+            max_exponent
+        };
+        let min_exponent = if min_exponent > max_exponent {
+            max_exponent
+        } else {
+            min_exponent
+        };
+        PollInterval {
+            exponent: min_exponent,
+            min_exponent,
+            max_exponent,
+            server_exponent: 0,
+            counter: 0,
+            last_offset: None,
+        }
+    }
+
+    /// The poll exponent to use, never more often than the server has asked for.
+    pub fn exponent(&self) -> i8 {
+        self.exponent
+            .max(self.server_exponent)
+            .clamp(0, NTP_MAX_POLL_EXPONENT)
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(1u64 << self.exponent())
+    }
+
+    /// Record the poll exponent from a server's response.
+    pub fn set_server_exponent(&mut self, exponent: i8) {
+        self.server_exponent = exponent;
+    }
+
+    /// Poll less often from now on, for when the server tells us we're polling too fast.
+    pub fn back_off(&mut self) {
+        self.min_exponent = (self.min_exponent + 1).min(NTP_MAX_POLL_EXPONENT);
+        self.max_exponent = self.max_exponent.max(self.min_exponent);
+        self.exponent = self.exponent.max(self.min_exponent);
+        self.counter = 0;
+    }
+
+    /// Update with the latest offset and jitter (nanoseconds), returns the new exponent.
+    pub fn update(&mut self, offset: i64, jitter: u64) -> i8 {
+        let change = self
+            .last_offset
+            .map(|last| (offset as i128 - last as i128).unsigned_abs())
+            .unwrap_or(0);
+        self.last_offset = Some(offset);
+        let jitter = jitter.max(precision_to_nanos(MIN_JITTER_PRECISION)) as u128;
+
+        if change < POLL_GATE as u128 * jitter {
+            self.counter += self.exponent as i32;
+            if self.counter > POLL_LIMIT {
+                self.counter = POLL_LIMIT;
+                if self.exponent < self.max_exponent {
+                    self.counter = 0;
+                    self.exponent += 1;
+                }
+            }
+        } else {
+            self.counter -= 2 * self.exponent as i32;
+            if self.counter < -POLL_LIMIT {
+                self.counter = -POLL_LIMIT;
+                if self.exponent > self.min_exponent {
+                    self.counter = 0;
+                    self.exponent -= 1;
+                }
+            }
+        }
+        self.exponent()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grows_while_stable() {
+        let mut poll = PollInterval::new();
+        assert_eq!(poll.interval(), Duration::from_secs(64));
+        for _ in 0..100 {
+            poll.update(100_000, 50_000);
+        }
+        assert_eq!(poll.exponent(), DEFAULT_MAX_POLL_EXPONENT);
+        assert_eq!(poll.interval(), Duration::from_secs(1024));
+    }
+
+    #[test]
+    fn shrinks_on_jitter() {
+        let mut poll = PollInterval::new();
+        for _ in 0..100 {
+            poll.update(0, 0);
+        }
+        assert_eq!(poll.exponent(), DEFAULT_MAX_POLL_EXPONENT);
+        for step in 0..20 {
+            let offset = if step % 2 == 0 {
+                50_000_000
+            } else {
+                -50_000_000
+            };
+            poll.update(offset, 1_000_000);
+        }
+        assert_eq!(poll.exponent(), NTP_MIN_POLL_EXPONENT);
+    }
+
+    #[test]
+    fn respects_server_and_back_off() {
+        let mut poll = PollInterval::new();
+        poll.set_server_exponent(8);
+        assert_eq!(poll.interval(), Duration::from_secs(256));
+        poll.set_server_exponent(0);
+        assert_eq!(poll.exponent(), NTP_MIN_POLL_EXPONENT);
+
+        let mut poll = PollInterval::with_limits(6, 6);
+        poll.back_off();
+        assert_eq!(poll.exponent(), 7);
+        for _ in 0..10 {
+            poll.back_off();
+        }
+        assert_eq!(poll.exponent(), NTP_MAX_POLL_EXPONENT);
+    }
+
+    #[test]
+    fn retry_delay_doubles_with_jitter() {
+        assert_eq!(retry_delay(1, 0), Duration::from_millis(250));
+        assert_eq!(retry_delay(2, 0), Duration::from_millis(500));
+        assert_eq!(retry_delay(3, 499), Duration::from_millis(1_499));
+        // the jitter never takes it past twice the backoff
+        assert_eq!(retry_delay(3, 1_000), Duration::from_millis(1_000));
+        assert_eq!(retry_delay(100, 0), retry_delay(9, 0));
+    }
+}
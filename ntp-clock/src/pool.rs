@@ -0,0 +1,193 @@
+//! Query several servers and use clock selection to decide which of them to believe.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::auth::KeyStore;
+use crate::constants::NTP_MAX_SELECTION_CANDIDATES;
+use crate::prelude::*;
+use crate::resolve_servers;
+use crate::selection::{Candidate, CandidateList, select};
+
+pub struct NtpPool {
+    peers: Vec<NtpClient>,
+}
+
+/// The result of polling every peer in the pool, all values are indexes into [NtpPool::peers].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PoolSelection {
+    /// Combined offset of the survivors, nanoseconds
+    pub offset: i64,
+    /// The best peer
+    pub system_peer: usize,
+    /// Peers which survived selection and clustering, best first
+    pub survivors: CandidateList,
+    /// Peers which disagreed with the majority
+    pub falsetickers: CandidateList,
+    /// Peers which didn't give us a usable sample this time
+    pub unreachable: CandidateList,
+}
+
+impl NtpPool {
+    /// Create a pool from a list of servers, every address each of them resolves to becomes a peer.
+    pub fn new(servers: &[&str]) -> Result<Self, ClockError> {
+        let mut addresses: Vec<SocketAddr> = Vec::new();
+        for server in servers {
+            for address in resolve_servers(server)? {
+                if !addresses.contains(&address) {
+                    addresses.push(address);
+                }
+            }
+        }
+        Self::with_addresses(&addresses)
+    }
+
+    pub fn with_addresses(addresses: &[SocketAddr]) -> Result<Self, ClockError> {
+        if addresses.is_empty() {
+            return Err(ClockError::ConfigError(
+                "No NTP servers configured".to_string(),
+            ));
+        }
+        if addresses.len() > NTP_MAX_SELECTION_CANDIDATES {
+            return Err(ClockError::ConfigError(format!(
+                "Too many NTP servers, at most {} are supported",
+                NTP_MAX_SELECTION_CANDIDATES
+            )));
+        }
+        Ok(NtpPool {
+            peers: addresses
+                .iter()
+                .map(|address| NtpClient::with_address(*address))
+                .collect(),
+        })
+    }
+
+    /// Authenticate every peer with `key_id` from `keys`, see [NtpClient::with_authentication].
+    pub fn with_authentication(self, key_id: u32, keys: &KeyStore) -> Result<Self, ClockError> {
+        Ok(NtpPool {
+            peers: self
+                .peers
+                .into_iter()
+                .map(|peer| peer.with_authentication(key_id, keys.clone()))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Wait up to `timeout` for each response, and ask each peer up to `retries` more times
+    /// when it doesn't answer, see [NtpClient::with_timeout] and [NtpClient::with_retries].
+    pub fn with_retry_policy(self, timeout: Duration, retries: u32) -> Self {
+        NtpPool {
+            peers: self
+                .peers
+                .into_iter()
+                .map(|peer| peer.with_timeout(timeout).with_retries(retries))
+                .collect(),
+        }
+    }
+
+    pub fn peers(&self) -> &[NtpClient] {
+        &self.peers
+    }
+
+    /// Query every peer then run selection over the ones which answered.
+    pub fn update(&mut self) -> Result<PoolSelection, ClockError> {
+        for peer in self.peers.iter_mut() {
+            if let Err(err) = peer.update() {
+                warn!("Failed to update from {}: {}", peer.server, err);
+                peer.last_sample = None;
+            }
+        }
+        self.select()
+    }
+
+    /// Query every peer at once then run selection over the ones which answered, see
+    /// [NtpPool::update].
+    #[cfg(feature = "tokio")]
+    pub async fn update_async(&mut self) -> Result<PoolSelection, ClockError> {
+        use core::future::{Future, poll_fn};
+        use core::pin::Pin;
+        use core::task::Poll;
+
+        type Update<'a> = Pin<Box<dyn Future<Output = Result<SyncSample, ClockError>> + 'a>>;
+
+        let mut results: Vec<Option<Result<SyncSample, ClockError>>> =
+            self.peers.iter().map(|_| None).collect();
+        {
+            let mut updates: Vec<Update<'_>> = self
+                .peers
+                .iter_mut()
+                .map(|peer| Box::pin(peer.update_async()) as Update<'_>)
+                .collect();
+            // poll them all on this task, so they can borrow the peers
+            poll_fn(|cx| {
+                for (update, result) in updates.iter_mut().zip(results.iter_mut()) {
+                    if result.is_none()
+                        && let Poll::Ready(finished) = update.as_mut().poll(cx)
+                    {
+                        *result = Some(finished);
+                    }
+                }
+                if results.iter().all(Option::is_some) {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            })
+            .await;
+        }
+        for (peer, result) in self.peers.iter_mut().zip(results) {
+            if let Some(Err(err)) = result {
+                warn!("Failed to update from {}: {}", peer.server, err);
+                peer.last_sample = None;
+            }
+        }
+        self.select()
+    }
+
+    /// Run selection over the clock filter estimates of the peers which answered last time.
+    pub fn select(&self) -> Result<PoolSelection, ClockError> {
+        let mut candidates: Vec<Candidate> = Vec::new();
+        let mut candidate_peers = CandidateList::new();
+        let mut unreachable = CandidateList::new();
+        for (index, peer) in self.peers.iter().enumerate() {
+            let stratum = peer.last_response.as_ref().map(|response| response.stratum);
+            // we've capped the number of peers, so these can't overflow
+            let estimate = peer
+                .last_sample
+                .as_ref()
+                .and_then(|sample| peer.filter.estimate(sample.t4));
+            match (estimate, stratum) {
+                (Some(estimate), Some(stratum)) => {
+                    candidates.push(Candidate::from_estimate(&estimate, stratum));
+                    let _ = candidate_peers.push(index);
+                }
+                _ => {
+                    let _ = unreachable.push(index);
+                }
+            }
+        }
+
+        let selection = select(&candidates)?;
+        let to_peers = |list: &CandidateList| -> CandidateList {
+            list.iter()
+                .filter_map(|index| candidate_peers.get(*index).copied())
+                .collect()
+        };
+        let result = PoolSelection {
+            offset: selection.offset,
+            system_peer: candidate_peers
+                .get(selection.system_peer)
+                .copied()
+                .ok_or(ClockError::NoTimeAvailable)?,
+            survivors: to_peers(&selection.survivors),
+            falsetickers: to_peers(&selection.falsetickers),
+            unreachable,
+        };
+        for index in result.falsetickers.iter() {
+            if let Some(peer) = self.peers.get(*index) {
+                warn!("{} is a falseticker", peer.server);
+            }
+        }
+        Ok(result)
+    }
+}
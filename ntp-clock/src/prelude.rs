@@ -1,7 +1,8 @@
 pub use crate::error::ClockError;
-pub use crate::{NTP_UNIX_EPOCH, parse_ntp_packet};
+pub use crate::sample::SyncSample;
 #[cfg(feature = "std")]
-pub use crate::{NtpClient, unix_nanos_now};
+pub use crate::unix_nanos_now;
+pub use crate::{NTP_UNIX_EPOCH, NtpClient, parse_ntp_packet, parse_ntp_response};
 
 pub use log::*;
 pub type UnixTimestampNanos = u64;
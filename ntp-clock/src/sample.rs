@@ -0,0 +1,170 @@
+//! Offset and delay calculation for a single client/server exchange, using the
+//! on-wire formulas from [RFC 5905 Section 8](https://www.rfc-editor.org/rfc/rfc5905#section-8).
+
+use crate::constants::{
+    NTP_ERA_PIVOT_UNIX_NANOS, NTP_LOCAL_PRECISION, NTP_MAX_DISPERSION_NANOS, NTP_PHI_PPM,
+};
+use crate::packets::NtpPacket;
+
+/// The result of one request/response exchange with a server.
+///
+/// All timestamps are UNIX nanoseconds, offsets and delays are nanoseconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SyncSample {
+    /// T1: local time the request left the client (origin timestamp)
+    pub t1: u64,
+    /// T2: server time the request arrived (receive timestamp)
+    pub t2: u64,
+    /// T3: server time the response left (transmit timestamp)
+    pub t3: u64,
+    /// T4: local time the response arrived (destination timestamp)
+    pub t4: u64,
+    /// theta: `((T2 - T1) + (T3 - T4)) / 2`, positive when the local clock is behind the server
+    pub offset: i64,
+    /// delta: `(T4 - T1) - (T3 - T2)`, never less than the local clock precision
+    pub delay: i64,
+    /// epsilon: precision of both clocks plus the frequency tolerance accumulated over the exchange
+    pub dispersion: u64,
+    /// Server's root delay (delay to its primary reference), negative values read as zero
+    pub root_delay: u64,
+    /// Server's root dispersion (maximum error relative to its primary reference)
+    pub root_dispersion: u64,
+}
+
+impl SyncSample {
+    /// Build a sample from the four timestamps and the server's advertised precision.
+    pub fn new(t1: u64, t2: u64, t3: u64, t4: u64, server_precision: i8) -> Self {
+        let (t1_i, t2_i, t3_i, t4_i) = (t1 as i128, t2 as i128, t3 as i128, t4 as i128);
+
+        let offset = ((t2_i - t1_i) + (t3_i - t4_i)) / 2;
+        let delay = (t4_i - t1_i) - (t3_i - t2_i);
+        let delay = delay.max(precision_to_nanos(NTP_LOCAL_PRECISION) as i128);
+
+        let elapsed = (t4_i - t1_i).max(0) as u128;
+        let dispersion = precision_to_nanos(server_precision) as u128
+            + precision_to_nanos(NTP_LOCAL_PRECISION) as u128
+            + (elapsed * NTP_PHI_PPM as u128) / 1_000_000;
+
+        SyncSample {
+            t1,
+            t2,
+            t3,
+            t4,
+            offset: clamp_i64(offset),
+            delay: clamp_i64(delay),
+            dispersion: (dispersion as u64).min(NTP_MAX_DISPERSION_NANOS),
+            root_delay: 0,
+            root_dispersion: 0,
+        }
+    }
+
+    /// Build a sample from a parsed server response, the origin time we sent and the
+    /// local time the response arrived.
+    pub fn from_packet(packet: &NtpPacket, origin: u64, destination: u64) -> Self {
+        Self::from_packet_near(packet, origin, destination, NTP_ERA_PIVOT_UNIX_NANOS)
+    }
+
+    /// As [Self::from_packet], decoding the server's timestamps in the era nearest `pivot`
+    /// (UNIX nanoseconds), usually the last server time we trusted.
+    pub fn from_packet_near(packet: &NtpPacket, origin: u64, destination: u64, pivot: u64) -> Self {
+        Self {
+            root_delay: packet.root_delay_nanos().max(0) as u64,
+            root_dispersion: packet.root_dispersion_nanos(),
+            ..Self::new(
+                origin,
+                packet.recv_time.to_unix_nanos_near(pivot),
+                packet.transmit_time.to_unix_nanos_near(pivot),
+                destination,
+                packet.precision,
+            )
+        }
+    }
+
+    /// Maximum error of the offset relative to the primary reference (root distance), half
+    /// the total round-trip delay plus the total dispersion.
+    pub fn distance(&self) -> u64 {
+        root_distance(self.delay, self.dispersion, self)
+    }
+
+    /// The server's time at the moment the response arrived, in UNIX nanoseconds.
+    pub fn server_time(&self) -> u64 {
+        (self.t4 as i128 + self.offset as i128).max(0) as u64
+    }
+}
+
+/// Convert a log2 seconds precision (as found in the packet precision field) to nanoseconds.
+pub fn precision_to_nanos(precision: i8) -> u64 {
+    if precision >= 0 {
+        1_000_000_000u64.saturating_mul(1u64 << precision.min(32))
+    } else {
+        1_000_000_000u64 >> (-(precision as i16)).min(63)
+    }
+}
+
+/// `(delay + root delay) / 2 + dispersion + root dispersion`, with the root values from `sample`.
+pub(crate) fn root_distance(delay: i64, dispersion: u64, sample: &SyncSample) -> u64 {
+    let delay = (delay.max(0) as u64).saturating_add(sample.root_delay);
+    (delay / 2)
+        .saturating_add(dispersion)
+        .saturating_add(sample.root_dispersion)
+}
+
+fn clamp_i64(value: i128) -> i64 {
+    value.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::NtpShort;
+
+    #[test]
+    fn symmetric_path_offset_and_delay() {
+        // local clock is 1s behind the server, 20ms each way, 1ms server processing
+        let t1 = 1_000_000_000_000;
+        let t2 = t1 + 1_000_000_000 + 20_000_000;
+        let t3 = t2 + 1_000_000;
+        let t4 = t1 + 41_000_000;
+        let sample = SyncSample::new(t1, t2, t3, t4, -20);
+        assert_eq!(sample.offset, 1_000_000_000);
+        assert_eq!(sample.delay, 40_000_000);
+        assert_eq!(sample.server_time(), t4 + 1_000_000_000);
+        // two clocks at ~1us precision, plus 15PPM of 41ms
+        assert_eq!(sample.dispersion, 953 + 953 + 615);
+    }
+
+    #[test]
+    fn delay_is_clamped_to_precision() {
+        let sample = SyncSample::new(10, 10, 10, 10, -20);
+        assert_eq!(sample.offset, 0);
+        assert_eq!(sample.delay, precision_to_nanos(NTP_LOCAL_PRECISION) as i64);
+    }
+
+    #[test]
+    fn root_distance_from_packet() {
+        let mut packet = NtpPacket::from_nanos(1_000_000_000_000);
+        packet.set_root_delay(NtpShort::from_nanos(30_000_000));
+        packet.set_root_dispersion(NtpShort::from_nanos(5_000_000));
+        let sample = SyncSample::from_packet(&packet, 999_990_000_000, 1_000_010_000_000);
+        assert_eq!(sample.root_delay, 30_014_038);
+        assert_eq!(sample.root_dispersion, 5_004_882);
+        assert_eq!(
+            sample.distance(),
+            (sample.delay as u64 + sample.root_delay) / 2
+                + sample.dispersion
+                + sample.root_dispersion
+        );
+
+        packet.set_root_delay(NtpShort::from_signed_nanos(-1_000_000));
+        let sample = SyncSample::from_packet(&packet, 999_990_000_000, 1_000_010_000_000);
+        assert_eq!(sample.root_delay, 0, "negative root delay counts as zero");
+    }
+
+    #[test]
+    fn precision_conversion() {
+        assert_eq!(precision_to_nanos(0), 1_000_000_000);
+        assert_eq!(precision_to_nanos(-10), 976_562);
+        assert_eq!(precision_to_nanos(-25), 29);
+        assert_eq!(precision_to_nanos(2), 4_000_000_000);
+    }
+}
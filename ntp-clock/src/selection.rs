@@ -0,0 +1,280 @@
+//! Clock selection, clustering and combining from
+//! [RFC 5905 Section 11.2](https://www.rfc-editor.org/rfc/rfc5905#section-11.2), used to pick
+//! the servers we trust when we're talking to more than one.
+
+use heapless::Vec;
+
+use crate::constants::{NTP_MAX_DISTANCE_NANOS, NTP_MAX_SELECTION_CANDIDATES};
+use crate::error::ClockError;
+use crate::filter::FilterEstimate;
+use crate::sample::SyncSample;
+
+/// Minimum number of survivors the clustering algorithm will keep (NMIN)
+const MIN_SURVIVORS: usize = 3;
+
+pub type CandidateList = Vec<usize, NTP_MAX_SELECTION_CANDIDATES>;
+
+/// A server we have a current sample from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Candidate {
+    /// Offset of the local clock from the server, nanoseconds
+    pub offset: i64,
+    /// Root distance (lambda), the maximum error of the offset, nanoseconds
+    pub distance: u64,
+    /// Peer jitter, nanoseconds
+    pub jitter: u64,
+    pub stratum: u8,
+}
+
+impl Candidate {
+    pub fn from_sample(sample: &SyncSample, stratum: u8) -> Self {
+        Candidate {
+            offset: sample.offset,
+            distance: sample.distance(),
+            jitter: 0,
+            stratum,
+        }
+    }
+
+    /// Build a candidate from the clock filter's view of a server, which includes its jitter.
+    pub fn from_estimate(estimate: &FilterEstimate, stratum: u8) -> Self {
+        Candidate {
+            offset: estimate.offset,
+            distance: estimate.distance(),
+            jitter: estimate.jitter,
+            stratum,
+        }
+    }
+
+    fn low(&self) -> i128 {
+        self.offset as i128 - self.distance as i128
+    }
+
+    fn high(&self) -> i128 {
+        self.offset as i128 + self.distance as i128
+    }
+
+    /// Lower is better, prefer lower stratum then lower distance
+    fn merit(&self) -> u128 {
+        self.stratum as u128 * NTP_MAX_DISTANCE_NANOS as u128 + self.distance as u128
+    }
+}
+
+/// The outcome of running selection over a set of candidates, all values are indexes into
+/// the candidate slice.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Selection {
+    /// Combined offset of the survivors, weighted by distance, nanoseconds
+    pub offset: i64,
+    /// The best survivor, which we'd report as our reference
+    pub system_peer: usize,
+    /// Candidates which survived selection and clustering, best first
+    pub survivors: CandidateList,
+    /// Candidates whose offset lies outside the intersection of the majority
+    pub falsetickers: CandidateList,
+}
+
+/// Run the intersection, clustering and combine algorithms over `candidates`.
+///
+/// Fails with [ClockError::NoMajority] if there's no set of more than half of the candidates
+/// which agree on the time, and [ClockError::NoTimeAvailable] if there are no candidates.
+pub fn select(candidates: &[Candidate]) -> Result<Selection, ClockError> {
+    let candidates = &candidates[..candidates.len().min(NTP_MAX_SELECTION_CANDIDATES)];
+    if candidates.is_empty() {
+        return Err(ClockError::NoTimeAvailable);
+    }
+    let (low, high) = intersection(candidates).ok_or(ClockError::NoMajority)?;
+
+    let mut survivors = CandidateList::new();
+    let mut falsetickers = CandidateList::new();
+    for (index, candidate) in candidates.iter().enumerate() {
+        let offset = candidate.offset as i128;
+        let list = if offset < low || offset > high {
+            &mut falsetickers
+        } else {
+            &mut survivors
+        };
+        // can't overflow, both lists have room for every candidate
+        let _ = list.push(index);
+    }
+    if survivors.is_empty() {
+        return Err(ClockError::NoMajority);
+    }
+
+    survivors.sort_unstable_by_key(|index| candidates[*index].merit());
+    cluster(candidates, &mut survivors);
+
+    Ok(Selection {
+        offset: combine(candidates, &survivors),
+        system_peer: survivors[0],
+        survivors,
+        falsetickers,
+    })
+}
+
+/// Marzullo's algorithm as modified by RFC 5905, returns the bounds of the interval which
+/// contains the midpoints of the majority of candidates.
+///
+/// This compares `chime` against `n - allow` as the reference implementation does, the
+/// `n - found` in the RFC's sample code never converges when there's a falseticker.
+fn intersection(candidates: &[Candidate]) -> Option<(i128, i128)> {
+    // edge value and type: -1 for low, 0 for midpoint, +1 for high
+    let mut edges: Vec<(i128, i8), { NTP_MAX_SELECTION_CANDIDATES * 3 }> = Vec::new();
+    for candidate in candidates {
+        let _ = edges.push((candidate.low(), -1));
+        let _ = edges.push((candidate.offset as i128, 0));
+        let _ = edges.push((candidate.high(), 1));
+    }
+    edges.sort_unstable();
+
+    let n = candidates.len() as i32;
+    let mut allow = 0;
+    while 2 * allow < n {
+        let mut found = 0;
+        let mut chime = 0;
+        let mut low = i128::MAX;
+        for (edge, kind) in edges.iter() {
+            chime -= *kind as i32;
+            if chime >= n - allow {
+                low = *edge;
+                break;
+            }
+            if *kind == 0 {
+                found += 1;
+            }
+        }
+
+        chime = 0;
+        let mut high = i128::MIN;
+        for (edge, kind) in edges.iter().rev() {
+            chime += *kind as i32;
+            if chime >= n - allow {
+                high = *edge;
+                break;
+            }
+            if *kind == 0 {
+                found += 1;
+            }
+        }
+
+        if found <= allow && low < high {
+            return Some((low, high));
+        }
+        allow += 1;
+    }
+    None
+}
+
+/// Prune the survivors with the highest selection jitter until they're no worse than the
+/// best peer jitter, or we're down to [MIN_SURVIVORS].
+fn cluster(candidates: &[Candidate], survivors: &mut CandidateList) {
+    while survivors.len() > MIN_SURVIVORS {
+        // compare squared jitters to avoid needing sqrt in no_std
+        let mut max_selection_jitter = 0u128;
+        let mut worst = 0;
+        for (position, index) in survivors.iter().enumerate() {
+            let offset = candidates[*index].offset as i128;
+            let sum: u128 = survivors
+                .iter()
+                .map(|other| {
+                    let diff = (candidates[*other].offset as i128 - offset).unsigned_abs();
+                    diff.saturating_mul(diff)
+                })
+                .fold(0u128, |acc, value| acc.saturating_add(value));
+            let selection_jitter = sum / (survivors.len() as u128 - 1);
+            if selection_jitter >= max_selection_jitter {
+                max_selection_jitter = selection_jitter;
+                worst = position;
+            }
+        }
+        let min_peer_jitter = survivors
+            .iter()
+            .map(|index| {
+                let jitter = candidates[*index].jitter as u128;
+                jitter.saturating_mul(jitter)
+            })
+            .min()
+            .unwrap_or(0);
+        if max_selection_jitter < min_peer_jitter {
+            break;
+        }
+        survivors.remove(worst);
+    }
+}
+
+/// Average the survivor offsets, weighted by the inverse of their root distance.
+fn combine(candidates: &[Candidate], survivors: &[usize]) -> i64 {
+    let mut weights = 0.0f64;
+    let mut offset = 0.0f64;
+    for index in survivors {
+        let candidate = &candidates[*index];
+        let weight = 1.0 / candidate.distance.max(1) as f64;
+        weights += weight;
+        offset += candidate.offset as f64 * weight;
+    }
+    (offset / weights) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(offset_ms: i64, distance_ms: u64) -> Candidate {
+        Candidate {
+            offset: offset_ms * 1_000_000,
+            distance: distance_ms * 1_000_000,
+            jitter: 0,
+            stratum: 2,
+        }
+    }
+
+    #[test]
+    fn single_candidate_is_selected() {
+        let selection = select(&[candidate(5, 10)]).expect("should select");
+        assert_eq!(selection.system_peer, 0);
+        assert_eq!(selection.offset, 5_000_000);
+        assert!(selection.falsetickers.is_empty());
+    }
+
+    #[test]
+    fn falseticker_is_excluded() {
+        let candidates = [
+            candidate(10, 20),
+            candidate(12, 20),
+            candidate(3_600_000, 20),
+            candidate(8, 15),
+        ];
+        let selection = select(&candidates).expect("should select");
+        assert_eq!(selection.falsetickers.as_slice(), &[2]);
+        assert_eq!(
+            selection.system_peer, 3,
+            "lowest distance should be preferred"
+        );
+        assert!(
+            (8_000_000..=12_000_000).contains(&selection.offset),
+            "combined offset {} should come from the truechimers",
+            selection.offset
+        );
+    }
+
+    #[test]
+    fn clustering_prunes_outliers() {
+        let candidates = [
+            candidate(10, 100),
+            candidate(11, 100),
+            candidate(12, 100),
+            candidate(60, 100),
+        ];
+        let selection = select(&candidates).expect("should select");
+        assert!(selection.falsetickers.is_empty());
+        assert_eq!(selection.survivors.len(), MIN_SURVIVORS);
+        assert!(!selection.survivors.contains(&3));
+    }
+
+    #[test]
+    fn no_majority() {
+        let candidates = [candidate(0, 1), candidate(1_000, 1)];
+        assert!(matches!(select(&candidates), Err(ClockError::NoMajority)));
+        assert!(matches!(select(&[]), Err(ClockError::NoTimeAvailable)));
+    }
+}
@@ -0,0 +1,288 @@
+//! Answering client requests, so a synchronized clock can be the time source for others.
+//!
+//! Replies follow [RFC 5905 Section 9.2](https://www.rfc-editor.org/rfc/rfc5905#section-9.2):
+//! the client's transmit time comes back as the origin, and we're one stratum below the
+//! upstream server we follow, with its address as our reference ID.
+
+use core::net::IpAddr;
+
+use md5::{Digest, Md5};
+
+use crate::constants::{
+    NTP_LOCAL_PRECISION, NTP_MAX_POLL_EXPONENT, NTP_MIN_POLL_EXPONENT, NTP_PHI_PPM,
+};
+use crate::error::ClockError;
+use crate::filter::FilterEstimate;
+use crate::packets::{NtpMode, NtpPacket, NtpShort, NtpTimestamp};
+use crate::parse_ntp_packet;
+
+/// Reference ID sent with stratum 0 until we've synchronized, the INIT kiss code
+const REFERENCE_ID_INIT: u32 = u32::from_be_bytes(*b"INIT");
+/// Stratum 16 means unsynchronized
+const STRATUM_UNSYNCHRONIZED: u8 = 16;
+
+/// What we tell clients about our clock, updated each time we synchronize with upstream.
+#[derive(Clone, Debug)]
+pub struct NtpServer {
+    leap_indicator: u8,
+    stratum: u8,
+    precision: i8,
+    reference_id: u32,
+    /// When we last synchronized, UNIX nanoseconds
+    reference_time: u64,
+    /// Round trip to the primary reference, nanoseconds
+    root_delay: u64,
+    /// Maximum error relative to the primary reference at `reference_time`, nanoseconds
+    root_dispersion: u64,
+}
+
+impl Default for NtpServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NtpServer {
+    /// A server which hasn't synchronized yet, it answers with leap indicator 3 and the INIT
+    /// kiss code so clients know not to use it.
+    pub const fn new() -> Self {
+        NtpServer {
+            leap_indicator: 3,
+            stratum: STRATUM_UNSYNCHRONIZED,
+            precision: NTP_LOCAL_PRECISION,
+            reference_id: REFERENCE_ID_INIT,
+            reference_time: 0,
+            root_delay: 0,
+            root_dispersion: 0,
+        }
+    }
+
+    pub fn is_synchronized(&self) -> bool {
+        self.leap_indicator != 3 && self.stratum < STRATUM_UNSYNCHRONIZED
+    }
+
+    pub fn stratum(&self) -> u8 {
+        self.stratum
+    }
+
+    pub fn reference_id(&self) -> u32 {
+        self.reference_id
+    }
+
+    /// Follow the upstream server at `address`, which sent `upstream`. The root delay and
+    /// dispersion are the upstream's plus ours to it from the filter `estimate`.
+    pub fn update_from_upstream(
+        &mut self,
+        upstream: &NtpPacket,
+        estimate: &FilterEstimate,
+        address: IpAddr,
+    ) {
+        self.leap_indicator = upstream.leap_indicator;
+        self.stratum = upstream
+            .stratum
+            .saturating_add(1)
+            .min(STRATUM_UNSYNCHRONIZED);
+        self.reference_id = reference_id(address);
+        self.reference_time = estimate.sample.server_time();
+        self.root_delay = estimate
+            .sample
+            .root_delay
+            .saturating_add(estimate.delay.max(0) as u64);
+        self.root_dispersion = estimate
+            .sample
+            .root_dispersion
+            .saturating_add(estimate.dispersion)
+            .saturating_add(estimate.jitter);
+    }
+
+    /// Build the reply to `request`, which arrived at `recv_time` with the reply leaving at
+    /// `transmit_time` (both UNIX nanoseconds).
+    ///
+    /// Only client requests are answered, anything else fails with [ClockError::ModeMismatch]
+    /// and should be dropped.
+    pub fn respond(
+        &self,
+        request: &[u8],
+        recv_time: u64,
+        transmit_time: u64,
+    ) -> Result<NtpPacket, ClockError> {
+        // requests aren't authenticated, we answer anyone
+        let request = parse_ntp_packet(request, None)?;
+        if request.mode() != NtpMode::Client {
+            return Err(ClockError::ModeMismatch(request.mode()));
+        }
+
+        let mut response = self.packet(transmit_time);
+        response.version = request.version;
+        response.poll = request
+            .poll
+            .clamp(NTP_MIN_POLL_EXPONENT, NTP_MAX_POLL_EXPONENT);
+        response.origin_time = request.transmit_time;
+        response.recv_time = NtpTimestamp::from_unix_nanos(recv_time);
+        Ok(response)
+    }
+
+    /// A server mode packet sent at `transmit_time` (UNIX nanoseconds) describing our clock:
+    /// the leap indicator, stratum, precision, root delay and dispersion, and reference.
+    /// The origin and receive times are left for the caller.
+    pub fn packet(&self, transmit_time: u64) -> NtpPacket {
+        let mut packet = NtpPacket::from_nanos(transmit_time);
+        packet.precision = self.precision;
+        packet.origin_time = NtpTimestamp::ZERO;
+        packet.recv_time = NtpTimestamp::ZERO;
+        if self.is_synchronized() {
+            packet.leap_indicator = self.leap_indicator;
+            packet.stratum = self.stratum;
+            packet.identifier = self.reference_id;
+            packet.ref_time = NtpTimestamp::from_unix_nanos(self.reference_time);
+            packet.set_root_delay(NtpShort::from_nanos(self.root_delay));
+            packet
+                .set_root_dispersion(NtpShort::from_nanos(self.root_dispersion_at(transmit_time)));
+        } else {
+            packet.leap_indicator = 3;
+            packet.stratum = 0;
+            packet.identifier = REFERENCE_ID_INIT;
+            packet.ref_time = NtpTimestamp::ZERO;
+            packet.set_root_delay(NtpShort::ZERO);
+            packet.set_root_dispersion(NtpShort::ZERO);
+        }
+        packet
+    }
+
+    /// Answer one request from `socket`, timestamping with `clock` (UNIX nanoseconds).
+    /// Returns who we answered.
+    #[cfg(feature = "std")]
+    pub fn serve(
+        &self,
+        socket: &std::net::UdpSocket,
+        clock: impl Fn() -> u64,
+    ) -> Result<std::net::SocketAddr, ClockError> {
+        let mut request = [0u8; crate::constants::NTP_MAX_PACKET_LEN];
+        let (len, client) = socket
+            .recv_from(&mut request)
+            .map_err(|err| crate::recv_error(&err))?;
+        let recv_time = clock();
+        let response = self.respond(&request[..len], recv_time, clock())?;
+        let response = response.as_bytes().map_err(|_| ClockError::Io)?;
+        socket
+            .send_to(&response, client)
+            .map_err(|_| ClockError::NetworkError)?;
+        Ok(client)
+    }
+
+    /// Our root dispersion grows at PHI from when we last synchronized.
+    fn root_dispersion_at(&self, now: u64) -> u64 {
+        let age = now.saturating_sub(self.reference_time) as u128;
+        let growth = (age * NTP_PHI_PPM as u128 / 1_000_000) as u64;
+        self.root_dispersion.saturating_add(growth)
+    }
+}
+
+/// The reference ID for an upstream server, from
+/// [RFC 5905 Section 7.3](https://www.rfc-editor.org/rfc/rfc5905#section-7.3): an IPv4
+/// address as is, or the first four octets of the MD5 of an IPv6 address.
+pub fn reference_id(address: IpAddr) -> u32 {
+    match address {
+        IpAddr::V4(address) => u32::from(address),
+        IpAddr::V6(address) => {
+            let digest = Md5::digest(address.octets());
+            u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::ClockFilter;
+    use crate::sample::SyncSample;
+    use core::net::{Ipv4Addr, Ipv6Addr};
+
+    const NOW: u64 = 1_769_311_396_443_478_400;
+
+    fn request(transmit_time: u64) -> [u8; 48] {
+        NtpPacket::request()
+            .with_transmit_time(NtpTimestamp::from_unix_nanos(transmit_time))
+            .as_bytes()
+            .expect("request should pack")
+    }
+
+    fn synchronized() -> NtpServer {
+        let mut upstream = NtpPacket::from_nanos(NOW);
+        upstream.stratum = 2;
+        upstream.set_root_delay(NtpShort::from_nanos(10_000_000));
+        upstream.set_root_dispersion(NtpShort::from_nanos(5_000_000));
+        let sample = SyncSample::from_packet(&upstream, NOW - 2_000_000, NOW + 2_000_000);
+        let estimate = ClockFilter::new().add(sample);
+        let mut server = NtpServer::new();
+        server.update_from_upstream(
+            &upstream,
+            &estimate,
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+        );
+        server
+    }
+
+    #[test]
+    fn answers_with_upstream_stratum_and_reference() {
+        let server = synchronized();
+        let client_transmit = NOW - 1_000;
+        let response = server
+            .respond(&request(client_transmit), NOW + 1_000, NOW + 2_000)
+            .expect("should answer a client");
+
+        assert_eq!(response.mode(), NtpMode::Server);
+        assert_eq!(response.stratum, 3);
+        assert_eq!(response.identifier, 0xc000_0201);
+        assert_eq!(
+            response.origin_time,
+            NtpTimestamp::from_unix_nanos(client_transmit)
+        );
+        assert_eq!(
+            response.recv_time,
+            NtpTimestamp::from_unix_nanos(NOW + 1_000)
+        );
+        assert_eq!(
+            response.transmit_time,
+            NtpTimestamp::from_unix_nanos(NOW + 2_000)
+        );
+        // ours on top of the upstream's
+        assert!(response.root_delay_nanos() > 10_000_000);
+        assert!(response.root_dispersion_nanos() > 5_000_000);
+        assert!(response.check_usable(NtpMode::Server).is_ok());
+    }
+
+    #[test]
+    fn unsynchronized_server_sends_init() {
+        let response = NtpServer::new()
+            .respond(&request(NOW), NOW, NOW)
+            .expect("should still answer");
+        assert_eq!(response.leap_indicator, 3);
+        assert_eq!(response.stratum, 0);
+        assert_eq!(response.kiss_code(), Some(crate::packets::KissCode::Init));
+    }
+
+    #[test]
+    fn only_answers_clients() {
+        let server = synchronized();
+        let not_a_request = NtpPacket::from_nanos(NOW).as_bytes().expect("should pack");
+        assert!(matches!(
+            server.respond(&not_a_request, NOW, NOW),
+            Err(ClockError::ModeMismatch(NtpMode::Server))
+        ));
+        assert!(matches!(
+            server.respond(&[0u8; 12], NOW, NOW),
+            Err(ClockError::PacketTooShort)
+        ));
+    }
+
+    #[test]
+    fn reference_id_hashes_ipv6() {
+        assert_eq!(
+            reference_id(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+            0x0a00_0001
+        );
+        // MD5 of the 16 zero octets of :: is 4ae71336...
+        assert_eq!(reference_id(IpAddr::V6(Ipv6Addr::UNSPECIFIED)), 0x4ae7_1336);
+    }
+}
@@ -0,0 +1,92 @@
+//! Where the client gets the local time from.
+//!
+//! A [TimeSource] has two clocks: the wall clock, which timestamps requests and responses and
+//! can be stepped, and a monotonic one for measuring how long things take. [SystemTimeSource]
+//! reads the host's clocks, `ntp-clock-hardware` has one on embassy-time, and
+//! [FakeTimeSource] only moves when it's told to, for tests.
+
+use core::cell::Cell;
+use core::time::Duration;
+
+/// Two local clocks, both in nanoseconds. Implementations are handles, cheap to clone, with the
+/// clones reading the same clocks.
+pub trait TimeSource {
+    /// The wall clock, UNIX nanoseconds.
+    fn unix_nanos(&self) -> u64;
+
+    /// Nanoseconds since some fixed point, never going backwards or jumping when the wall
+    /// clock is stepped.
+    fn monotonic_nanos(&self) -> u64;
+}
+
+impl<T: TimeSource + ?Sized> TimeSource for &T {
+    fn unix_nanos(&self) -> u64 {
+        (**self).unix_nanos()
+    }
+
+    fn monotonic_nanos(&self) -> u64 {
+        (**self).monotonic_nanos()
+    }
+}
+
+/// The host's clocks, [std::time::SystemTime] and [std::time::Instant].
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemTimeSource;
+
+#[cfg(feature = "std")]
+impl TimeSource for SystemTimeSource {
+    fn unix_nanos(&self) -> u64 {
+        crate::unix_nanos_now()
+    }
+
+    fn monotonic_nanos(&self) -> u64 {
+        static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+        START
+            .get_or_init(std::time::Instant::now)
+            .elapsed()
+            .as_nanos() as u64
+    }
+}
+
+/// Clocks which stand still until [FakeTimeSource::advance] or [FakeTimeSource::step] moves
+/// them, share it with `&FakeTimeSource`.
+#[derive(Debug, Default)]
+pub struct FakeTimeSource {
+    unix_nanos: Cell<u64>,
+    monotonic_nanos: Cell<u64>,
+}
+
+impl FakeTimeSource {
+    /// Start the wall clock at `unix_nanos`, and the monotonic clock at zero.
+    pub fn new(unix_nanos: u64) -> Self {
+        FakeTimeSource {
+            unix_nanos: Cell::new(unix_nanos),
+            monotonic_nanos: Cell::new(0),
+        }
+    }
+
+    /// Let `elapsed` pass on both clocks.
+    pub fn advance(&self, elapsed: Duration) {
+        let elapsed = elapsed.as_nanos() as u64;
+        self.unix_nanos
+            .set(self.unix_nanos.get().saturating_add(elapsed));
+        self.monotonic_nanos
+            .set(self.monotonic_nanos.get().saturating_add(elapsed));
+    }
+
+    /// Set the wall clock to `unix_nanos`, without the monotonic clock moving.
+    pub fn step(&self, unix_nanos: u64) {
+        self.unix_nanos.set(unix_nanos);
+    }
+}
+
+impl TimeSource for FakeTimeSource {
+    fn unix_nanos(&self) -> u64 {
+        self.unix_nanos.get()
+    }
+
+    fn monotonic_nanos(&self) -> u64 {
+        self.monotonic_nanos.get()
+    }
+}
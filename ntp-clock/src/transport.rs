@@ -0,0 +1,737 @@
+//! Sending a request and waiting for its response, apart from how the packets travel.
+//!
+//! [NtpExchange] is the state machine for one request: it hands out the request, works out
+//! how much longer to wait, and sorts what turns up into our response or something to ignore,
+//! like packets from another host or late responses to an earlier request. [exchange] and
+//! [exchange_async] drive it over an [NtpTransport] or [AsyncNtpTransport], which is all the
+//! host client, the async client and the firmware have to provide. [NtpRequester] builds the
+//! request and checks the response, [crate::NtpClient] is one and [UnicastRequest] is a
+//! stateless one for callers which keep their own state.
+//!
+//! Around that, [NtpQuery] decides when to ask again after a timeout, how long to back off
+//! first, and when to fall through to a server's next address, so the clients only have to
+//! sleep and run exchanges when it says.
+
+use core::future::Future;
+use core::net::SocketAddr;
+use core::ops::ControlFlow;
+use core::time::Duration;
+
+use crate::auth::KeyStore;
+use crate::constants::{NTP_MAX_PACKET_LEN, NTP_MAX_SERVER_ADDRESSES};
+use crate::message::NtpMessage;
+use crate::packets::{KissAction, NtpPacket, NtpTimestamp};
+use crate::prelude::*;
+use crate::timesource::TimeSource;
+
+/// Sends packets to a server and waits for packets from anywhere.
+pub trait NtpTransport {
+    fn send_to(&mut self, packet: &[u8], server: SocketAddr) -> Result<(), ClockError>;
+
+    /// Wait up to `timeout` for a packet, returning its length and where it came from.
+    /// Fails with [ClockError::Timeout] if nothing arrives.
+    fn recv_from(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Duration,
+    ) -> Result<(usize, SocketAddr), ClockError>;
+}
+
+impl<T: NtpTransport + ?Sized> NtpTransport for &mut T {
+    fn send_to(&mut self, packet: &[u8], server: SocketAddr) -> Result<(), ClockError> {
+        (**self).send_to(packet, server)
+    }
+
+    fn recv_from(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Duration,
+    ) -> Result<(usize, SocketAddr), ClockError> {
+        (**self).recv_from(buffer, timeout)
+    }
+}
+
+/// [NtpTransport], for sockets which wait without blocking.
+pub trait AsyncNtpTransport {
+    fn send_to(
+        &mut self,
+        packet: &[u8],
+        server: SocketAddr,
+    ) -> impl Future<Output = Result<(), ClockError>>;
+
+    /// See [NtpTransport::recv_from].
+    fn recv_from(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Duration,
+    ) -> impl Future<Output = Result<(usize, SocketAddr), ClockError>>;
+}
+
+/// Builds requests and checks the responses to them.
+pub trait NtpRequester {
+    type Response;
+
+    /// The request to send, at `transmit_time` (UNIX nanoseconds).
+    fn request(
+        &mut self,
+        transmit_time: u64,
+    ) -> Result<heapless::Vec<u8, NTP_MAX_PACKET_LEN>, ClockError>;
+
+    /// Does `packet` answer our request, by echoing its origin (or NTS unique identifier)?
+    /// Packets which don't, including ones too mangled to tell, are ignored without ending
+    /// the exchange, since anyone can send us garbage from the server's address.
+    fn is_response(&self, packet: &[u8]) -> bool;
+
+    /// Check a response which arrived at `local_time`, once [Self::is_response] has matched
+    /// it. [ClockError::OriginMismatch] means it isn't the response to our request after
+    /// all, so the exchange keeps waiting.
+    fn response(&mut self, packet: &[u8], local_time: u64) -> Result<Self::Response, ClockError>;
+}
+
+/// Does `packet` have a server header echoing `origin` (UNIX nanoseconds) as its origin time?
+pub fn echoes_origin(packet: &[u8], origin: u64) -> bool {
+    crate::unpack_ntp_packet(packet)
+        .is_ok_and(|header| header.origin_time == NtpTimestamp::from_unix_nanos(origin))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExchangeState {
+    /// Nothing sent yet
+    Idle,
+    /// Waiting for the response until `deadline`, on the monotonic clock
+    Waiting { deadline: u64 },
+    /// Got the response, or gave up
+    Complete,
+}
+
+/// One request to `server` and its response.
+#[derive(Clone, Debug)]
+pub struct NtpExchange {
+    pub server: SocketAddr,
+    pub timeout: Duration,
+    state: ExchangeState,
+}
+
+impl NtpExchange {
+    pub fn new(server: SocketAddr, timeout: Duration) -> Self {
+        NtpExchange {
+            server,
+            timeout,
+            state: ExchangeState::Idle,
+        }
+    }
+
+    pub fn state(&self) -> ExchangeState {
+        self.state
+    }
+
+    /// Build the request from `requester`, sent at `transmit_time` (UNIX nanoseconds), and
+    /// start waiting for the response at `now` on the monotonic clock.
+    pub fn request<R: NtpRequester + ?Sized>(
+        &mut self,
+        requester: &mut R,
+        transmit_time: u64,
+        now: u64,
+    ) -> Result<heapless::Vec<u8, NTP_MAX_PACKET_LEN>, ClockError> {
+        let request = requester.request(transmit_time)?;
+        self.state = ExchangeState::Waiting {
+            deadline: now.saturating_add(self.timeout.as_nanos() as u64),
+        };
+        Ok(request)
+    }
+
+    /// How much longer to wait for the response at `now` on the monotonic clock, failing with [ClockError::Timeout]
+    /// once it's overdue.
+    pub fn remaining(&mut self, now: u64) -> Result<Duration, ClockError> {
+        match self.state {
+            ExchangeState::Waiting { deadline } if deadline > now => {
+                Ok(Duration::from_nanos(deadline - now))
+            }
+            _ => {
+                self.state = ExchangeState::Complete;
+                Err(ClockError::Timeout)
+            }
+        }
+    }
+
+    /// Handle `packet` from `source`, which arrived at `local_time`. Returns `None` if it isn't
+    /// our response and we should keep waiting, otherwise the requester's verdict on it.
+    pub fn receive<R: NtpRequester + ?Sized>(
+        &mut self,
+        requester: &mut R,
+        packet: &[u8],
+        source: SocketAddr,
+        local_time: u64,
+    ) -> Result<Option<R::Response>, ClockError> {
+        if !matches!(self.state, ExchangeState::Waiting { .. }) {
+            return Err(ClockError::OriginMismatch);
+        }
+        if source != self.server {
+            warn!(
+                "Ignoring NTP response from {}, expected {}",
+                source, self.server
+            );
+            return Ok(None);
+        }
+        if !requester.is_response(packet) {
+            debug!(
+                "Ignoring NTP packet from {} which doesn't answer our request",
+                source
+            );
+            return Ok(None);
+        }
+        match requester.response(packet, local_time) {
+            Err(ClockError::OriginMismatch) => {
+                debug!("Ignoring NTP response from {} to another request", source);
+                Ok(None)
+            }
+            result => {
+                self.state = ExchangeState::Complete;
+                result.map(Some)
+            }
+        }
+    }
+}
+
+/// What a query does next, from [NtpQuery::next].
+#[derive(Clone, Debug)]
+pub enum QueryStep {
+    /// Run an [NtpExchange] with `server`, then [NtpQuery::record] how it went
+    Exchange(SocketAddr),
+    /// Back off for this long before the next exchange
+    Sleep(Duration),
+    /// Every address has had its retries without answering
+    Failed(ClockError),
+}
+
+/// Asking a server until it answers: [NtpQuery::retries] more times after each timeout, with
+/// a growing delay from [crate::poll::retry_delay], then falling through to its next address.
+/// Addresses we can't send to at all, or which tell us to go away with a Kiss-o'-Death, are
+/// skipped straight away.
+#[derive(Clone, Debug)]
+pub struct NtpQuery {
+    /// The addresses in the order we'll try them
+    addresses: heapless::Vec<SocketAddr, NTP_MAX_SERVER_ADDRESSES>,
+    pub retries: u32,
+    /// Which address we're on
+    index: usize,
+    /// Which attempt at it, 0 for the first
+    attempt: u32,
+    /// Whether we've backed off before this attempt yet
+    backed_off: bool,
+    /// Why the last exchange failed
+    error: ClockError,
+}
+
+impl NtpQuery {
+    /// Query `addresses` starting with `current`, or the first if it isn't one of them.
+    pub fn new(addresses: &[SocketAddr], current: SocketAddr, retries: u32) -> Self {
+        let start = addresses
+            .iter()
+            .position(|address| *address == current)
+            .unwrap_or(0);
+        let addresses = (0..addresses.len())
+            .map(|step| addresses[(start + step) % addresses.len()])
+            .take(NTP_MAX_SERVER_ADDRESSES)
+            .collect();
+        NtpQuery {
+            addresses,
+            retries,
+            index: 0,
+            attempt: 0,
+            backed_off: false,
+            error: ClockError::Timeout,
+        }
+    }
+
+    /// Leave `address` out, failing with `error` if that leaves nothing to ask.
+    pub fn skipping(mut self, address: SocketAddr, error: ClockError) -> Self {
+        if let Some(index) = self.addresses.iter().position(|a| *a == address) {
+            self.addresses.remove(index);
+            self.error = error;
+        }
+        self
+    }
+
+    /// What to do next, with `entropy` to spread out retries (see [crate::poll::retry_delay]).
+    pub fn next(&mut self, entropy: u64) -> QueryStep {
+        let Some(address) = self.addresses.get(self.index).copied() else {
+            return QueryStep::Failed(self.error.clone());
+        };
+        if self.attempt > 0 && !self.backed_off {
+            self.backed_off = true;
+            let delay = crate::poll::retry_delay(self.attempt, entropy);
+            debug!("No response from {}, retrying in {:?}", address, delay);
+            return QueryStep::Sleep(delay);
+        }
+        QueryStep::Exchange(address)
+    }
+
+    /// Record the result of the exchange, breaking with it when the query is over.
+    pub fn record<T>(
+        &mut self,
+        result: Result<T, ClockError>,
+    ) -> ControlFlow<Result<T, ClockError>> {
+        match result {
+            Err(ClockError::Timeout) => {
+                self.error = ClockError::Timeout;
+                self.attempt += 1;
+                self.backed_off = false;
+                if self.attempt > self.retries {
+                    self.fall_through();
+                }
+                ControlFlow::Continue(())
+            }
+            // can't reach it at all, like an IPv6 address without an IPv6 route
+            Err(ClockError::NetworkError) => {
+                if let Some(address) = self.addresses.get(self.index) {
+                    warn!("Failed to send to {}", address);
+                }
+                self.error = ClockError::NetworkError;
+                self.fall_through();
+                ControlFlow::Continue(())
+            }
+            // that address is done with us, the others might not be
+            Err(ClockError::KissOfDeath(code)) if code.action() == KissAction::Demobilize => {
+                self.error = ClockError::KissOfDeath(code);
+                self.fall_through();
+                ControlFlow::Continue(())
+            }
+            result => ControlFlow::Break(result),
+        }
+    }
+
+    fn fall_through(&mut self) {
+        self.index += 1;
+        self.attempt = 0;
+        self.backed_off = false;
+    }
+}
+
+/// Send a request to `server` over `transport` and wait up to `timeout` for the response,
+/// timestamping both with the wall clock from `time`.
+pub fn exchange<T, R, C>(
+    transport: &mut T,
+    requester: &mut R,
+    server: SocketAddr,
+    timeout: Duration,
+    time: &C,
+) -> Result<R::Response, ClockError>
+where
+    T: NtpTransport + ?Sized,
+    R: NtpRequester + ?Sized,
+    C: TimeSource + ?Sized,
+{
+    let mut exchange = NtpExchange::new(server, timeout);
+    let request = exchange.request(requester, time.unix_nanos(), time.monotonic_nanos())?;
+    transport.send_to(&request, server)?;
+
+    let mut response = [0u8; NTP_MAX_PACKET_LEN];
+    loop {
+        let remaining = exchange.remaining(time.monotonic_nanos())?;
+        let (len, source) = transport.recv_from(&mut response, remaining)?;
+        let local_time = time.unix_nanos();
+        if let Some(result) = exchange.receive(requester, &response[..len], source, local_time)? {
+            return Ok(result);
+        }
+    }
+}
+
+/// [exchange], over an [AsyncNtpTransport].
+pub async fn exchange_async<T, R, C>(
+    transport: &mut T,
+    requester: &mut R,
+    server: SocketAddr,
+    timeout: Duration,
+    time: &C,
+) -> Result<R::Response, ClockError>
+where
+    T: AsyncNtpTransport + ?Sized,
+    R: NtpRequester + ?Sized,
+    C: TimeSource + ?Sized,
+{
+    let mut exchange = NtpExchange::new(server, timeout);
+    let request = exchange.request(requester, time.unix_nanos(), time.monotonic_nanos())?;
+    transport.send_to(&request, server).await?;
+
+    let mut response = [0u8; NTP_MAX_PACKET_LEN];
+    loop {
+        let remaining = exchange.remaining(time.monotonic_nanos())?;
+        let (len, source) = transport.recv_from(&mut response, remaining).await?;
+        let local_time = time.unix_nanos();
+        if let Some(result) = exchange.receive(requester, &response[..len], source, local_time)? {
+            return Ok(result);
+        }
+    }
+}
+
+/// A request with nothing kept between exchanges, optionally signed with a symmetric key,
+/// whose responses become a [SyncSample] with timestamps decoded near `pivot`.
+pub struct UnicastRequest<'a> {
+    key: Option<(u32, &'a KeyStore)>,
+    pivot: u64,
+    origin: Option<u64>,
+}
+
+impl<'a> UnicastRequest<'a> {
+    pub fn new(key: Option<(u32, &'a KeyStore)>, pivot: u64) -> Self {
+        UnicastRequest {
+            key,
+            pivot,
+            origin: None,
+        }
+    }
+}
+
+impl NtpRequester for UnicastRequest<'_> {
+    type Response = (NtpPacket, SyncSample);
+
+    fn request(
+        &mut self,
+        transmit_time: u64,
+    ) -> Result<heapless::Vec<u8, NTP_MAX_PACKET_LEN>, ClockError> {
+        let mut request = NtpMessage::new(
+            NtpPacket::request().with_transmit_time(NtpTimestamp::from_unix_nanos(transmit_time)),
+        );
+        if let Some((key_id, keys)) = self.key {
+            keys.sign(key_id, &mut request)?;
+        }
+        let request = request.to_bytes()?;
+        self.origin = Some(transmit_time);
+        Ok(request)
+    }
+
+    fn is_response(&self, packet: &[u8]) -> bool {
+        self.origin
+            .is_some_and(|origin| echoes_origin(packet, origin))
+    }
+
+    fn response(&mut self, packet: &[u8], local_time: u64) -> Result<Self::Response, ClockError> {
+        let origin = self.origin.ok_or(ClockError::OriginMismatch)?;
+        // refuses unsynchronized servers too
+        let response = parse_ntp_response(
+            packet,
+            NtpTimestamp::from_unix_nanos(origin),
+            self.key.map(|(_, keys)| keys),
+        )?;
+        self.origin = None;
+        let sample = SyncSample::from_packet_near(&response, origin, local_time, self.pivot);
+        Ok((response, sample))
+    }
+}
+
+#[cfg(feature = "std")]
+impl NtpTransport for std::net::UdpSocket {
+    fn send_to(&mut self, packet: &[u8], server: SocketAddr) -> Result<(), ClockError> {
+        std::net::UdpSocket::send_to(self, packet, server)
+            .map(|_| ())
+            .map_err(|_| ClockError::NetworkError)
+    }
+
+    fn recv_from(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Duration,
+    ) -> Result<(usize, SocketAddr), ClockError> {
+        self.set_read_timeout(Some(timeout))?;
+        std::net::UdpSocket::recv_from(self, buffer).map_err(|err| crate::recv_error(&err))
+    }
+}
+
+/// UDP from the host. Each request goes from a new socket in the server's address family, so
+/// late responses to earlier requests never reach us.
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub struct UdpTransport {
+    socket: Option<std::net::UdpSocket>,
+}
+
+#[cfg(feature = "std")]
+impl NtpTransport for UdpTransport {
+    fn send_to(&mut self, packet: &[u8], server: SocketAddr) -> Result<(), ClockError> {
+        let socket = self.socket.insert(crate::bind_for(server)?);
+        NtpTransport::send_to(socket, packet, server)
+    }
+
+    fn recv_from(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Duration,
+    ) -> Result<(usize, SocketAddr), ClockError> {
+        let socket = self.socket.as_mut().ok_or(ClockError::NetworkError)?;
+        NtpTransport::recv_from(socket, buffer, timeout)
+    }
+}
+
+/// Packets to deliver for each one sent, from [MockTransport]'s responder.
+#[cfg(feature = "std")]
+pub type MockResponder = Box<dyn FnMut(&[u8], SocketAddr) -> Vec<(Vec<u8>, SocketAddr)>>;
+
+/// An in-memory transport for tests, where a closure stands in for the network.
+///
+/// Every packet sent is recorded and handed to the responder, and whatever it returns is
+/// queued up for [NtpTransport::recv_from]. Receiving with nothing queued times out at once.
+#[cfg(feature = "std")]
+pub struct MockTransport {
+    /// Everything sent, and where to
+    pub sent: Vec<(Vec<u8>, SocketAddr)>,
+    received: std::collections::VecDeque<(Vec<u8>, SocketAddr)>,
+    responder: MockResponder,
+}
+
+#[cfg(feature = "std")]
+impl MockTransport {
+    pub fn new(
+        responder: impl FnMut(&[u8], SocketAddr) -> Vec<(Vec<u8>, SocketAddr)> + 'static,
+    ) -> Self {
+        MockTransport {
+            sent: Vec::new(),
+            received: std::collections::VecDeque::new(),
+            responder: Box::new(responder),
+        }
+    }
+
+    /// A transport where nothing ever answers.
+    pub fn silent() -> Self {
+        Self::new(|_, _| Vec::new())
+    }
+
+    /// Queue a packet from `source`, as if it had turned up unasked.
+    pub fn deliver(&mut self, packet: &[u8], source: SocketAddr) {
+        self.received.push_back((packet.to_vec(), source));
+    }
+}
+
+#[cfg(feature = "std")]
+impl NtpTransport for MockTransport {
+    fn send_to(&mut self, packet: &[u8], server: SocketAddr) -> Result<(), ClockError> {
+        self.sent.push((packet.to_vec(), server));
+        let replies = (self.responder)(packet, server);
+        self.received.extend(replies);
+        Ok(())
+    }
+
+    fn recv_from(
+        &mut self,
+        buffer: &mut [u8],
+        _timeout: Duration,
+    ) -> Result<(usize, SocketAddr), ClockError> {
+        let (packet, source) = self.received.pop_front().ok_or(ClockError::Timeout)?;
+        let len = packet.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&packet[..len]);
+        Ok((len, source))
+    }
+}
+
+#[cfg(feature = "std")]
+impl AsyncNtpTransport for MockTransport {
+    async fn send_to(&mut self, packet: &[u8], server: SocketAddr) -> Result<(), ClockError> {
+        NtpTransport::send_to(self, packet, server)
+    }
+
+    async fn recv_from(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Duration,
+    ) -> Result<(usize, SocketAddr), ClockError> {
+        NtpTransport::recv_from(self, buffer, timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use packed_struct::PackedStruct;
+
+    use super::*;
+    use crate::packets::KissCode;
+    use crate::timesource::FakeTimeSource;
+
+    const NOW: u64 = 1_735_689_600_000_000_000;
+
+    fn server() -> SocketAddr {
+        "192.0.2.1:123".parse().expect("address")
+    }
+
+    /// A stratum 1 response to `request`, 1ms later.
+    fn response_to(request: &[u8]) -> Vec<u8> {
+        let request = crate::unpack_ntp_packet(request).expect("should parse request");
+        let mut response = NtpPacket::from_nanos(NOW + 1_000_000);
+        response.origin_time = request.transmit_time;
+        response.pack().expect("should pack").to_vec()
+    }
+
+    #[test]
+    fn exchange_ignores_other_packets() {
+        let stale = {
+            let mut stale = NtpPacket::from_nanos(NOW);
+            stale.origin_time = NtpTimestamp::from_unix_nanos(NOW - 1);
+            stale.pack().expect("should pack").to_vec()
+        };
+        let other: SocketAddr = "192.0.2.2:123".parse().expect("address");
+        let mut transport = MockTransport::new(move |request, server| {
+            vec![
+                (response_to(request), other),
+                (stale.clone(), server),
+                (response_to(request), server),
+            ]
+        });
+        let mut request = UnicastRequest::new(None, NOW);
+        let (packet, sample) = exchange(
+            &mut transport,
+            &mut request,
+            server(),
+            Duration::from_secs(1),
+            &FakeTimeSource::new(NOW),
+        )
+        .expect("should get the response");
+        assert_eq!(packet.stratum, 1);
+        assert!((sample.offset - 1_000_000).abs() < 1_000);
+        assert_eq!(transport.sent.len(), 1);
+        assert_eq!(transport.sent[0].1, server());
+    }
+
+    #[test]
+    fn exchange_waits_past_spoofed_garbage() {
+        // too short to be a header, from the server's address, ahead of the real reply
+        let mut transport = MockTransport::new(|request, server| {
+            vec![(vec![0u8; 12], server), (response_to(request), server)]
+        });
+        let time = FakeTimeSource::new(NOW);
+        let mut client = crate::NtpClient::with_address(server()).with_time_source(&time);
+        let sample = client
+            .update_via(&mut transport)
+            .expect("the real reply should still count");
+        assert!((sample.offset - 1_000_000).abs() < 1_000);
+        assert_eq!(transport.sent.len(), 1);
+
+        // once a packet does echo our origin, its errors are the answer
+        let keys = KeyStore::from_keys_file("5 SHA1 secret").expect("keys should parse");
+        let mut request = UnicastRequest::new(Some((5, &keys)), NOW);
+        let mut transport = MockTransport::new(|request, server| {
+            vec![(vec![0u8; 12], server), (response_to(request), server)]
+        });
+        assert!(matches!(
+            exchange(
+                &mut transport,
+                &mut request,
+                server(),
+                Duration::from_secs(1),
+                &time
+            ),
+            Err(ClockError::AuthenticationFailed)
+        ));
+    }
+
+    #[test]
+    fn query_retries_then_falls_through() {
+        let addresses: [SocketAddr; 3] = [
+            "192.0.2.1:123".parse().expect("address"),
+            "192.0.2.2:123".parse().expect("address"),
+            "192.0.2.3:123".parse().expect("address"),
+        ];
+        let timeout = || Err::<u32, _>(ClockError::Timeout);
+        let mut query = NtpQuery::new(&addresses, addresses[1], 1);
+        assert!(matches!(query.next(0), QueryStep::Exchange(a) if a == addresses[1]));
+        assert!(query.record(timeout()).is_continue());
+        assert!(matches!(query.next(0), QueryStep::Sleep(delay) if delay.as_millis() == 250));
+        assert!(matches!(query.next(0), QueryStep::Exchange(a) if a == addresses[1]));
+        assert!(query.record(timeout()).is_continue());
+        // out of retries, on to the next without waiting
+        assert!(matches!(query.next(0), QueryStep::Exchange(a) if a == addresses[2]));
+        assert!(
+            query
+                .record(Err::<u32, _>(ClockError::NetworkError))
+                .is_continue()
+        );
+        assert!(matches!(query.next(0), QueryStep::Exchange(a) if a == addresses[0]));
+        assert!(matches!(
+            query.record(Ok::<u32, _>(5)),
+            ControlFlow::Break(Ok(5))
+        ));
+
+        let mut query = NtpQuery::new(&addresses[..1], addresses[0], 0);
+        assert!(matches!(query.next(0), QueryStep::Exchange(_)));
+        assert!(query.record(timeout()).is_continue());
+        assert!(matches!(
+            query.next(0),
+            QueryStep::Failed(ClockError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn query_skips_demobilized_addresses() {
+        let addresses: [SocketAddr; 3] = [
+            "192.0.2.1:123".parse().expect("address"),
+            "192.0.2.2:123".parse().expect("address"),
+            "192.0.2.3:123".parse().expect("address"),
+        ];
+        let deny = ClockError::KissOfDeath(KissCode::Deny);
+        let mut query = NtpQuery::new(&addresses, addresses[0], 0).skipping(addresses[1], deny);
+        assert!(matches!(query.next(0), QueryStep::Exchange(a) if a == addresses[0]));
+        // an address telling us to go away moves on to the next, rather than ending the query
+        assert!(
+            query
+                .record(Err::<u32, _>(ClockError::KissOfDeath(KissCode::Rstr)))
+                .is_continue()
+        );
+        assert!(matches!(query.next(0), QueryStep::Exchange(a) if a == addresses[2]));
+        assert!(matches!(
+            query.record(Err::<u32, _>(ClockError::KissOfDeath(KissCode::Rate))),
+            ControlFlow::Break(Err(ClockError::KissOfDeath(KissCode::Rate)))
+        ));
+
+        let mut query = NtpQuery::new(&addresses[..1], addresses[0], 0)
+            .skipping(addresses[0], ClockError::KissOfDeath(KissCode::Deny));
+        assert!(matches!(
+            query.next(0),
+            QueryStep::Failed(ClockError::KissOfDeath(KissCode::Deny))
+        ));
+    }
+
+    #[test]
+    fn exchange_times_out() {
+        let mut exchange = NtpExchange::new(server(), Duration::from_secs(2));
+        let mut request = UnicastRequest::new(None, NOW);
+        exchange
+            .request(&mut request, NOW, 0)
+            .expect("should build");
+        assert_eq!(
+            exchange.remaining(500_000_000).expect("waiting"),
+            Duration::from_millis(1_500)
+        );
+        assert!(matches!(
+            exchange.remaining(2_000_000_000),
+            Err(ClockError::Timeout)
+        ));
+        assert_eq!(exchange.state(), ExchangeState::Complete);
+
+        let mut transport = MockTransport::silent();
+        assert!(matches!(
+            super::exchange(
+                &mut transport,
+                &mut request,
+                server(),
+                Duration::from_secs(1),
+                &FakeTimeSource::new(NOW)
+            ),
+            Err(ClockError::Timeout)
+        ));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn async_exchange_matches_blocking() {
+        let mut transport =
+            MockTransport::new(|request, server| vec![(response_to(request), server)]);
+        let mut request = UnicastRequest::new(None, NOW);
+        let (_, sample) = exchange_async(
+            &mut transport,
+            &mut request,
+            server(),
+            Duration::from_secs(1),
+            &FakeTimeSource::new(NOW),
+        )
+        .await
+        .expect("should get the response");
+        assert!((sample.offset - 1_000_000).abs() < 1_000);
+    }
+}
@@ -1,13 +1,17 @@
-use ntp_clock::{NtpClient, packets::NtpPacket, unix_nanos_now};
+use ntp_clock::{
+    NtpClient,
+    packets::{NtpPacket, NtpTimestamp},
+    unix_nanos_now,
+};
 use packed_struct::PackedStruct;
 
-fn unix_nanos_to_ntp_timestamp(unix_nanos: u64) -> u64 {
+fn unix_nanos_to_ntp_timestamp(unix_nanos: u64) -> NtpTimestamp {
     const NTP_UNIX_EPOCH: i128 = 2_208_988_800;
     let unix_seconds = (unix_nanos / 1_000_000_000) as i128;
     let nanos = (unix_nanos % 1_000_000_000) as i128;
     let ntp_seconds = unix_seconds + NTP_UNIX_EPOCH;
     let fraction = (nanos << 32) / 1_000_000_000i128;
-    ((ntp_seconds as u64) << 32) | (fraction as u64)
+    NtpTimestamp(((ntp_seconds as u64) << 32) | (fraction as u64))
 }
 
 use ntest::timeout;
@@ -22,16 +26,20 @@ fn live_ntp_time_is_reasonable() {
         println!("Skipping live NTP test in sandboxed or CI environment");
         let mut client = NtpClient::new("127.0.0.1").expect("client should parse server");
         let local_time = unix_nanos_now();
+        client
+            .build_request(local_time)
+            .expect("should build NTP request");
         let ntp_timestamp = unix_nanos_to_ntp_timestamp(local_time);
-        let mut packet = NtpPacket::request();
+        let mut packet = NtpPacket::from_nanos(local_time);
         packet.ref_time = ntp_timestamp;
         packet.origin_time = ntp_timestamp;
         packet.recv_time = ntp_timestamp;
         packet.transmit_time = ntp_timestamp;
         let response = packet.pack().expect("should pack NTP response");
-        let ntp_time = client
+        let sample = client
             .update_from_response(&response, local_time)
             .expect("mocked NTP update should succeed");
+        let ntp_time = sample.t3;
 
         let delta = ((ntp_time as i128 - local_time as i128).abs() / 1_000_000_000) as i128;
         assert!(
@@ -48,12 +56,22 @@ fn live_ntp_time_is_reasonable() {
             offset_seconds <= 600,
             "mocked ntp offset too large: {offset_seconds} seconds"
         );
+        assert_eq!(
+            client.last_sample,
+            Some(sample),
+            "sample should be stored on the client"
+        );
         return;
     }
 
     println!("Running actual live NTP test against au.pool.ntp.org");
     let mut client = NtpClient::new("au.pool.ntp.org").expect("client should resolve server");
-    client.update().expect("NTP update should succeed");
+    let sample = client.update().expect("NTP update should succeed");
+    assert!(sample.delay > 0, "round-trip delay should be positive");
+    assert!(
+        sample.t1 <= sample.t4,
+        "request should be sent before the response arrives"
+    );
     println!(
         "NtpResponse: {:?}",
         client
@@ -0,0 +1,176 @@
+//! NTS against a stand-in NTS-KE and NTP server on localhost. The stand-in's cookies are just
+//! the keys, which is fine for a test but no use for a real server.
+#![cfg(feature = "nts")]
+
+use std::io::Write;
+use std::net::{TcpListener, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+
+use ntp_clock::{
+    NtpClient,
+    message::{ExtensionField, NtpMessage, write_extension_fields},
+    nts::{
+        AEAD_AES_SIV_CMAC_256, EF_NTS_AUTHENTICATOR, EF_NTS_COOKIE, EF_NTS_COOKIE_PLACEHOLDER,
+        EF_UNIQUE_IDENTIFIER, NTS_EXPORTER_LABEL, NTS_KE_ALPN, NTS_PROTOCOL_NTPV4, NtsKeRecord,
+        NtsSession, aes_siv_open, aes_siv_seal, authenticator, client_config_with_roots,
+        parse_authenticator,
+    },
+    packets::NtpPacket,
+    unix_nanos_now,
+};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+
+const CERTIFICATE: &[u8] = include_bytes!("data/nts-localhost.crt");
+const PRIVATE_KEY: &[u8] = include_bytes!("data/nts-localhost.key");
+
+fn server_config() -> Arc<ServerConfig> {
+    let certificate = CertificateDer::from_pem_slice(CERTIFICATE).expect("test certificate");
+    let key = PrivateKeyDer::from_pem_slice(PRIVATE_KEY).expect("test key");
+    let mut config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .expect("TLS 1.3 is supported")
+            .with_no_client_auth()
+            .with_single_cert(vec![certificate], key)
+            .expect("test certificate should load");
+    config.alpn_protocols = vec![NTS_KE_ALPN.to_vec()];
+    Arc::new(config)
+}
+
+fn export_key(connection: &ServerConnection, direction: u8) -> [u8; 32] {
+    let mut context = Vec::new();
+    context.extend_from_slice(&NTS_PROTOCOL_NTPV4.to_be_bytes());
+    context.extend_from_slice(&AEAD_AES_SIV_CMAC_256.to_be_bytes());
+    context.push(direction);
+    connection
+        .export_keying_material([0u8; 32], NTS_EXPORTER_LABEL, Some(&context))
+        .expect("should export keys")
+}
+
+/// Answer one NTS-KE session, pointing the client at `ntp_port`.
+fn serve_key_exchange(listener: &TcpListener, ntp_port: u16) {
+    let (stream, _) = listener.accept().expect("should accept");
+    let connection = ServerConnection::new(server_config()).expect("should start TLS");
+    let mut tls = StreamOwned::new(connection, stream);
+    loop {
+        let record = NtsKeRecord::read(&mut tls).expect("should read a record");
+        if record.record_type == NtsKeRecord::END_OF_MESSAGE {
+            break;
+        }
+    }
+    let mut cookie = export_key(&tls.conn, 0).to_vec();
+    cookie.extend_from_slice(&export_key(&tls.conn, 1));
+
+    let mut records = vec![
+        NtsKeRecord::new(
+            true,
+            NtsKeRecord::NEXT_PROTOCOL,
+            &NTS_PROTOCOL_NTPV4.to_be_bytes(),
+        ),
+        NtsKeRecord::new(
+            true,
+            NtsKeRecord::AEAD_ALGORITHM,
+            &AEAD_AES_SIV_CMAC_256.to_be_bytes(),
+        ),
+        NtsKeRecord::new(false, NtsKeRecord::NTPV4_SERVER, b"127.0.0.1"),
+        NtsKeRecord::new(false, NtsKeRecord::NTPV4_PORT, &ntp_port.to_be_bytes()),
+    ];
+    for _ in 0..2 {
+        records.push(NtsKeRecord::new(false, NtsKeRecord::NEW_COOKIE, &cookie));
+    }
+    records.push(NtsKeRecord::end_of_message());
+    for record in records {
+        tls.write_all(&record.to_bytes().expect("record"))
+            .expect("write");
+    }
+    tls.conn.send_close_notify();
+    let _ = tls.flush();
+}
+
+/// Answer one NTS protected NTP request, with a new cookie for each one the client asked for.
+fn serve_ntp(socket: &UdpSocket) -> usize {
+    let mut buffer = [0u8; 1280];
+    let (len, client) = socket.recv_from(&mut buffer).expect("should receive");
+    let request = NtpMessage::parse(&buffer[..len]).expect("request should parse");
+
+    let cookie = request
+        .extension(EF_NTS_COOKIE)
+        .expect("cookie")
+        .value
+        .clone();
+    let c2s: [u8; 32] = cookie[..32].try_into().expect("32 byte key");
+    let s2c: [u8; 32] = cookie[32..].try_into().expect("32 byte key");
+    let index = request
+        .extensions
+        .iter()
+        .position(|field| field.field_type == EF_NTS_AUTHENTICATOR)
+        .expect("authenticator");
+    let (nonce, ciphertext) = parse_authenticator(&request.extensions[index]).expect("auth");
+    let associated_data = request.bytes_before_extension(index).expect("bytes");
+    aes_siv_open(&c2s, &[&associated_data, nonce], ciphertext).expect("request should verify");
+
+    let placeholders = request
+        .extensions
+        .iter()
+        .filter(|field| field.field_type == EF_NTS_COOKIE_PLACEHOLDER)
+        .count();
+    let cookies: Vec<ExtensionField> = (0..=placeholders)
+        .map(|_| ExtensionField::new(EF_NTS_COOKIE, &cookie).expect("cookie"))
+        .collect();
+    let plaintext = write_extension_fields(&cookies).expect("cookies");
+
+    let now = unix_nanos_now();
+    let mut header = NtpPacket::from_nanos(now);
+    header.origin_time = request.header.transmit_time;
+    let mut response = NtpMessage::new(header);
+    response
+        .push_extension(
+            request
+                .extension(EF_UNIQUE_IDENTIFIER)
+                .expect("unique identifier")
+                .clone(),
+        )
+        .expect("room");
+    let associated_data = response.bytes_before_extension(1).expect("bytes");
+    let nonce = [7u8; 16];
+    let sealed = aes_siv_seal(&s2c, &[&associated_data, &nonce], &plaintext).expect("should seal");
+    response
+        .push_extension(authenticator(&nonce, &sealed).expect("auth"))
+        .expect("room");
+    socket
+        .send_to(&response.to_bytes().expect("response"), client)
+        .expect("should send");
+    placeholders + 1
+}
+
+#[test]
+fn nts_key_exchange_and_query() {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("should bind");
+    let ke_port = listener.local_addr().expect("address").port();
+    let ntp_socket = UdpSocket::bind("127.0.0.1:0").expect("should bind");
+    let ntp_port = ntp_socket.local_addr().expect("address").port();
+    let key_exchange = thread::spawn(move || serve_key_exchange(&listener, ntp_port));
+    let ntp = thread::spawn(move || serve_ntp(&ntp_socket));
+
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(CertificateDer::from_pem_slice(CERTIFICATE).expect("test certificate"))
+        .expect("should trust the test certificate");
+    let config = client_config_with_roots(roots).expect("client config");
+    let session = NtsSession::key_exchange("localhost", ke_port, config).expect("NTS-KE");
+    key_exchange.join().expect("NTS-KE server");
+    assert_eq!(session.cookies(), 2);
+    assert_eq!(session.ntp_server.port(), ntp_port);
+
+    let mut client = NtpClient::with_nts(session);
+    let sample = client
+        .update()
+        .expect("NTS protected update should succeed");
+    let returned = ntp.join().expect("NTP server");
+    assert!(sample.delay >= 0);
+    // one for the cookie it used, and as many placeholders as fit
+    assert_eq!(returned, 6, "the client should ask to refill its cookies");
+}
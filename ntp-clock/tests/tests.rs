@@ -1,15 +1,30 @@
-use ntp_clock::{error::ClockError, packets::NtpPacket, parse_ntp_packet};
+use ntp_clock::{
+    NtpClient,
+    auth::KeyStore,
+    broadcast::{BroadcastClient, bind_broadcast_socket},
+    control::ControlClient,
+    error::ClockError,
+    message::{ExtensionField, NtpMessage},
+    packets::{ControlMessage, ControlOpcode, KissCode, NtpMode, NtpPacket, NtpTimestamp},
+    parse_ntp_packet, parse_ntp_response,
+    peer::SymmetricPeer,
+    pool::NtpPool,
+    server::NtpServer,
+    timesource::{FakeTimeSource, TimeSource},
+    transport::MockTransport,
+    unix_nanos_now,
+};
 use packed_struct::PackedStruct;
 
 const UNIX_NANOS_SAMPLE: u64 = 1_735_689_600_000_000_000;
 
-fn unix_nanos_to_ntp_timestamp(unix_nanos: u64) -> u64 {
+fn unix_nanos_to_ntp_timestamp(unix_nanos: u64) -> NtpTimestamp {
     const NTP_UNIX_EPOCH: i128 = 2_208_988_800;
     let unix_seconds = (unix_nanos / 1_000_000_000) as i128;
     let nanos = (unix_nanos % 1_000_000_000) as i128;
     let ntp_seconds = unix_seconds + NTP_UNIX_EPOCH;
     let fraction = (nanos << 32) / 1_000_000_000i128;
-    ((ntp_seconds as u64) << 32) | (fraction as u64)
+    NtpTimestamp(((ntp_seconds as u64) << 32) | (fraction as u64))
 }
 
 #[test]
@@ -22,20 +37,805 @@ fn parse_packet_valid() {
     packet.recv_time = ntp_timestamp;
     packet.transmit_time = ntp_timestamp;
     let packet = packet.pack().expect("Should pack NTP response");
-    let response = parse_ntp_packet(&packet, local_time).expect("Failed to parse valid NTP packet");
+    let response = parse_ntp_packet(&packet, None).expect("Failed to parse valid NTP packet");
     dbg!(&response);
     assert_eq!(response.offset_from_local(local_time), 0);
 }
 
 #[test]
 fn parse_packet_invalid() {
-    let local_time = UNIX_NANOS_SAMPLE;
     let packet = [0u8; 12];
-    let parsed = parse_ntp_packet(&packet, local_time);
+    let parsed = parse_ntp_packet(&packet, None);
     dbg!(&parsed);
     assert!(matches!(parsed, Err(ClockError::PacketTooShort)));
     let packet = [0u8; 48];
-    let parsed = parse_ntp_packet(&packet, local_time);
+    let parsed = parse_ntp_packet(&packet, None);
     dbg!(&parsed);
     assert!(matches!(parsed, Err(ClockError::InvalidVersion)));
 }
+
+#[test]
+fn parse_packet_with_extension_fields() {
+    let mut message = NtpMessage::new(NtpPacket::from_nanos(UNIX_NANOS_SAMPLE));
+    message
+        .push_extension(ExtensionField::new(0x0104, &[0u8; 64]).expect("fits"))
+        .expect("room for a field");
+    let bytes = message.to_bytes().expect("should serialize");
+    assert!(bytes.len() > 60);
+
+    let header = parse_ntp_packet(&bytes, None).expect("header should parse");
+    assert_eq!(header.transmit_time.to_unix_nanos(), UNIX_NANOS_SAMPLE);
+    let parsed = NtpMessage::parse(&bytes).expect("message should parse");
+    assert_eq!(parsed.extensions, message.extensions);
+}
+
+#[test]
+fn parse_packet_verifies_mac() {
+    let keys = KeyStore::from_keys_file("5 SHA1 secret").expect("keys should parse");
+    let unsigned = NtpMessage::new(NtpPacket::from_nanos(UNIX_NANOS_SAMPLE));
+    let mut signed = unsigned.clone();
+    keys.sign(5, &mut signed).expect("should sign");
+    let unsigned = unsigned.to_bytes().expect("should serialize");
+    let signed = signed.to_bytes().expect("should serialize");
+
+    assert!(parse_ntp_packet(&unsigned, None).is_ok());
+    assert!(parse_ntp_packet(&signed, Some(&keys)).is_ok());
+    assert!(matches!(
+        parse_ntp_packet(&unsigned, Some(&keys)),
+        Err(ClockError::AuthenticationFailed)
+    ));
+    let other = KeyStore::from_keys_file("6 SHA1 secret").expect("keys should parse");
+    assert!(matches!(
+        parse_ntp_packet(&signed, Some(&other)),
+        Err(ClockError::UnknownKey(5))
+    ));
+}
+
+fn response_for(origin: NtpTimestamp) -> [u8; 48] {
+    let ntp_timestamp = unix_nanos_to_ntp_timestamp(UNIX_NANOS_SAMPLE);
+    let mut packet = NtpPacket::from_nanos(UNIX_NANOS_SAMPLE);
+    packet.origin_time = origin;
+    packet.recv_time = ntp_timestamp;
+    packet.transmit_time = ntp_timestamp;
+    packet.pack().expect("Should pack NTP response")
+}
+
+#[test]
+fn parse_response_checks_origin() {
+    let origin = unix_nanos_to_ntp_timestamp(UNIX_NANOS_SAMPLE);
+    let response =
+        parse_ntp_response(&response_for(origin), origin, None).expect("origin should match");
+    assert_eq!(response.origin_time.to_unix_nanos(), UNIX_NANOS_SAMPLE);
+
+    let parsed = parse_ntp_response(&response_for(NtpTimestamp(origin.0 + 1)), origin, None);
+    assert!(matches!(parsed, Err(ClockError::OriginMismatch)));
+    let parsed = parse_ntp_response(&response_for(NtpTimestamp::ZERO), NtpTimestamp::ZERO, None);
+    assert!(matches!(parsed, Err(ClockError::OriginMismatch)));
+}
+
+#[test]
+fn client_rejects_stale_and_duplicate_responses() {
+    let mut client = NtpClient::new("127.0.0.1").expect("client should parse server");
+    let origin = unix_nanos_to_ntp_timestamp(UNIX_NANOS_SAMPLE);
+
+    // nothing outstanding yet
+    let result = client.update_from_response(&response_for(origin), UNIX_NANOS_SAMPLE);
+    assert!(matches!(result, Err(ClockError::OriginMismatch)));
+
+    client
+        .build_request(UNIX_NANOS_SAMPLE)
+        .expect("should build NTP request");
+    let result =
+        client.update_from_response(&response_for(NtpTimestamp(origin.0 - 1)), UNIX_NANOS_SAMPLE);
+    assert!(matches!(result, Err(ClockError::OriginMismatch)));
+    assert!(client.last_sample.is_none(), "bogus reply must not be used");
+
+    client
+        .update_from_response(&response_for(origin), UNIX_NANOS_SAMPLE)
+        .expect("matching reply should be accepted");
+    let result = client.update_from_response(&response_for(origin), UNIX_NANOS_SAMPLE);
+    assert!(matches!(result, Err(ClockError::OriginMismatch)));
+}
+
+#[test]
+fn client_rejects_unsynchronized_servers() {
+    let mut client = NtpClient::new("127.0.0.1").expect("client should parse server");
+    let origin = unix_nanos_to_ntp_timestamp(UNIX_NANOS_SAMPLE);
+    let mut packet = NtpPacket::from_nanos(UNIX_NANOS_SAMPLE);
+    packet.origin_time = origin;
+
+    packet.leap_indicator = 3;
+    client
+        .build_request(UNIX_NANOS_SAMPLE)
+        .expect("should build NTP request");
+    let response = packet.pack().expect("Should pack NTP response");
+    let result = client.update_from_response(&response, UNIX_NANOS_SAMPLE);
+    assert!(matches!(result, Err(ClockError::Unsynchronized)));
+
+    packet.leap_indicator = 0;
+    packet.stratum = 16;
+    client
+        .build_request(UNIX_NANOS_SAMPLE)
+        .expect("should build NTP request");
+    let response = packet.pack().expect("Should pack NTP response");
+    let result = client.update_from_response(&response, UNIX_NANOS_SAMPLE);
+    assert!(matches!(result, Err(ClockError::InvalidStratum(16))));
+    assert!(
+        client.last_sample.is_none(),
+        "unsynchronized servers must not be used"
+    );
+}
+
+#[test]
+fn client_requires_authenticated_responses() {
+    let keys = KeyStore::from_keys_file("5 SHA1 secret").expect("keys should parse");
+    let mut client = NtpClient::new("127.0.0.1")
+        .expect("client should parse server")
+        .with_authentication(5, keys.clone())
+        .expect("key 5 is in the store");
+    let origin = unix_nanos_to_ntp_timestamp(UNIX_NANOS_SAMPLE);
+
+    let request = client
+        .build_request(UNIX_NANOS_SAMPLE)
+        .expect("should build NTP request");
+    let request = NtpMessage::parse(&request).expect("request should parse");
+    assert_eq!(keys.verify(&request).expect("request should be signed"), 5);
+
+    let result = client.update_from_response(&response_for(origin), UNIX_NANOS_SAMPLE);
+    assert!(matches!(result, Err(ClockError::AuthenticationFailed)));
+
+    client
+        .build_request(UNIX_NANOS_SAMPLE)
+        .expect("should build NTP request");
+    let mut response = NtpMessage::new(
+        NtpPacket::unpack(&response_for(origin)).expect("Should unpack NTP response"),
+    );
+    keys.sign(5, &mut response).expect("should sign");
+    let response = response.to_bytes().expect("should serialize");
+    client
+        .update_from_response(&response, UNIX_NANOS_SAMPLE)
+        .expect("signed reply should be accepted");
+}
+
+fn kiss_of_death(origin: NtpTimestamp, code: &[u8; 4]) -> [u8; 48] {
+    let mut packet = NtpPacket::request();
+    packet.origin_time = origin;
+    packet.identifier = u32::from_be_bytes(*code);
+    packet.pack().expect("Should pack NTP response")
+}
+
+/// Send a request at `now` and have the server answer it with a RATE Kiss-o'-Death.
+fn rate_kiss(
+    client: &mut NtpClient<&FakeTimeSource>,
+    now: u64,
+) -> Result<ntp_clock::sample::SyncSample, ClockError> {
+    client.build_request(now)?;
+    let origin = unix_nanos_to_ntp_timestamp(now);
+    client.update_from_response(&kiss_of_death(origin, b"RATE"), now)
+}
+
+#[test]
+fn client_backs_off_after_rate_kiss() {
+    let time = FakeTimeSource::new(UNIX_NANOS_SAMPLE);
+    let mut client = NtpClient::new("127.0.0.1")
+        .expect("client should parse server")
+        .with_time_source(&time);
+    assert!(matches!(
+        rate_kiss(&mut client, time.unix_nanos()),
+        Err(ClockError::KissOfDeath(KissCode::Rate))
+    ));
+    assert!(
+        client.last_sample.is_none(),
+        "KoD timestamps must not be used"
+    );
+
+    // the hold is on the monotonic clock, stepping the wall clock doesn't end it
+    time.step(UNIX_NANOS_SAMPLE + 3_600_000_000_000);
+    assert!(matches!(
+        client.build_request(time.unix_nanos()),
+        Err(ClockError::KissOfDeath(KissCode::Rate))
+    ));
+    time.advance(std::time::Duration::from_secs(64));
+    client
+        .build_request(time.unix_nanos())
+        .expect("should be allowed to poll after the backoff");
+
+    // another one doubles the hold
+    assert!(rate_kiss(&mut client, time.unix_nanos()).is_err());
+    time.advance(std::time::Duration::from_secs(64));
+    assert!(client.build_request(time.unix_nanos()).is_err());
+    time.advance(std::time::Duration::from_secs(64));
+
+    // and a response we accept starts it again from the minimum
+    let now = time.unix_nanos();
+    client
+        .build_request(now)
+        .expect("should be allowed to poll after the backoff");
+    let mut response = NtpPacket::from_nanos(now);
+    response.origin_time = unix_nanos_to_ntp_timestamp(now);
+    let response = response.pack().expect("Should pack NTP response");
+    client
+        .update_from_response(&response, now)
+        .expect("should accept the response");
+    assert!(rate_kiss(&mut client, now).is_err());
+    time.advance(std::time::Duration::from_secs(64));
+    client
+        .build_request(time.unix_nanos())
+        .expect("should be allowed to poll after the backoff");
+}
+
+#[test]
+fn client_polls_less_after_rate_kiss() {
+    let time = FakeTimeSource::new(UNIX_NANOS_SAMPLE);
+    let mut client = NtpClient::new("127.0.0.1")
+        .expect("client should parse server")
+        .with_time_source(&time);
+    assert_eq!(client.poll.interval(), std::time::Duration::from_secs(64));
+    assert!(rate_kiss(&mut client, time.unix_nanos()).is_err());
+    assert_eq!(client.poll.interval(), std::time::Duration::from_secs(128));
+
+    // and never more often than the poll field of the kiss asks
+    time.advance(std::time::Duration::from_secs(3_600));
+    let now = time.unix_nanos();
+    client.build_request(now).expect("should build NTP request");
+    let mut kiss = NtpPacket::unpack(&kiss_of_death(unix_nanos_to_ntp_timestamp(now), b"RATE"))
+        .expect("Should unpack NTP response");
+    kiss.poll = 12;
+    let kiss = kiss.pack().expect("Should pack NTP response");
+    assert!(client.update_from_response(&kiss, now).is_err());
+    assert_eq!(
+        client.poll.interval(),
+        std::time::Duration::from_secs(4_096)
+    );
+}
+
+#[test]
+fn client_stops_after_deny_kiss() {
+    let mut client = NtpClient::new("127.0.0.1").expect("client should parse server");
+    client
+        .build_request(UNIX_NANOS_SAMPLE)
+        .expect("should build NTP request");
+    let origin = unix_nanos_to_ntp_timestamp(UNIX_NANOS_SAMPLE);
+    let result = client.update_from_response(&kiss_of_death(origin, b"DENY"), UNIX_NANOS_SAMPLE);
+    assert!(matches!(
+        result,
+        Err(ClockError::KissOfDeath(KissCode::Deny))
+    ));
+    let result = client.build_request(UNIX_NANOS_SAMPLE + 1_000_000_000_000);
+    assert!(matches!(
+        result,
+        Err(ClockError::KissOfDeath(KissCode::Deny))
+    ));
+}
+
+#[test]
+fn client_only_stops_querying_the_address_which_sent_deny() {
+    let denying: std::net::SocketAddr = "192.0.2.1:123".parse().expect("address");
+    let answering: std::net::SocketAddr = "192.0.2.2:123".parse().expect("address");
+    let mut transport = MockTransport::new(move |request, server| {
+        let request = parse_ntp_packet(request, None).expect("should parse request");
+        let response = if server == denying {
+            kiss_of_death(request.transmit_time, b"DENY")
+        } else {
+            let mut response = NtpPacket::from_nanos(unix_nanos_now());
+            response.origin_time = request.transmit_time;
+            response.pack().expect("Should pack NTP response")
+        };
+        vec![(response.to_vec(), server)]
+    });
+
+    let mut client = NtpClient::with_addresses(&[denying, answering])
+        .expect("should create client")
+        .with_retries(0);
+    client
+        .update_via(&mut transport)
+        .expect("should fall through to the other address");
+    assert_eq!(client.server, answering);
+    assert!(!client.is_demobilized());
+    client
+        .update_via(&mut transport)
+        .expect("should sync again");
+    let destinations: Vec<_> = transport.sent.iter().map(|(_, to)| *to).collect();
+    assert_eq!(destinations, [denying, answering, answering]);
+
+    let mut client = NtpClient::with_address(denying);
+    assert!(matches!(
+        client.update_via(&mut transport),
+        Err(ClockError::KissOfDeath(KissCode::Deny))
+    ));
+    assert!(client.is_demobilized());
+    // nothing left to ask, so nothing is sent
+    let sent = transport.sent.len();
+    assert!(matches!(
+        client.update_via(&mut transport),
+        Err(ClockError::KissOfDeath(KissCode::Deny))
+    ));
+    assert_eq!(transport.sent.len(), sent);
+}
+
+#[test]
+fn pool_requires_servers() {
+    assert!(matches!(
+        NtpPool::with_addresses(&[]),
+        Err(ClockError::ConfigError(_))
+    ));
+    let pool = NtpPool::new(&["127.0.0.1", "127.0.0.2"]).expect("pool should parse servers");
+    assert_eq!(pool.peers().len(), 2);
+    assert!(matches!(pool.select(), Err(ClockError::NoTimeAvailable)));
+}
+
+#[test]
+fn client_syncs_from_local_server() {
+    // follow a stratum 1 upstream at 192.0.2.1
+    let now = unix_nanos_now();
+    let mut client = NtpClient::with_address("192.0.2.1:123".parse().expect("address"));
+    client.build_request(now).expect("should build NTP request");
+    let mut upstream = NtpPacket::from_nanos(now);
+    upstream.origin_time = NtpTimestamp::from_unix_nanos(now);
+    let upstream = upstream.pack().expect("Should pack NTP response");
+    client
+        .update_from_response(&upstream, now)
+        .expect("upstream reply should be accepted");
+    let mut server = NtpServer::new();
+    server.update_from_upstream(
+        client.last_response.as_ref().expect("upstream response"),
+        &client.filter.estimate(now).expect("upstream estimate"),
+        client.server.ip(),
+    );
+
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").expect("should bind");
+    let address = socket.local_addr().expect("address");
+    let serving = std::thread::spawn(move || server.serve(&socket, unix_nanos_now));
+
+    let mut downstream = NtpClient::with_address(address);
+    let sample = downstream.update().expect("should sync from our server");
+    serving
+        .join()
+        .expect("server thread")
+        .expect("should answer the request");
+    let response = downstream.last_response.expect("response");
+    assert_eq!(response.stratum, 2);
+    assert_eq!(response.identifier, 0xc000_0201);
+    assert!(sample.offset.abs() < 1_000_000_000);
+}
+
+#[test]
+fn client_times_out_and_falls_through() {
+    // bound but never answers
+    let silent = std::net::UdpSocket::bind("127.0.0.1:0").expect("should bind");
+    let silent = silent.local_addr().expect("address");
+    let timeout = std::time::Duration::from_millis(50);
+
+    let mut client = NtpClient::with_address(silent)
+        .with_timeout(timeout)
+        .with_retries(1);
+    assert!(matches!(client.update(), Err(ClockError::Timeout)));
+
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").expect("should bind");
+    let address = socket.local_addr().expect("address");
+    let server = NtpServer::new();
+    let serving = std::thread::spawn(move || server.serve(&socket, unix_nanos_now));
+    let mut client = NtpClient::with_addresses(&[silent, address])
+        .expect("should create client")
+        .with_timeout(timeout)
+        .with_retries(1);
+    // our server isn't synchronized, but it answered
+    assert!(matches!(
+        client.update(),
+        Err(ClockError::KissOfDeath(KissCode::Init))
+    ));
+    assert_eq!(client.server, address);
+    serving
+        .join()
+        .expect("server thread")
+        .expect("should answer the request");
+}
+
+#[test]
+fn client_falls_through_over_mock_transport() {
+    let silent: std::net::SocketAddr = "192.0.2.1:123".parse().expect("address");
+    let answering: std::net::SocketAddr = "192.0.2.2:123".parse().expect("address");
+    let mut transport = MockTransport::new(move |request, server| {
+        if server != answering {
+            return Vec::new();
+        }
+        let request = parse_ntp_packet(request, None).expect("should parse request");
+        let mut response = NtpPacket::from_nanos(unix_nanos_now());
+        response.origin_time = request.transmit_time;
+        let response = response.pack().expect("Should pack NTP response");
+        // a late duplicate of an earlier response turns up first, and is ignored
+        let mut stale = NtpPacket::from_nanos(unix_nanos_now());
+        stale.origin_time = unix_nanos_to_ntp_timestamp(UNIX_NANOS_SAMPLE);
+        let stale = stale.pack().expect("Should pack NTP response");
+        vec![(stale.to_vec(), server), (response.to_vec(), server)]
+    });
+
+    let mut client = NtpClient::with_addresses(&[silent, answering])
+        .expect("should create client")
+        .with_retries(0);
+    let sample = client
+        .update_via(&mut transport)
+        .expect("should sync from the second address");
+    assert_eq!(client.server, answering);
+    let destinations: Vec<_> = transport.sent.iter().map(|(_, to)| *to).collect();
+    assert_eq!(destinations, [silent, answering]);
+    assert!(sample.offset.abs() < 1_000_000_000);
+}
+
+/// A server whose clock reads `server_time` whenever it's asked.
+fn fixed_server(server_time: u64) -> MockTransport {
+    MockTransport::new(move |request, server| {
+        let request = parse_ntp_packet(request, None).expect("should parse request");
+        let mut response = NtpPacket::from_nanos(server_time);
+        response.origin_time = request.transmit_time;
+        let response = response.pack().expect("Should pack NTP response");
+        vec![(response.to_vec(), server)]
+    })
+}
+
+#[test]
+fn client_offsets_are_reproducible_with_fake_time() {
+    let time = FakeTimeSource::new(UNIX_NANOS_SAMPLE);
+    let mut client =
+        NtpClient::with_address("192.0.2.1:123".parse().expect("address")).with_time_source(&time);
+    let mut transport = fixed_server(UNIX_NANOS_SAMPLE + 250_000_000);
+    for _ in 0..3 {
+        let sample = client.update_via(&mut transport).expect("should sync");
+        assert!((sample.offset - 250_000_000).abs() < 1_000);
+        assert!(sample.delay < 1_000);
+    }
+    // stepping our clock back a second puts the server a second further ahead
+    time.step(UNIX_NANOS_SAMPLE - 1_000_000_000);
+    let sample = client.update_via(&mut transport).expect("should sync");
+    assert!((sample.offset - 1_250_000_000).abs() < 1_000);
+}
+
+#[test]
+fn client_time_is_valid_for_one_poll_interval() {
+    let time = FakeTimeSource::new(UNIX_NANOS_SAMPLE);
+    let mut client =
+        NtpClient::with_address("192.0.2.1:123".parse().expect("address")).with_time_source(&time);
+    assert!(!client.time_is_valid());
+    client
+        .update_via(&mut fixed_server(UNIX_NANOS_SAMPLE))
+        .expect("should sync");
+    assert!(client.time_is_valid());
+
+    let interval = client.poll.interval();
+    time.advance(interval - std::time::Duration::from_millis(1));
+    assert!(client.time_is_valid());
+    time.advance(std::time::Duration::from_millis(1));
+    assert!(!client.time_is_valid());
+}
+
+#[test]
+fn client_time_moves_on_with_the_monotonic_clock() {
+    let time = FakeTimeSource::new(UNIX_NANOS_SAMPLE);
+    let mut client =
+        NtpClient::with_address("192.0.2.1:123".parse().expect("address")).with_time_source(&time);
+    let server_time = UNIX_NANOS_SAMPLE + 250_000_000;
+    client
+        .update_via(&mut fixed_server(server_time))
+        .expect("should sync");
+    let synced = client.get_time().expect("should have the time");
+    assert!(synced.abs_diff(server_time) < 1_000);
+
+    // stepping our wall clock doesn't move the server's time, only time passing does
+    time.step(UNIX_NANOS_SAMPLE - 3_600_000_000_000);
+    assert_eq!(client.get_time().expect("should have the time"), synced);
+    time.advance(std::time::Duration::from_secs(10));
+    let later = client.get_time().expect("should have the time");
+    assert_eq!(later, synced + 10_000_000_000);
+
+    // a server which is behind where we've got to doesn't take the time backwards
+    client
+        .update_via(&mut fixed_server(server_time))
+        .expect("should sync");
+    assert_eq!(client.get_time().expect("should have the time"), later);
+    time.advance(std::time::Duration::from_secs(20));
+    let resumed = client.get_time().expect("should have the time");
+    assert!(resumed.abs_diff(server_time + 20_000_000_000) < 1_000);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(flavor = "current_thread")]
+async fn client_updates_with_its_callers_transport_and_sleep() {
+    let time = FakeTimeSource::new(UNIX_NANOS_SAMPLE);
+    let mut client =
+        NtpClient::new_with_time_source("192.0.2.1:123".parse().expect("address"), &time)
+            .with_retries(1);
+    assert!(matches!(
+        client.current_time(),
+        Err(ClockError::NoTimeAvailable)
+    ));
+
+    let mut slept = Vec::new();
+    let result = client
+        .update_with(&mut MockTransport::silent(), |delay| {
+            slept.push(delay);
+            async {}
+        })
+        .await;
+    assert!(matches!(result, Err(ClockError::Timeout)));
+    assert_eq!(slept.len(), 1);
+
+    let server_time = UNIX_NANOS_SAMPLE + 250_000_000;
+    let sample = client
+        .update_with(&mut fixed_server(server_time), |_| async {})
+        .await
+        .expect("should sync");
+    assert!((sample.offset - 250_000_000).abs() < 1_000);
+    let now = client.current_time().expect("should have the time");
+    assert!(now.abs_diff(server_time) < 1_000);
+}
+
+#[test]
+fn client_syncs_over_ipv6() {
+    // follow an upstream at 2001:db8::1, and serve on the IPv6 loopback
+    let now = unix_nanos_now();
+    let mut client = NtpClient::with_address("[2001:db8::1]:123".parse().expect("address"));
+    client.build_request(now).expect("should build NTP request");
+    let mut upstream = NtpPacket::from_nanos(now);
+    upstream.origin_time = NtpTimestamp::from_unix_nanos(now);
+    let upstream = upstream.pack().expect("Should pack NTP response");
+    client
+        .update_from_response(&upstream, now)
+        .expect("upstream reply should be accepted");
+    let mut server = NtpServer::new();
+    server.update_from_upstream(
+        client.last_response.as_ref().expect("upstream response"),
+        &client.filter.estimate(now).expect("upstream estimate"),
+        client.server.ip(),
+    );
+
+    let socket = std::net::UdpSocket::bind("[::1]:0").expect("should bind");
+    let address = socket.local_addr().expect("address");
+    let serving = std::thread::spawn(move || server.serve(&socket, unix_nanos_now));
+
+    let mut downstream = NtpClient::with_address(address);
+    downstream.update().expect("should sync over IPv6");
+    serving
+        .join()
+        .expect("server thread")
+        .expect("should answer the request");
+    let response = downstream.last_response.expect("response");
+    let reference = ntp_clock::server::reference_id(client.server.ip());
+    assert_eq!(response.identifier, reference);
+}
+
+#[test]
+fn control_queries_on_loopback() {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").expect("should bind");
+    let address = socket.local_addr().expect("address");
+    // answers READSTAT with one association, and READVAR in two fragments sent backwards
+    let serving = std::thread::spawn(move || {
+        let mut buffer = [0u8; 1280];
+        for _ in 0..2 {
+            let (len, client) = socket.recv_from(&mut buffer).expect("should receive");
+            let request = ControlMessage::parse(&buffer[..len]).expect("should parse");
+            let mut response = request.clone();
+            response.response = true;
+            let fragments: &[(u16, &[u8], bool)] = match request.opcode {
+                ControlOpcode::ReadStatus => &[(0, &[0x00, 0x01, 0x96, 0x1a], false)],
+                _ => &[(18, b"stratum=3", false), (0, b"srcadr=192.0.2.1, ", true)],
+            };
+            for (offset, data, more) in fragments {
+                response.offset = *offset;
+                response.more = *more;
+                response.data = heapless::Vec::from_slice(data).expect("fits");
+                socket
+                    .send_to(&response.to_bytes().expect("should encode"), client)
+                    .expect("should send");
+            }
+        }
+    });
+
+    let mut client = ControlClient::with_address(address);
+    let associations = client.read_status().expect("should read status");
+    assert_eq!(associations.len(), 1);
+    assert_eq!(associations[0].0, 1);
+    assert_eq!(associations[0].1.tally(), '*');
+    let variables = client.read_variables(1).expect("should read variables");
+    serving.join().expect("server thread");
+    assert_eq!(
+        variables,
+        [
+            ("srcadr".to_string(), "192.0.2.1".to_string()),
+            ("stratum".to_string(), "3".to_string())
+        ]
+    );
+}
+
+#[test]
+fn broadcast_client_uses_signed_broadcasts() {
+    let keys = KeyStore::from_keys_file("5 SHA1 secret").expect("keys should parse");
+    let socket = bind_broadcast_socket(0, None).expect("should bind");
+    let port = socket.local_addr().expect("address").port();
+    let mut client = BroadcastClient::new("127.0.0.1".parse().expect("address"))
+        .with_authentication(keys.clone());
+    let now = unix_nanos_now();
+    for _ in 0..4 {
+        client.calibrate(&ntp_clock::sample::SyncSample::new(
+            now,
+            now,
+            now,
+            now + 2_000_000,
+            -20,
+        ));
+    }
+
+    let server = std::net::UdpSocket::bind("127.0.0.1:0").expect("should bind");
+    let mut packet = NtpPacket::from_nanos(now);
+    packet.set_mode(NtpMode::Broadcast);
+    server
+        .send_to(
+            &packet.pack().expect("Should pack NTP broadcast"),
+            ("127.0.0.1", port),
+        )
+        .expect("should send");
+    let mut signed = NtpMessage::new(NtpPacket::from_nanos(unix_nanos_now()));
+    signed.header.set_mode(NtpMode::Broadcast);
+    keys.sign(5, &mut signed).expect("should sign");
+    server
+        .send_to(
+            &signed.to_bytes().expect("should serialize"),
+            ("127.0.0.1", port),
+        )
+        .expect("should send");
+
+    assert!(matches!(
+        client.listen(&socket),
+        Err(ClockError::AuthenticationFailed)
+    ));
+    let (_, sample) = client
+        .listen(&socket)
+        .expect("signed broadcast should be used");
+    assert_eq!(sample.delay, 2_000_000);
+    assert!(sample.offset.abs() < 1_000_000_000);
+}
+
+fn recv_peer_packet(socket: &std::net::UdpSocket) -> (Vec<u8>, std::net::SocketAddr) {
+    let mut buffer = [0u8; 1280];
+    let (len, source) = socket.recv_from(&mut buffer).expect("should receive");
+    (buffer[..len].to_vec(), source)
+}
+
+#[test]
+fn symmetric_peers_on_loopback() {
+    // A follows a stratum 1 server, B has nothing to follow yet
+    let now = unix_nanos_now();
+    let upstream = NtpPacket::from_nanos(now);
+    let sample = ntp_clock::sample::SyncSample::from_packet(&upstream, now, now + 1_000_000);
+    let mut system_a = NtpServer::new();
+    system_a.update_from_upstream(
+        &upstream,
+        &ntp_clock::filter::ClockFilter::new().add(sample),
+        "192.0.2.1".parse().expect("address"),
+    );
+    let mut system_b = NtpServer::new();
+
+    let socket_a = std::net::UdpSocket::bind("127.0.0.1:0").expect("should bind");
+    let socket_b = std::net::UdpSocket::bind("127.0.0.1:0").expect("should bind");
+    for socket in [&socket_a, &socket_b] {
+        socket
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .expect("should set timeout");
+    }
+    let address_a = socket_a.local_addr().expect("address");
+    let address_b = socket_b.local_addr().expect("address");
+
+    // both configured with the other as a peer, like two instances with --peer
+    let mut a = SymmetricPeer::active(address_b);
+    let mut b = SymmetricPeer::active(address_a);
+    a.send(&socket_a, &system_a, unix_nanos_now())
+        .expect("should send");
+    let (packet, source) = recv_peer_packet(&socket_b);
+    assert_eq!(source, address_a);
+    assert!(matches!(
+        b.receive(&packet, unix_nanos_now()),
+        Err(ClockError::OriginMismatch)
+    ));
+
+    b.send(&socket_b, &system_b, unix_nanos_now())
+        .expect("should send");
+    let (packet, _) = recv_peer_packet(&socket_a);
+    assert!(matches!(
+        a.receive(&packet, unix_nanos_now()),
+        Err(ClockError::Unsynchronized)
+    ));
+
+    a.send(&socket_a, &system_a, unix_nanos_now())
+        .expect("should send");
+    let (packet, _) = recv_peer_packet(&socket_b);
+    let sample = b
+        .receive(&packet, unix_nanos_now())
+        .expect("A is synchronized, so B can follow it");
+    assert!(sample.offset.abs() < 1_000_000_000);
+    system_b.update_from_upstream(
+        b.last_response.as_ref().expect("response"),
+        &b.filter.estimate(unix_nanos_now()).expect("estimate"),
+        address_a.ip(),
+    );
+    assert_eq!(system_b.stratum(), 3);
+
+    b.send(&socket_b, &system_b, unix_nanos_now())
+        .expect("should send");
+    let (packet, _) = recv_peer_packet(&socket_a);
+    a.receive(&packet, unix_nanos_now())
+        .expect("B is synchronized now too");
+    assert_eq!(a.last_response.expect("response").stratum, 3);
+}
+
+/// A stratum 2 server following a stratum 1 upstream at `upstream`.
+#[cfg(feature = "tokio")]
+fn synced_server(upstream: &str) -> NtpServer {
+    let now = unix_nanos_now();
+    let mut client = NtpClient::with_address(upstream.parse().expect("address"));
+    client.build_request(now).expect("should build NTP request");
+    let mut response = NtpPacket::from_nanos(now);
+    response.origin_time = NtpTimestamp::from_unix_nanos(now);
+    let response = response.pack().expect("Should pack NTP response");
+    client
+        .update_from_response(&response, now)
+        .expect("upstream reply should be accepted");
+    let mut server = NtpServer::new();
+    server.update_from_upstream(
+        client.last_response.as_ref().expect("upstream response"),
+        &client.filter.estimate(now).expect("upstream estimate"),
+        client.server.ip(),
+    );
+    server
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(flavor = "current_thread")]
+async fn async_client_syncs_from_local_server() {
+    let server = synced_server("192.0.2.1:123");
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").expect("should bind");
+    let address = socket.local_addr().expect("address");
+    let serving = std::thread::spawn(move || server.serve(&socket, unix_nanos_now));
+
+    let mut client = NtpClient::with_address(address);
+    let sample = client.update_async().await.expect("should sync");
+    serving
+        .join()
+        .expect("server thread")
+        .expect("should answer the request");
+    assert_eq!(client.last_response.expect("response").stratum, 2);
+    assert!(sample.offset.abs() < 1_000_000_000);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(flavor = "current_thread")]
+async fn async_pool_queries_peers_at_once() {
+    let mut addresses = Vec::new();
+    let mut serving = Vec::new();
+    for upstream in ["192.0.2.1:123", "192.0.2.2:123"] {
+        let server = synced_server(upstream);
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").expect("should bind");
+        addresses.push(socket.local_addr().expect("address"));
+        serving.push(std::thread::spawn(move || {
+            server.serve(&socket, unix_nanos_now)
+        }));
+    }
+    // bound but never answers
+    let silent = std::net::UdpSocket::bind("127.0.0.1:0").expect("should bind");
+    addresses.push(silent.local_addr().expect("address"));
+
+    let timeout = std::time::Duration::from_millis(200);
+    let mut pool = NtpPool::with_addresses(&addresses)
+        .expect("should create pool")
+        .with_retry_policy(timeout, 0);
+    let started = std::time::Instant::now();
+    let selection = pool.update_async().await.expect("should select a peer");
+    // waiting on the silent peer didn't hold up the others
+    assert!(started.elapsed() < timeout * 2);
+    assert!(selection.system_peer < 2);
+    assert!(pool.peers()[2].last_sample.is_none());
+    for serving in serving {
+        serving
+            .join()
+            .expect("server thread")
+            .expect("should answer the request");
+    }
+}